@@ -1,10 +1,14 @@
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
 use ethers::prelude::Address;
 use stderrlog::Timestamp;
 
-use crate::server::run;
+use crate::server::{
+    nonce_repair, parse_gas_limit_multiplier, run, CaptchaProvider, DripArg, DripKeyKind,
+    FeeStrategyKind, KeySecret, LedgerBackend, Quota, SignerStrategy, StateBackend,
+};
 
 mod server;
 
@@ -13,18 +17,93 @@ mod server;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
-    /// Wallet private key (ECDSA, secp256k1) used to register new accounts and send drips.
-    #[arg(short, long, env)]
-    private_key: String,
-    /// Cloudflare secret key.
+    /// Wallet private keys (ECDSA, secp256k1) used to register new accounts and send drips.
+    /// Multiple keys form a signer pool.
+    #[arg(
+        short,
+        long,
+        env,
+        value_delimiter = ',',
+        required_unless_present = "mnemonic"
+    )]
+    private_key: Vec<String>,
+    /// BIP-39 mnemonic used to derive signer pool wallets, in addition to any private keys.
+    #[arg(long, env)]
+    mnemonic: Option<String>,
+    /// Number of wallets to derive from the mnemonic.
+    #[arg(long, env, default_value_t = 1)]
+    mnemonic_count: u32,
+    /// Strategy used to spread requests over the signer pool.
+    #[arg(long, env, value_enum, default_value_t = SignerStrategy::RoundRobin)]
+    signer_strategy: SignerStrategy,
+    /// Service used to verify that drip requests come from humans.
+    #[arg(long, env, value_enum, default_value_t = CaptchaProvider::Turnstile)]
+    captcha_provider: CaptchaProvider,
+    /// Cloudflare secret key. Required by the `turnstile` captcha provider.
     #[arg(short, long, env)]
-    ts_secret_key: String,
+    ts_secret_key: Option<String>,
+    /// Hostnames Turnstile tokens must have been issued on. Any hostname is accepted if empty.
+    #[arg(long, env, value_delimiter = ',')]
+    turnstile_hostnames: Vec<String>,
+    /// Widget action Turnstile tokens must have been issued for.
+    #[arg(long, env)]
+    turnstile_action: Option<String>,
+    /// Customer data (`cdata`) Turnstile tokens must carry.
+    #[arg(long, env)]
+    turnstile_cdata: Option<String>,
+    /// hCaptcha or reCAPTCHA secret key.
+    #[arg(long, env)]
+    captcha_secret_key: Option<String>,
+    /// Minimum score (0.0 to 1.0) a reCAPTCHA v3 response needs to pass.
+    #[arg(long, env, default_value_t = 0.5)]
+    recaptcha_min_score: f64,
+    /// Leading zero bits required of a proof-of-work stamp.
+    #[arg(long, env, default_value_t = 20)]
+    pow_difficulty: u32,
+    /// Secret used to sign proof-of-work challenges issued by `/challenge`. Challenges are
+    /// disabled if not set.
+    #[arg(long, env)]
+    challenge_secret: Option<String>,
+    /// Difficulty, in leading zero bits, of challenges at low drip volume.
+    #[arg(long, env, default_value_t = 20)]
+    challenge_difficulty: u32,
+    /// Maximum difficulty of challenges under high drip volume.
+    #[arg(long, env, default_value_t = 28)]
+    challenge_max_difficulty: u32,
+    /// Drips per minute, per replica, above which challenge difficulty increases.
+    #[arg(long, env, default_value_t = 30)]
+    challenge_target_rate: u32,
+    /// Seconds a challenge stays valid.
+    #[arg(long, env, default_value_t = 300)]
+    challenge_ttl: u64,
     /// IP address of the proxy server this is running behind.
     #[arg(long, env, value_delimiter = ',')]
     trusted_proxy_ips: Vec<IpAddr>,
     /// RECALL faucet contract address.
     #[arg(long, env)]
     faucet_address: Address,
+    /// JSON ABI file of the deployed faucet, either a plain ABI or a build artifact with an `abi`
+    /// field. The compiled-in faucet ABI is used if not set.
+    #[arg(long, env)]
+    faucet_abi: Option<PathBuf>,
+    /// Name or signature, e.g. `drip(address,string[])`, of the faucet drip method.
+    #[arg(long, env, default_value = "drip")]
+    faucet_drip_method: String,
+    /// Arguments of the faucet drip method, in order.
+    #[arg(
+        long,
+        env,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "recipient,keys"
+    )]
+    faucet_drip_args: Vec<DripArg>,
+    /// Faucet errors meaning a key's cooldown hasn't passed, answered with `429`.
+    #[arg(long, env, value_delimiter = ',', default_value = "TryLater")]
+    faucet_rate_limited_errors: Vec<String>,
+    /// Faucet errors meaning the faucet is out of funds, answered with `503`.
+    #[arg(long, env, value_delimiter = ',', default_value = "FaucetEmpty")]
+    faucet_empty_errors: Vec<String>,
     /// Target chain Ethereum RPC URL.
     #[arg(long, env, default_value = "http://127.0.0.1:8545")]
     evm_rpc_url: String,
@@ -41,15 +120,163 @@ struct Cli {
     #[arg(short, long, env, default_value_t = false)]
     quiet: bool,
 
+    /// Rate limit per client IP for each of `/register` and `/drip`, as `<requests>/<seconds>`.
+    /// `0/<seconds>` disables the limit.
+    #[arg(long, env, default_value = "10/60")]
+    rate_limit_ip: Quota,
+    /// Rate limit per client network (IPv4 /24 or IPv6 /64), as `<requests>/<seconds>`.
+    #[arg(long, env, default_value = "60/60")]
+    rate_limit_prefix: Quota,
+    /// Rate limit per target address, as `<requests>/<seconds>`.
+    #[arg(long, env, default_value = "3/3600")]
+    rate_limit_address: Quota,
+
+    /// How the fees of sent transactions are chosen.
+    #[arg(long, env, value_enum, default_value_t = FeeStrategyKind::Percentile)]
+    fee_strategy: FeeStrategyKind,
+    /// Max fee per gas, in wei, of the `fixed` fee strategy.
+    #[arg(long, env, required_if_eq("fee_strategy", "fixed"))]
+    fixed_max_fee: Option<u128>,
+    /// Priority fee per gas, in wei, of the `fixed` fee strategy.
+    #[arg(long, env, required_if_eq("fee_strategy", "fixed"))]
+    fixed_priority_fee: Option<u128>,
+    /// Minimum priority fee per gas, in wei.
+    #[arg(long, env)]
+    min_priority_fee: Option<u128>,
+    /// Maximum priority fee per gas, in wei.
+    #[arg(long, env)]
+    max_priority_fee: Option<u128>,
+    /// Minimum max fee per gas, or gas price of legacy transactions, in wei.
+    #[arg(long, env)]
+    min_fee_cap: Option<u128>,
+    /// Maximum max fee per gas, or gas price of legacy transactions, in wei.
+    #[arg(long, env)]
+    max_fee_cap: Option<u128>,
+    /// Milliseconds between checks for a new block to refresh the cached fee estimate at. Fees
+    /// are estimated per transaction if `0`.
+    #[arg(long, env, default_value_t = 1000)]
+    fee_refresh_interval_ms: u64,
+    /// Milliseconds after which the cached fee estimate is stale and fees are estimated per
+    /// transaction until a refresh succeeds.
+    #[arg(long, env, default_value_t = 30000)]
+    fee_max_age_ms: u64,
+    /// Factor gas estimates are multiplied by to get the gas limit of sent transactions, at
+    /// least 1.0.
+    #[arg(long, env, default_value_t = 1.0, value_parser = parse_gas_limit_multiplier)]
+    gas_limit_multiplier: f64,
+    /// Maximum gas limit of sent transactions. Requests needing more gas are rejected.
+    #[arg(long, env)]
+    gas_limit_cap: Option<u64>,
+
+    /// Maximum balance, in wei, an address may hold to receive a drip. Unlimited if not set.
+    #[arg(long, env)]
+    drip_max_balance: Option<u128>,
+    /// Signals each drip gets a faucet key for, enforcing the faucet cooldown per signal.
+    #[arg(
+        long,
+        env,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "address,ip"
+    )]
+    drip_keys: Vec<DripKeyKind>,
+    /// Secrets faucet keys are derived with, as `<version>:<secret>`. Keys are HMACs of the
+    /// drip signals, so no plaintext IP address is sent on chain. To rotate, add the new secret
+    /// and remove the old one once the faucet cooldown has passed.
+    #[arg(long, env, value_delimiter = ',')]
+    drip_key_secrets: Vec<KeySecret>,
+    /// Prefix length of `ip_prefix` faucet keys for IPv4 clients.
+    #[arg(long, env, default_value_t = 24)]
+    drip_key_ipv4_prefix: u8,
+    /// Prefix length of `ip_prefix` faucet keys for IPv6 clients.
+    #[arg(long, env, default_value_t = 64)]
+    drip_key_ipv6_prefix: u8,
+
+    /// Lotus-compatible JSON-RPC URL, e.g. http://127.0.0.1:1234/rpc/v1, used to register f1 and
+    /// f3 addresses with native messages. Those addresses are rejected if not set.
+    #[arg(long, env)]
+    lotus_rpc_url: Option<String>,
+    /// Optional bearer token for the Lotus API.
+    #[arg(long, env)]
+    lotus_token: Option<String>,
+
+    /// Where rate-limit counters, idempotency records and in-flight locks are kept. Use `redis`
+    /// to share them between replicas.
+    #[arg(long, env, value_enum, default_value_t = StateBackend::Memory)]
+    state_backend: StateBackend,
+    /// Redis URL for the `redis` state backend, e.g. redis://127.0.0.1:6379/0
+    #[arg(long, env)]
+    redis_url: Option<String>,
+
+    /// Bearer token required for `/admin` endpoints. Admin endpoints are disabled if not set.
+    #[arg(long, env)]
+    admin_token: Option<String>,
+
     /// Prometheus metrics socket address, e.g. 127.0.0.1:9090
     #[arg(long, env)]
     metrics_listen_address: Option<SocketAddr>,
+
+    /// Path of the embedded database recording every register and drip request.
+    /// The ledger is disabled if not set.
+    #[arg(long, env)]
+    ledger_path: Option<PathBuf>,
+    /// Embedded database backend used for the ledger.
+    #[arg(long, env, value_enum, default_value_t = LedgerBackend::Sqlite)]
+    ledger_backend: LedgerBackend,
+
+    /// Seconds a transaction may stay unmined before it's replaced with bumped fees.
+    #[arg(long, env, default_value_t = 60)]
+    stuck_tx_timeout: u64,
+    /// Fee increase, in percent, applied to each replacement transaction (at least 10).
+    #[arg(long, env, default_value_t = 20)]
+    fee_bump_percent: u64,
+    /// Maximum fee per gas, in wei, that replacement transactions may use.
+    #[arg(long, env, default_value_t = 1_000_000_000_000)]
+    fee_ceiling: u128,
+
+    /// Maximum gas fees, in wei, the signer wallets may spend per hour. Unlimited if not set.
+    #[arg(long, env)]
+    max_gas_spend_per_hour: Option<u128>,
+    /// Maximum gas fees, in wei, the signer wallets may spend per day. Unlimited if not set.
+    #[arg(long, env)]
+    max_gas_spend_per_day: Option<u128>,
+    /// Maximum number of transactions, including fee bump replacements, the signer wallets may
+    /// send per minute. Unlimited if not set.
+    #[arg(long, env)]
+    max_txs_per_minute: Option<u64>,
+
+    /// Coalesce concurrent register requests into batched multicall transactions.
+    #[arg(long, env, default_value_t = false)]
+    batch_register: bool,
+    /// Coalesce concurrent drip requests into batched transactions to `--drip-batch-address`.
+    #[arg(long, env, default_value_t = false)]
+    batch_drip: bool,
+    /// Contract drip batches are sent through. It must implement Multicall3's `aggregate3Value`,
+    /// accept calls from the signer wallets only and be allowed to drip by the faucet. Required
+    /// by `--batch-drip`.
+    #[arg(long, env)]
+    drip_batch_address: Option<Address>,
+    /// Multicall3 contract used to send batched register transactions.
+    #[arg(
+        long,
+        env,
+        default_value = "0xcA11bde05977b3631167028862bE2a173976CA11"
+    )]
+    multicall_address: Address,
+    /// Milliseconds to collect requests into a batch after the first one arrives.
+    #[arg(long, env, default_value_t = 250)]
+    batch_window_ms: u64,
+    /// Maximum number of requests per batch.
+    #[arg(long, env, default_value_t = 50)]
+    batch_max_size: usize,
 }
 
 #[derive(Clone, Debug, Subcommand)]
 enum Commands {
     /// Start the registration service.
     Start,
+    /// Resynchronise signer nonces with the chain and fill nonce gaps with no-op transfers.
+    NonceRepair,
 }
 
 #[tokio::main]
@@ -63,5 +290,8 @@ async fn main() -> anyhow::Result<()> {
         .timestamp(Timestamp::Millisecond)
         .init()?;
 
-    run(cli).await
+    match cli.command {
+        Commands::Start => run(cli).await,
+        Commands::NonceRepair => nonce_repair(cli).await,
+    }
 }
@@ -1,47 +1,283 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Context;
-use cf_turnstile::TurnstileClient;
-use ethers::prelude::{
-    Http, LocalWallet, Middleware, NonceManagerMiddleware, Provider, Signer, SignerMiddleware,
-};
-use log::info;
+use anyhow::{bail, Context};
+use ethers::prelude::{Http, Middleware, Provider, U256};
+use log::{info, warn};
 use util::log_failed_request;
 use warp::{Filter, Rejection, Reply};
 
-use crate::server::shared::{DefaultSignerMiddleware, Faucet, FaucetContract};
+use crate::server::batch::BatchConfig;
+use crate::server::budget::{BudgetConfig, SpendingBudget};
+use crate::server::challenge::{ChallengeConfig, ChallengeIssuer};
+use crate::server::faucet::{FaucetAbi, FaucetAbiConfig};
+use crate::server::fees::{build_fee_strategy, FeeConfig, FeeOracle, FeeOracleConfig};
+use crate::server::keys::{KeyConfig, KeyDeriver};
+use crate::server::ledger::Ledger;
+use crate::server::lotus::{LotusConfig, NativeSender};
+use crate::server::monitor::{MonitorConfig, TxMonitor};
+use crate::server::pool::{load_wallets, GasConfig, SignerPool};
+use crate::server::ratelimit::{RateLimitConfig, RateLimiter};
+use crate::server::shared::RequestDedupe;
+use crate::server::state::SharedState;
+use crate::server::verifier::{build_verifier, VerifierConfig};
 use crate::Cli;
 
+pub use faucet::DripArg;
+pub use fees::FeeStrategyKind;
+pub use keys::{DripKeyKind, KeySecret};
+pub use ledger::LedgerBackend;
+pub use pool::{parse_gas_limit_multiplier, SignerStrategy};
+pub use ratelimit::Quota;
+pub use state::StateBackend;
+pub use verifier::CaptchaProvider;
+
+mod account;
+mod address;
+mod admin;
+mod batch;
+mod budget;
+mod challenge;
 mod drip;
+mod faucet;
+mod fees;
+mod keys;
+mod ledger;
+mod lotus;
+mod monitor;
+mod nonce;
+mod pool;
+mod ratelimit;
 mod register;
+mod revert;
 mod shared;
+mod state;
+mod tx;
 mod util;
+mod verifier;
 
 /// Server entrypoint for the service.
 pub async fn run(cli: Cli) -> anyhow::Result<()> {
-    let private_key = cli
-        .private_key
-        .strip_prefix("0x")
-        .unwrap_or(&cli.private_key);
-    let private_key = hex::decode(private_key)?;
     let trusted_proxy_ips = cli.trusted_proxy_ips;
     let faucet_address = cli.faucet_address;
     let evm_rpc_url = cli.evm_rpc_url;
 
     let provider = Provider::<Http>::try_from(evm_rpc_url)?;
     let chain_id = provider.get_chainid().await?.as_u64();
-    let wallet = LocalWallet::from_bytes(&private_key)?.with_chain_id(chain_id);
-    let provider_with_nonce = NonceManagerMiddleware::new(provider, wallet.address());
-    let client: DefaultSignerMiddleware = SignerMiddleware::new(provider_with_nonce, wallet);
-    let client = Arc::new(client);
-    let faucet: Faucet = FaucetContract::new(faucet_address, client.clone());
-    let turnstile = TurnstileClient::new(cli.ts_secret_key.into());
+    let wallets = load_wallets(
+        &cli.private_key,
+        cli.mnemonic.as_deref(),
+        cli.mnemonic_count,
+        chain_id,
+    )?;
+    let fees = build_fee_strategy(FeeConfig {
+        strategy: cli.fee_strategy,
+        fixed_max_fee: cli.fixed_max_fee.map(U256::from),
+        fixed_priority_fee: cli.fixed_priority_fee.map(U256::from),
+        min_priority_fee: cli.min_priority_fee.map(U256::from),
+        max_priority_fee: cli.max_priority_fee.map(U256::from),
+        min_fee_cap: cli.min_fee_cap.map(U256::from),
+        max_fee_cap: cli.max_fee_cap.map(U256::from),
+    })?;
+    info!("using {} fee strategy", fees.name());
+    let fees = if cli.fee_refresh_interval_ms > 0 {
+        FeeOracle::spawn(
+            fees,
+            provider.clone(),
+            FeeOracleConfig {
+                refresh_interval: Duration::from_millis(cli.fee_refresh_interval_ms),
+                max_age: Duration::from_millis(cli.fee_max_age_ms),
+            },
+        )
+    } else {
+        fees
+    };
+    let pool = SignerPool::new(
+        provider,
+        wallets,
+        faucet_address,
+        cli.multicall_address,
+        cli.signer_strategy,
+        fees,
+        GasConfig {
+            multiplier: cli.gas_limit_multiplier,
+            cap: cli.gas_limit_cap,
+        },
+    )?;
+    info!(
+        "using {} signer wallet(s) with {:?} strategy",
+        pool.signers().len(),
+        cli.signer_strategy
+    );
+    let pool = Arc::new(pool);
+    let state = Arc::new(open_state(cli.state_backend, cli.redis_url.as_deref())?);
+    let verifier = build_verifier(
+        VerifierConfig {
+            provider: cli.captcha_provider,
+            ts_secret_key: cli.ts_secret_key,
+            turnstile_hostnames: cli.turnstile_hostnames,
+            turnstile_action: cli.turnstile_action,
+            turnstile_cdata: cli.turnstile_cdata,
+            captcha_secret_key: cli.captcha_secret_key,
+            recaptcha_min_score: cli.recaptcha_min_score,
+            pow_difficulty: cli.pow_difficulty,
+        },
+        state.clone(),
+    )?;
+    info!("verifying drip requests with {}", verifier.name());
+    let native = cli.lotus_rpc_url.map(|url| {
+        let key = pool.primary().client.signer().signer().clone();
+        let native = NativeSender::new(
+            LotusConfig {
+                url,
+                token: cli.lotus_token,
+            },
+            key,
+        );
+        info!(
+            "registering f1 and f3 addresses with native messages from {}",
+            native.address()
+        );
+        Arc::new(native)
+    });
+    let challenges = cli.challenge_secret.map(|secret| {
+        info!("issuing proof-of-work challenges");
+        Arc::new(ChallengeIssuer::new(
+            ChallengeConfig {
+                secret: secret.into_bytes(),
+                base_difficulty: cli.challenge_difficulty,
+                max_difficulty: cli.challenge_max_difficulty,
+                target_rate: cli.challenge_target_rate,
+                ttl: Duration::from_secs(cli.challenge_ttl),
+            },
+            state.clone(),
+        ))
+    });
+    let key_deriver = Arc::new(
+        KeyDeriver::new(KeyConfig {
+            kinds: cli.drip_keys,
+            secrets: cli.drip_key_secrets,
+            ipv4_prefix: cli.drip_key_ipv4_prefix,
+            ipv6_prefix: cli.drip_key_ipv6_prefix,
+        })
+        .context("failed to configure drip keys, set DRIP_KEY_SECRETS")?,
+    );
+    let ledger = match &cli.ledger_path {
+        Some(path) => {
+            info!(
+                "recording requests to {:?} ledger at {}",
+                cli.ledger_backend,
+                path.display()
+            );
+            Ledger::open(cli.ledger_backend, path)?
+        }
+        None => Ledger::disabled(),
+    };
+    let budget = Arc::new(SpendingBudget::new(
+        BudgetConfig {
+            max_gas_spend_per_hour: cli.max_gas_spend_per_hour.map(U256::from),
+            max_gas_spend_per_day: cli.max_gas_spend_per_day.map(U256::from),
+            max_txs_per_minute: cli.max_txs_per_minute,
+        },
+        state.clone(),
+    ));
+    let monitor = TxMonitor::new(
+        MonitorConfig {
+            stuck_timeout: Duration::from_secs(cli.stuck_tx_timeout),
+            fee_bump_percent: cli.fee_bump_percent,
+            fee_ceiling: U256::from(cli.fee_ceiling),
+        },
+        ledger.clone(),
+        budget.clone(),
+    );
+
+    let faucet_abi = FaucetAbi::load(FaucetAbiConfig {
+        abi_path: cli.faucet_abi,
+        drip_method: cli.faucet_drip_method,
+        drip_args: cli.faucet_drip_args,
+        rate_limited_errors: cli.faucet_rate_limited_errors,
+        empty_errors: cli.faucet_empty_errors,
+    })?;
+    info!(
+        "dripping with faucet method {}",
+        faucet_abi.drip_signature()
+    );
+    let faucet_abi = Arc::new(faucet_abi);
+
+    let batch_config = BatchConfig {
+        window: Duration::from_millis(cli.batch_window_ms),
+        max_size: cli.batch_max_size,
+    };
+    let register_batcher = cli.batch_register.then(|| {
+        info!(
+            "batching register requests through multicall contract {:?}",
+            cli.multicall_address
+        );
+        register::register_batcher(batch_config, pool.clone(), monitor.clone())
+    });
+    let drip_batcher = match (cli.batch_drip, cli.drip_batch_address) {
+        (false, _) => None,
+        (true, None) => bail!(
+            "batching drips requires DRIP_BATCH_ADDRESS, a batch contract only the signer \
+             wallets can call"
+        ),
+        (true, Some(batch_address)) => {
+            drip::check_batch_contract(&pool, batch_address).await?;
+            info!(
+                "batching drip requests through batch contract {:?}",
+                batch_address
+            );
+            Some(drip::drip_batcher(
+                batch_config,
+                pool.clone(),
+                batch_address,
+                faucet_abi.clone(),
+                monitor.clone(),
+            ))
+        }
+    };
+
+    let limiter = Arc::new(RateLimiter::new(
+        RateLimitConfig {
+            ip: cli.rate_limit_ip,
+            prefix: cli.rate_limit_prefix,
+            address: cli.rate_limit_address,
+        },
+        state.clone(),
+    ));
+    let dedupe = RequestDedupe::new(state);
 
     let health_route = warp::path!("health")
         .and(warp::get())
         .and_then(handle_health);
-    let register_route = register::register_route(client.clone());
-    let drip_route = drip::drip_route(trusted_proxy_ips, faucet, Arc::new(turnstile));
+    let register_route = register::register_route(
+        trusted_proxy_ips.clone(),
+        pool.clone(),
+        limiter.clone(),
+        dedupe.clone(),
+        native,
+        ledger.clone(),
+        monitor.clone(),
+        register_batcher,
+    );
+    let drip_route = drip::drip_route(
+        trusted_proxy_ips,
+        pool.clone(),
+        limiter,
+        dedupe,
+        verifier,
+        challenges.clone(),
+        cli.drip_max_balance.map(U256::from),
+        key_deriver,
+        faucet_abi,
+        ledger.clone(),
+        monitor.clone(),
+        drip_batcher,
+    );
+    let challenge_route = challenge::challenge_route(challenges);
+    let address_route = address::address_route();
+    let tx_route = tx::tx_route(pool.primary().client.clone(), ledger);
+    let admin_routes = admin::admin_routes(cli.admin_token, pool.clone(), monitor, budget);
     let log = warp::log::custom(log_failed_request);
     let request_metrics = warp::log::custom(util::request_metrics);
 
@@ -54,11 +290,19 @@ pub async fn run(cli: Cli) -> anyhow::Result<()> {
     let router = health_route
         .or(register_route)
         .or(drip_route)
+        .or(challenge_route)
+        .or(address_route)
+        .or(tx_route)
+        .or(admin_routes)
         .recover(shared::handle_rejection)
         .with(
             warp::cors()
                 .allow_any_origin()
-                .allow_headers(vec!["Content-Type"])
+                .allow_headers(vec![
+                    "Content-Type",
+                    "Idempotency-Key",
+                    "X-Device-Fingerprint",
+                ])
                 .allow_methods(vec!["GET", "POST"]),
         )
         .with(request_metrics)
@@ -71,6 +315,95 @@ pub async fn run(cli: Cli) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Repairs the nonce sequences of all signer wallets and prints a report.
+///
+/// The highest nonce recorded in the ledger, if any, is taken as the service's local nonce, so
+/// nonces the service used but the chain never saw are filled. Waits for the fills to be mined.
+pub async fn nonce_repair(cli: Cli) -> anyhow::Result<()> {
+    let provider = Provider::<Http>::try_from(cli.evm_rpc_url)?;
+    let chain_id = provider.get_chainid().await?.as_u64();
+    let wallets = load_wallets(
+        &cli.private_key,
+        cli.mnemonic.as_deref(),
+        cli.mnemonic_count,
+        chain_id,
+    )?;
+    let fees = build_fee_strategy(FeeConfig {
+        strategy: cli.fee_strategy,
+        fixed_max_fee: cli.fixed_max_fee.map(U256::from),
+        fixed_priority_fee: cli.fixed_priority_fee.map(U256::from),
+        min_priority_fee: cli.min_priority_fee.map(U256::from),
+        max_priority_fee: cli.max_priority_fee.map(U256::from),
+        min_fee_cap: cli.min_fee_cap.map(U256::from),
+        max_fee_cap: cli.max_fee_cap.map(U256::from),
+    })?;
+    let pool = SignerPool::new(
+        provider,
+        wallets,
+        cli.faucet_address,
+        cli.multicall_address,
+        cli.signer_strategy,
+        fees,
+        GasConfig {
+            multiplier: cli.gas_limit_multiplier,
+            cap: cli.gas_limit_cap,
+        },
+    )?;
+    let ledger = match &cli.ledger_path {
+        Some(path) => Ledger::open(cli.ledger_backend, path)?,
+        None => Ledger::disabled(),
+    };
+    let state = Arc::new(open_state(cli.state_backend, cli.redis_url.as_deref())?);
+    let budget = Arc::new(SpendingBudget::new(
+        BudgetConfig {
+            max_gas_spend_per_hour: cli.max_gas_spend_per_hour.map(U256::from),
+            max_gas_spend_per_day: cli.max_gas_spend_per_day.map(U256::from),
+            max_txs_per_minute: cli.max_txs_per_minute,
+        },
+        state,
+    ));
+    let monitor = TxMonitor::new(
+        MonitorConfig {
+            stuck_timeout: Duration::from_secs(cli.stuck_tx_timeout),
+            fee_bump_percent: cli.fee_bump_percent,
+            fee_ceiling: U256::from(cli.fee_ceiling),
+        },
+        ledger.clone(),
+        budget,
+    );
+
+    let mut receipts = vec![];
+    for signer in pool.signers() {
+        if let Some(nonce) = ledger.max_nonce(signer.address()).await? {
+            signer.seed_nonce(nonce + 1).await;
+        }
+        let (report, fills) = signer.repair_nonce(&monitor).await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        receipts.extend(fills);
+    }
+    // Fills are replaced by the monitor if they get stuck, so wait for them to be mined.
+    for receipt in receipts {
+        match receipt.await {
+            Ok(Ok(receipt)) => info!("nonce gap fill {:?} mined", receipt.transaction_hash),
+            Ok(Err(e)) => warn!("nonce gap fill failed: {}", e),
+            Err(_) => warn!("nonce gap fill did not return a receipt"),
+        }
+    }
+    Ok(())
+}
+
+/// Opens the shared state on the configured backend.
+fn open_state(backend: StateBackend, redis_url: Option<&str>) -> anyhow::Result<SharedState> {
+    match backend {
+        StateBackend::Memory => Ok(SharedState::memory()),
+        StateBackend::Redis => {
+            let url = redis_url.context("--redis-url is required with the redis state backend")?;
+            info!("keeping shared state in redis");
+            SharedState::redis(url)
+        }
+    }
+}
+
 async fn handle_health() -> Result<impl Reply, Rejection> {
     Ok(warp::reply::reply())
 }
@@ -0,0 +1,37 @@
+use ethers::prelude::{Address, Middleware, U256};
+use serde::Serialize;
+
+/// On-chain state of an address.
+#[derive(Clone, Debug, Serialize)]
+pub struct AccountState {
+    pub nonce: U256,
+    pub balance: U256,
+    /// Whether the address holds contract code.
+    pub has_code: bool,
+}
+
+impl AccountState {
+    /// Returns whether the address was used on chain, in which case the FVM already created an
+    /// account for it.
+    pub fn exists(&self) -> bool {
+        !self.nonce.is_zero() || !self.balance.is_zero() || self.has_code
+    }
+}
+
+/// Fetches the nonce, balance and code of an address.
+pub async fn account_state<M: Middleware>(
+    client: &M,
+    address: Address,
+) -> anyhow::Result<AccountState> {
+    let (nonce, balance, code) = tokio::try_join!(
+        client.get_transaction_count(address, None),
+        client.get_balance(address, None),
+        client.get_code(address, None),
+    )
+    .map_err(|e| anyhow::anyhow!("failed to fetch account state: {}", e))?;
+    Ok(AccountState {
+        nonce,
+        balance,
+        has_code: !code.is_empty(),
+    })
+}
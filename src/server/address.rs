@@ -0,0 +1,413 @@
+use anyhow::{anyhow, bail};
+use data_encoding::{Encoding, Specification};
+use ethers::prelude::Address;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::json;
+use warp::{Filter, Rejection, Reply};
+
+use crate::server::shared::BadRequest;
+
+/// Namespace of the Ethereum Address Manager, the only one f4 addresses are accepted for.
+const EAM_NAMESPACE: u64 = 10;
+
+const CHECKSUM_LEN: usize = 4;
+
+/// Prefix of EVM addresses that encode an actor ID in the last eight bytes.
+const MASKED_ID_PREFIX: [u8; 12] = [0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// Lowercase RFC 4648 base32 without padding, as used by Filecoin addresses.
+pub static BASE32: Lazy<Encoding> = Lazy::new(|| {
+    let mut spec = Specification::new();
+    spec.symbols.push_str("abcdefghijklmnopqrstuvwxyz234567");
+    spec.encoding().expect("valid base32 specification")
+});
+
+/// Protocol of a Filecoin address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressKind {
+    /// `0x` EVM address.
+    Eth,
+    /// f0 actor ID address.
+    Id,
+    /// f1 secp256k1 key address.
+    Secp256k1,
+    /// f2 actor address.
+    Actor,
+    /// f3 BLS key address.
+    Bls,
+    /// f410 delegated address managed by the Ethereum Address Manager.
+    Delegated,
+}
+
+impl AddressKind {
+    /// Returns the protocol byte of the address kind.
+    fn protocol(&self) -> u8 {
+        match self {
+            AddressKind::Id => 0,
+            AddressKind::Secp256k1 => 1,
+            AddressKind::Actor => 2,
+            AddressKind::Bls => 3,
+            AddressKind::Eth | AddressKind::Delegated => 4,
+        }
+    }
+}
+
+/// Target address of a request, as an EVM address or any Filecoin address format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TargetAddress {
+    /// EVM address.
+    Eth(Address),
+    /// f410 delegated address, with the EVM address it wraps.
+    Delegated(Address),
+    /// Actor ID.
+    Id(u64),
+    /// Address of a native key or actor, which has no EVM form.
+    Native { kind: AddressKind, payload: Vec<u8> },
+}
+
+impl TargetAddress {
+    /// Parses a `0x` EVM address or an f0, f1, f2, f3 or f410 Filecoin address, with either
+    /// the mainnet `f` or testnet `t` prefix. Checksums of Filecoin addresses are verified.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let unprefixed_hex = s.len() == 40 && s.bytes().all(|b| b.is_ascii_hexdigit());
+        if s.starts_with("0x") || s.starts_with("0X") || unprefixed_hex {
+            let address = s
+                .parse::<Address>()
+                .map_err(|e| anyhow!("invalid ethereum address: {}", e))?;
+            return Ok(Self::from_eth(address));
+        }
+
+        let mut chars = s.chars();
+        if !matches!(chars.next(), Some('f' | 't')) {
+            bail!("unknown address format, expected a 0x, f or t address");
+        }
+        let rest = chars.as_str();
+        let Some(protocol) = rest.chars().next() else {
+            bail!("missing filecoin address protocol");
+        };
+        let data = &rest[protocol.len_utf8()..];
+        match protocol {
+            '0' => {
+                if data.is_empty()
+                    || data.len() > 20
+                    || !data.bytes().all(|b| b.is_ascii_digit())
+                    || (data.len() > 1 && data.starts_with('0'))
+                {
+                    bail!("invalid filecoin id address");
+                }
+                Ok(Self::Id(
+                    data.parse().map_err(|_| anyhow!("actor id out of range"))?,
+                ))
+            }
+            '1' => Self::native(AddressKind::Secp256k1, data, 20),
+            '2' => Self::native(AddressKind::Actor, data, 20),
+            '3' => Self::native(AddressKind::Bls, data, 48),
+            '4' => {
+                let (namespace, data) = data
+                    .split_once('f')
+                    .ok_or_else(|| anyhow!("invalid filecoin delegated address"))?;
+                let namespace: u64 = namespace
+                    .parse()
+                    .ok()
+                    .filter(|n: &u64| n.to_string() == namespace)
+                    .ok_or_else(|| anyhow!("invalid filecoin delegated address namespace"))?;
+                if namespace != EAM_NAMESPACE {
+                    bail!(
+                        "unsupported delegated address namespace {}, only f410 addresses are accepted",
+                        namespace
+                    );
+                }
+                let mut prefix = vec![4];
+                prefix.extend(leb128(namespace));
+                let payload = decode_checked(&prefix, data, 20)?;
+                Ok(Self::Delegated(Address::from_slice(&payload)))
+            }
+            _ => bail!("unknown filecoin address protocol {}", protocol),
+        }
+    }
+
+    fn from_eth(address: Address) -> Self {
+        let bytes = address.as_bytes();
+        if bytes[..12] == MASKED_ID_PREFIX {
+            let mut id = [0u8; 8];
+            id.copy_from_slice(&bytes[12..]);
+            Self::Id(u64::from_be_bytes(id))
+        } else {
+            Self::Eth(address)
+        }
+    }
+
+    fn native(kind: AddressKind, data: &str, len: usize) -> anyhow::Result<Self> {
+        let payload = decode_checked(&[kind.protocol()], data, len)?;
+        Ok(Self::Native { kind, payload })
+    }
+
+    pub fn kind(&self) -> AddressKind {
+        match self {
+            Self::Eth(_) => AddressKind::Eth,
+            Self::Delegated(_) => AddressKind::Delegated,
+            Self::Id(_) => AddressKind::Id,
+            Self::Native { kind, .. } => *kind,
+        }
+    }
+
+    /// Returns the EVM form of the address: the address itself for EVM and f410 addresses,
+    /// the masked ID address for f0 addresses, and `None` for native f1, f2 and f3 addresses.
+    pub fn eth_address(&self) -> Option<Address> {
+        match self {
+            Self::Eth(address) | Self::Delegated(address) => Some(*address),
+            Self::Id(id) => {
+                let mut bytes = [0u8; 20];
+                bytes[..12].copy_from_slice(&MASKED_ID_PREFIX);
+                bytes[12..].copy_from_slice(&id.to_be_bytes());
+                Some(Address::from(bytes))
+            }
+            Self::Native { .. } => None,
+        }
+    }
+
+    /// Returns the EVM form of the address, or an error explaining that native addresses
+    /// can't be reached through the EVM.
+    pub fn require_eth(&self) -> anyhow::Result<Address> {
+        self.eth_address().ok_or_else(|| {
+            anyhow!(
+                "{} addresses can't be used with EVM transactions, use a 0x, f410 or f0 address",
+                match self.kind() {
+                    AddressKind::Secp256k1 => "f1",
+                    AddressKind::Bls => "f3",
+                    _ => "f2",
+                }
+            )
+        })
+    }
+
+    /// Returns the canonical form of the address, used to key rate limits and deduplication:
+    /// the lowercase EVM address if there is one, else the mainnet Filecoin address.
+    pub fn canonical(&self) -> String {
+        match self.eth_address() {
+            Some(address) => format!("{:?}", address),
+            None => self.to_filecoin('f'),
+        }
+    }
+
+    /// Returns the binary form of the Filecoin address: the protocol byte followed by the
+    /// payload. EVM addresses are encoded as f410 delegated addresses.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Id(id) => {
+                let mut bytes = vec![0];
+                bytes.extend(leb128(*id));
+                bytes
+            }
+            Self::Eth(address) | Self::Delegated(address) => {
+                let mut bytes = vec![4];
+                bytes.extend(leb128(EAM_NAMESPACE));
+                bytes.extend_from_slice(address.as_bytes());
+                bytes
+            }
+            Self::Native { kind, payload } => {
+                let mut bytes = vec![kind.protocol()];
+                bytes.extend_from_slice(payload);
+                bytes
+            }
+        }
+    }
+
+    /// Formats the address as a Filecoin address with the given network prefix (`f` or `t`).
+    /// EVM addresses are formatted as f410 delegated addresses.
+    pub fn to_filecoin(&self, network: char) -> String {
+        match self {
+            Self::Id(id) => format!("{}0{}", network, id),
+            Self::Eth(address) | Self::Delegated(address) => {
+                let mut prefix = vec![4];
+                prefix.extend(leb128(EAM_NAMESPACE));
+                format!(
+                    "{}4{}f{}",
+                    network,
+                    EAM_NAMESPACE,
+                    encode_checked(&prefix, address.as_bytes())
+                )
+            }
+            Self::Native { kind, payload } => {
+                let protocol = kind.protocol();
+                format!(
+                    "{}{}{}",
+                    network,
+                    protocol,
+                    encode_checked(&[protocol], payload)
+                )
+            }
+        }
+    }
+}
+
+/// Decodes a base32 payload followed by its checksum over `prefix ++ payload`.
+fn decode_checked(prefix: &[u8], data: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+    let bytes = BASE32
+        .decode(data.to_ascii_lowercase().as_bytes())
+        .map_err(|e| anyhow!("invalid filecoin address encoding: {}", e))?;
+    if bytes.len() != len + CHECKSUM_LEN {
+        bail!("invalid filecoin address length");
+    }
+    let (payload, checksum) = bytes.split_at(len);
+    if checksum != address_checksum(prefix, payload) {
+        bail!("invalid filecoin address checksum");
+    }
+    Ok(payload.to_vec())
+}
+
+fn encode_checked(prefix: &[u8], payload: &[u8]) -> String {
+    let mut bytes = payload.to_vec();
+    bytes.extend_from_slice(&address_checksum(prefix, payload));
+    BASE32.encode(&bytes)
+}
+
+/// Four byte blake2b checksum of an address.
+fn address_checksum(prefix: &[u8], payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let hash = blake2b_simd::Params::new()
+        .hash_length(CHECKSUM_LEN)
+        .to_state()
+        .update(prefix)
+        .update(payload)
+        .finalize();
+    let mut checksum = [0u8; CHECKSUM_LEN];
+    checksum.copy_from_slice(hash.as_bytes());
+    checksum
+}
+
+fn leb128(mut n: u64) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Route filter for `/address/{addr}` endpoint.
+pub fn address_route() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("address" / String)
+        .and(warp::get())
+        .and_then(handle_address)
+}
+
+/// Handles the `/address/{addr}` request.
+pub async fn handle_address(address: String) -> anyhow::Result<impl Reply, Rejection> {
+    let target = TargetAddress::parse(&address).map_err(|e| {
+        Rejection::from(BadRequest {
+            message: format!("invalid address: {}", e),
+        })
+    })?;
+    Ok(warp::reply::json(&json!({
+        "address": address,
+        "type": target.kind(),
+        "eth_address": target.eth_address(),
+        "mainnet_address": target.to_filecoin('f'),
+        "testnet_address": target.to_filecoin('t'),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETH: &str = "0x52963ef50e27e06d72d59fcb4f3c2a687be3cfef";
+    const F410: &str = "f410fkkld55ioe7qg24wvt7fu6pbknb56ht7pt4zamxa";
+    const T1: &str = "t15ihq5ibzwki2b4ep2f46avlkrqzhpqgtga7pdrq";
+    const F3: &str =
+        "f3vvmn62lofvhjd2ugzca6sof2j2ubwok6cj4xxbfzz4yuxfkgobpihhd2thlanmsh3w2ptld2gqkn2jvlss4a";
+
+    fn parse_err(s: &str) -> String {
+        TargetAddress::parse(s).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_known_addresses() {
+        let eth: Address = ETH.parse().unwrap();
+        let delegated = TargetAddress::parse(F410).unwrap();
+        assert_eq!(delegated, TargetAddress::Delegated(eth));
+        assert_eq!(delegated.eth_address(), Some(eth));
+        assert_eq!(TargetAddress::parse(ETH).unwrap().to_filecoin('f'), F410);
+        assert_eq!(
+            TargetAddress::parse(&F410.replacen('f', "t", 1)).unwrap(),
+            delegated
+        );
+
+        let secp = TargetAddress::parse(T1).unwrap();
+        assert_eq!(secp.kind(), AddressKind::Secp256k1);
+        assert_eq!(
+            hex::encode(&secp.to_bytes()[1..]),
+            "ea0f0ea039b291a0f08fd179e0556a8c3277c0d3"
+        );
+        assert_eq!(secp.to_filecoin('t'), T1);
+        assert_eq!(secp.canonical(), T1.replacen('t', "f", 1));
+        assert!(secp.require_eth().is_err());
+
+        let bls = TargetAddress::parse(F3).unwrap();
+        assert_eq!(bls.kind(), AddressKind::Bls);
+        assert_eq!(bls.to_filecoin('f'), F3);
+        assert_eq!(bls.eth_address(), None);
+    }
+
+    #[test]
+    fn maps_id_addresses_to_masked_eth_addresses() {
+        let id = TargetAddress::parse("f01024").unwrap();
+        assert_eq!(id, TargetAddress::Id(1024));
+        let masked = id.eth_address().unwrap();
+        assert_eq!(
+            format!("{:?}", masked),
+            "0xff00000000000000000000000000000000000400"
+        );
+        assert_eq!(TargetAddress::parse(&format!("{:?}", masked)).unwrap(), id);
+        assert_eq!(id.to_bytes(), vec![0, 0x80, 0x08]);
+        assert_eq!(TargetAddress::parse("t00").unwrap(), TargetAddress::Id(0));
+        assert_eq!(
+            TargetAddress::parse("f018446744073709551615").unwrap(),
+            TargetAddress::Id(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        assert_eq!(
+            parse_err(&F410.replacen("kkld", "kkle", 1)),
+            "invalid filecoin address checksum"
+        );
+        assert_eq!(
+            parse_err(&T1.replacen("5i", "5j", 1)),
+            "invalid filecoin address checksum"
+        );
+        assert!(parse_err(&T1.replacen('t', "x", 1)).starts_with("unknown address format"));
+        assert!(parse_err(&T1.replacen('1', "5", 1)).starts_with("unknown filecoin address"));
+        // The checksum covers the protocol.
+        assert_eq!(
+            parse_err(&T1.replacen('1', "2", 1)),
+            "invalid filecoin address checksum"
+        );
+        assert_eq!(parse_err(&F410[..37]), "invalid filecoin address length");
+
+        for id in ["f0", "f001024", "f00x1", "f018446744073709551616"] {
+            assert!(TargetAddress::parse(id).is_err(), "{}", id);
+        }
+        assert!(parse_err(&F410.replacen("410", "4010", 1)).contains("namespace"));
+        assert!(parse_err("f412fkkld55ioe7qg24wvt7fu6pbknb56ht7pt4zamxa").contains("namespace"));
+        assert!(parse_err("0x52963ef50e27e06d72d59fcb4f3c2a687be3cf").contains("ethereum"));
+    }
+
+    #[test]
+    fn encodes_leb128() {
+        assert_eq!(leb128(0), vec![0]);
+        assert_eq!(leb128(10), vec![10]);
+        assert_eq!(leb128(127), vec![0x7f]);
+        assert_eq!(leb128(128), vec![0x80, 0x01]);
+        assert_eq!(leb128(624485), vec![0xe5, 0x8e, 0x26]);
+        assert_eq!(leb128(u64::MAX).len(), 10);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::server::budget::SpendingBudget;
+use crate::server::monitor::TxMonitor;
+use crate::server::pool::SignerPool;
+use crate::server::shared::{with_admin_auth, with_budget, with_monitor, with_pool};
+use serde_json::json;
+use std::sync::Arc;
+use warp::{Filter, Rejection, Reply};
+
+/// Route filter for `/admin/*` endpoints.
+pub fn admin_routes(
+    admin_token: Option<String>,
+    pool: Arc<SignerPool>,
+    monitor: TxMonitor,
+    budget: Arc<SpendingBudget>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let nonce_repair = warp::path!("admin" / "nonce-repair")
+        .and(warp::post())
+        .and(with_admin_auth(admin_token.clone()))
+        .and(with_pool(pool))
+        .and(with_monitor(monitor))
+        .and_then(handle_nonce_repair);
+    let budget_status = warp::path!("admin" / "budget")
+        .and(warp::get())
+        .and(with_admin_auth(admin_token.clone()))
+        .and(with_budget(budget.clone()))
+        .and_then(handle_budget_status);
+    let budget_reset = warp::path!("admin" / "budget" / "reset")
+        .and(warp::post())
+        .and(with_admin_auth(admin_token))
+        .and(with_budget(budget))
+        .and_then(handle_budget_reset);
+    nonce_repair.or(budget_status).or(budget_reset)
+}
+
+/// Handles the `/admin/nonce-repair` request. Gap fills are broadcast but not waited for.
+pub async fn handle_nonce_repair(
+    pool: Arc<SignerPool>,
+    monitor: TxMonitor,
+) -> anyhow::Result<impl Reply, Rejection> {
+    let mut reports = vec![];
+    for signer in pool.signers() {
+        match signer.repair_nonce(&monitor).await {
+            Ok((report, _)) => reports.push(json!(report)),
+            Err(e) => reports.push(json!({
+                "address": signer.address(),
+                "error": e.to_string(),
+            })),
+        }
+    }
+    Ok(warp::reply::json(&json!({ "signers": reports })))
+}
+
+/// Handles the `/admin/budget` request.
+pub async fn handle_budget_status(
+    budget: Arc<SpendingBudget>,
+) -> anyhow::Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(
+        &json!({ "budgets": budget.status().await }),
+    ))
+}
+
+/// Handles the `/admin/budget/reset` request.
+pub async fn handle_budget_reset(
+    budget: Arc<SpendingBudget>,
+) -> anyhow::Result<impl Reply, Rejection> {
+    budget.reset().await;
+    Ok(warp::reply::json(
+        &json!({ "budgets": budget.status().await }),
+    ))
+}
@@ -0,0 +1,317 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use ethers::contract::multicall_contract::{Call3Value, Result as CallResult};
+use ethers::prelude::{Eip1559TransactionRequest, Middleware, TransactionReceipt, TxHash, U256};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::{
+    GethDebugBuiltInTracerType, GethDebugTracerType, GethDebugTracingOptions, GethTrace,
+    GethTraceFrame,
+};
+use lazy_static::lazy_static;
+use log::warn;
+use prometheus::{register_histogram_vec, HistogramVec};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::sleep;
+
+use crate::server::ledger::LedgerEntry;
+use crate::server::monitor::TxMonitor;
+use crate::server::pool::{PrepareError, SignerLease};
+use crate::server::shared::Multicall;
+
+lazy_static! {
+    static ref BATCH_SIZE: HistogramVec = register_histogram_vec!(
+        "batch_size",
+        "Number of requests combined into a batched transaction.",
+        &["kind"],
+        vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0]
+    )
+    .unwrap();
+}
+
+/// Request batching settings.
+#[derive(Clone, Copy, Debug)]
+pub struct BatchConfig {
+    /// Time to collect requests after the first one of a batch arrives.
+    pub window: Duration,
+    /// Maximum number of requests per batch. A full batch is sent before the window closes.
+    pub max_size: usize,
+}
+
+/// A request waiting to be sent as part of a batch.
+pub struct BatchRequest<T> {
+    pub call: T,
+    pub entry: LedgerEntry,
+    /// Yields the shared transaction once the batch was broadcast.
+    pub sent: oneshot::Sender<anyhow::Result<BatchTicket>>,
+}
+
+/// A caller's share of a broadcast batch transaction.
+pub struct BatchTicket {
+    pub tx_hash: TxHash,
+    /// Position of the caller's request in the batch.
+    pub index: usize,
+    /// Yields the outcome of the batch transaction once mined, see [`TxMonitor::watch`].
+    ///
+    /// [`TxMonitor::watch`]: crate::server::monitor::TxMonitor::watch
+    pub outcome: oneshot::Receiver<anyhow::Result<BatchOutcome>>,
+}
+
+/// A caller's share of a mined batch transaction.
+pub struct BatchOutcome {
+    pub receipt: TransactionReceipt,
+    /// Result of the caller's call. Only set for batches whose calls may fail, if the batch
+    /// succeeded and its results could be read from a trace of the transaction.
+    pub result: Option<CallResult>,
+}
+
+/// Queue that coalesces concurrent requests into batches.
+///
+/// A batch is closed once the window has passed since its first request, or once it holds
+/// `max_size` requests, and is then handed to the dispatch function. Batches are dispatched
+/// concurrently, so a slow batch doesn't hold up the next one.
+pub struct Batcher<T> {
+    sender: mpsc::UnboundedSender<BatchRequest<T>>,
+}
+
+impl<T> Clone for Batcher<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> Batcher<T> {
+    /// Spawns the batching task for requests of the given kind.
+    pub fn spawn<F, Fut>(kind: &'static str, config: BatchConfig, dispatch: F) -> Self
+    where
+        F: Fn(Vec<BatchRequest<T>>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let max_size = config.max_size.max(1);
+        tokio::spawn(async move {
+            while let Some(first) = receiver.recv().await {
+                let mut batch = vec![first];
+                let window = sleep(config.window);
+                tokio::pin!(window);
+                while batch.len() < max_size {
+                    tokio::select! {
+                        _ = &mut window => break,
+                        request = receiver.recv() => match request {
+                            Some(request) => batch.push(request),
+                            None => break,
+                        },
+                    }
+                }
+                BATCH_SIZE
+                    .with_label_values(&[kind])
+                    .observe(batch.len() as f64);
+                tokio::spawn(dispatch(batch));
+            }
+        });
+        Self { sender }
+    }
+
+    /// Queues a request and waits until its batch was broadcast.
+    pub async fn submit(&self, call: T, entry: LedgerEntry) -> anyhow::Result<BatchTicket> {
+        let (sent, receiver) = oneshot::channel();
+        self.sender
+            .send(BatchRequest { call, entry, sent })
+            .map_err(|_| anyhow!("batch queue closed"))?;
+        receiver
+            .await
+            .map_err(|_| anyhow!("batch was dropped before it was sent"))?
+    }
+}
+
+/// Sends the calls of a batch as one `aggregate3Value` transaction to a Multicall3 compatible
+/// contract, one call per request, and hands out tickets to the requests. The monitor records the
+/// transaction to every request's ledger entry.
+pub async fn send_batch<T>(
+    signer: SignerLease,
+    contract: Multicall,
+    monitor: &TxMonitor,
+    requests: Vec<BatchRequest<T>>,
+    calls: Vec<Call3Value>,
+) {
+    // The results of calls that may fail are read back once the batch is mined.
+    let results_from = calls
+        .iter()
+        .any(|call| call.allow_failure)
+        .then(|| contract.clone());
+    let (tx, hash) = match send_multicall(&signer, &contract, monitor, calls).await {
+        Ok(sent) => sent,
+        Err(e) => return fail_batch(requests, e),
+    };
+    let entries = requests
+        .iter()
+        .enumerate()
+        .map(|(index, request)| {
+            let mut entry = request.entry.clone();
+            entry.record_tx(&tx);
+            entry.tx_hash = Some(hash);
+            entry.batch_index = Some(index as u32);
+            entry
+        })
+        .collect();
+    signer.record_sent(tx.nonce().copied()).await;
+    let receipt = monitor.watch(signer, tx, entries);
+    issue_tickets(requests, hash, receipt, results_from);
+}
+
+async fn send_multicall(
+    signer: &SignerLease,
+    contract: &Multicall,
+    monitor: &TxMonitor,
+    calls: Vec<Call3Value>,
+) -> anyhow::Result<(TypedTransaction, TxHash)> {
+    let value = calls
+        .iter()
+        .fold(U256::zero(), |sum, call| sum + call.value);
+    let data = contract
+        .aggregate_3_value(calls)
+        .calldata()
+        .ok_or_else(|| anyhow!("failed to encode multicall"))?;
+    let mut tx: TypedTransaction = Eip1559TransactionRequest::new()
+        .to(contract.address())
+        .data(data)
+        .value(value)
+        .into();
+    signer.prepare(&mut tx).await?;
+    match signer.client.send_transaction(tx.clone(), None).await {
+        Ok(pending) => Ok((tx, pending.tx_hash())),
+        Err(e) => {
+            signer
+                .release_nonce(tx.nonce().copied(), &e.to_string(), monitor)
+                .await;
+            Err(e.into())
+        }
+    }
+}
+
+/// Hands out tickets for a broadcast batch, forwarding the batch receipt, and the result of
+/// their call if `results_from` is set, to every caller.
+fn issue_tickets<T>(
+    requests: Vec<BatchRequest<T>>,
+    tx_hash: TxHash,
+    receipt: oneshot::Receiver<anyhow::Result<TransactionReceipt>>,
+    results_from: Option<Multicall>,
+) {
+    let mut waiters = Vec::with_capacity(requests.len());
+    for (index, request) in requests.into_iter().enumerate() {
+        let (sender, receiver) = oneshot::channel();
+        waiters.push(sender);
+        let _ = request.sent.send(Ok(BatchTicket {
+            tx_hash,
+            index,
+            outcome: receiver,
+        }));
+    }
+    tokio::spawn(async move {
+        let receipt = receipt
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("batch did not return a receipt")));
+        let results = match (&receipt, results_from) {
+            (Ok(receipt), Some(contract)) if receipt.status == Some(1u64.into()) => {
+                match call_results(&contract, receipt.transaction_hash).await {
+                    Ok(results) => Some(results),
+                    Err(e) => {
+                        warn!(
+                            "failed to read call results of batch {:?}: {}",
+                            receipt.transaction_hash, e
+                        );
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        for (index, waiter) in waiters.into_iter().enumerate() {
+            let outcome = match &receipt {
+                Ok(receipt) => Ok(BatchOutcome {
+                    receipt: receipt.clone(),
+                    result: results.as_ref().and_then(|r| r.get(index).cloned()),
+                }),
+                Err(e) => Err(anyhow!("{}", e)),
+            };
+            let _ = waiter.send(outcome);
+        }
+    });
+}
+
+/// Returns the per-call results of a mined `aggregate3Value` transaction, decoded from the
+/// output of a `callTracer` trace.
+async fn call_results(contract: &Multicall, tx_hash: TxHash) -> anyhow::Result<Vec<CallResult>> {
+    let options = GethDebugTracingOptions {
+        tracer: Some(GethDebugTracerType::BuiltInTracer(
+            GethDebugBuiltInTracerType::CallTracer,
+        )),
+        ..Default::default()
+    };
+    let trace = contract
+        .client()
+        .provider()
+        .debug_trace_transaction(tx_hash, options)
+        .await?;
+    let GethTrace::Known(GethTraceFrame::CallTracer(frame)) = trace else {
+        return Err(anyhow!("unexpected trace format"));
+    };
+    let output = frame.output.ok_or_else(|| anyhow!("trace has no output"))?;
+    Ok(contract.decode_output("aggregate3Value", output)?)
+}
+
+/// Fails every request of a batch that couldn't be sent. A [`PrepareError`] is passed on to
+/// every request, so callers can answer it the same way as for a transaction of their own.
+pub fn fail_batch<T>(requests: Vec<BatchRequest<T>>, error: anyhow::Error) {
+    warn!(
+        "failed to send batch of {} requests: {}",
+        requests.len(),
+        error
+    );
+    let prepare_error = error.downcast_ref::<PrepareError>();
+    for request in requests {
+        let error = match prepare_error.and_then(PrepareError::try_clone) {
+            Some(e) => e.into(),
+            None => anyhow!("{}", error),
+        };
+        let _ = request.sent.send(Err(error));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ledger::RequestKind;
+    use ethers::prelude::Address;
+
+    #[test]
+    fn fail_batch_passes_prepare_errors_to_every_request() {
+        let mut receivers = vec![];
+        let requests = (0..2)
+            .map(|_| {
+                let (sent, receiver) = oneshot::channel();
+                receivers.push(receiver);
+                BatchRequest {
+                    call: (),
+                    entry: LedgerEntry::new(RequestKind::Register, Address::zero(), None),
+                    sent,
+                }
+            })
+            .collect();
+        let error = PrepareError::InsufficientFunds {
+            required: U256::from(2),
+            balance: U256::one(),
+        };
+        fail_batch(requests, error.into());
+        for mut receiver in receivers {
+            let error = receiver.try_recv().unwrap().err().unwrap();
+            assert!(matches!(
+                error.downcast_ref::<PrepareError>(),
+                Some(PrepareError::InsufficientFunds { .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use ethers::prelude::{TransactionReceipt, U256};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{register_gauge_vec, register_int_counter_vec, GaugeVec, IntCounterVec};
+use serde::Serialize;
+use warp::Rejection;
+
+use crate::server::shared::Unavailable;
+use crate::server::state::SharedState;
+
+lazy_static! {
+    static ref BUDGET_USED: GaugeVec = register_gauge_vec!(
+        "budget_used",
+        "Amount used of each spending budget in its current window, in wei or transactions.",
+        &["budget"]
+    )
+    .unwrap();
+    static ref BUDGET_REJECTIONS: IntCounterVec = register_int_counter_vec!(
+        "budget_rejected_requests_total",
+        "Number of requests rejected because a spending budget was exhausted, by budget.",
+        &["budget"]
+    )
+    .unwrap();
+}
+
+/// Limits on what the signer wallets may spend. Unset limits are not enforced.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BudgetConfig {
+    /// Maximum gas spend, in wei, per hour.
+    pub max_gas_spend_per_hour: Option<U256>,
+    /// Maximum gas spend, in wei, per day.
+    pub max_gas_spend_per_day: Option<U256>,
+    /// Maximum number of transactions sent per minute.
+    pub max_txs_per_minute: Option<u64>,
+}
+
+/// What a budget counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Usage {
+    /// Gas fees paid, from transaction receipts.
+    GasSpend,
+    /// Transactions sent.
+    Transactions,
+}
+
+/// Budget over fixed windows, each starting over once its length has passed.
+#[derive(Debug)]
+struct Budget {
+    name: &'static str,
+    usage: Usage,
+    limit: U256,
+    length: Duration,
+}
+
+impl Budget {
+    fn key(&self) -> String {
+        format!("budget:{}", self.name)
+    }
+}
+
+/// State of a budget in its current window.
+#[derive(Clone, Debug, Serialize)]
+pub struct BudgetStatus {
+    pub budget: &'static str,
+    pub limit: U256,
+    pub used: U256,
+    pub resets_in_secs: u64,
+}
+
+/// Circuit breaker on the gas spend and transaction rate of the signer wallets.
+///
+/// Sent transactions and native messages, and the gas fees of their receipts, are counted
+/// against every configured budget. Once a budget is used up, requests that would send a
+/// transaction are rejected with `503 Service Unavailable` until its window passes or an
+/// operator resets the budgets. Transactions already in flight still count, so a budget may be
+/// overshot by their fees. Usage is kept in the shared state.
+pub struct SpendingBudget {
+    budgets: Vec<Budget>,
+    state: Arc<SharedState>,
+}
+
+impl SpendingBudget {
+    pub fn new(config: BudgetConfig, state: Arc<SharedState>) -> Self {
+        let mut budgets = vec![];
+        if let Some(limit) = config.max_gas_spend_per_hour {
+            budgets.push(Budget {
+                name: "gas_per_hour",
+                usage: Usage::GasSpend,
+                limit,
+                length: Duration::from_secs(60 * 60),
+            });
+        }
+        if let Some(limit) = config.max_gas_spend_per_day {
+            budgets.push(Budget {
+                name: "gas_per_day",
+                usage: Usage::GasSpend,
+                limit,
+                length: Duration::from_secs(24 * 60 * 60),
+            });
+        }
+        if let Some(limit) = config.max_txs_per_minute {
+            budgets.push(Budget {
+                name: "txs_per_minute",
+                usage: Usage::Transactions,
+                limit: U256::from(limit),
+                length: Duration::from_secs(60),
+            });
+        }
+        Self { budgets, state }
+    }
+
+    /// Admits a request that sends a transaction, or rejects it with `Unavailable` if a budget
+    /// is used up.
+    pub async fn check(&self) -> Result<(), Rejection> {
+        for budget in &self.budgets {
+            let (used, resets_in) = self.add(budget, U256::zero()).await;
+            if used < budget.limit {
+                continue;
+            }
+            BUDGET_REJECTIONS.with_label_values(&[budget.name]).inc();
+            let reason = match budget.usage {
+                Usage::GasSpend => "gas-budget-exceeded",
+                Usage::Transactions => "tx-budget-exceeded",
+            };
+            return Err(warp::reject::custom(Unavailable {
+                reason,
+                message: format!(
+                    "{} budget of {} used up, resets in {}s",
+                    budget.name,
+                    budget.limit,
+                    resets_in.as_secs() + 1
+                ),
+            }));
+        }
+        Ok(())
+    }
+
+    /// Counts a broadcast transaction, including fee bump replacements, or a pushed native
+    /// message.
+    pub async fn record_tx(&self) {
+        self.record(Usage::Transactions, U256::one()).await;
+    }
+
+    /// Counts the gas fees paid for a mined transaction. `tx` is the mined transaction, whose
+    /// gas price is used if the receipt has no effective gas price.
+    pub async fn record_receipt(&self, receipt: &TransactionReceipt, tx: &TypedTransaction) {
+        let (Some(gas_used), Some(gas_price)) = (
+            receipt.gas_used,
+            receipt.effective_gas_price.or_else(|| tx.gas_price()),
+        ) else {
+            warn!(
+                "receipt of {:?} has no gas fees to count against budgets",
+                receipt.transaction_hash
+            );
+            return;
+        };
+        self.record_fees(gas_used.saturating_mul(gas_price)).await;
+    }
+
+    /// Counts gas fees paid, in wei.
+    pub async fn record_fees(&self, fees: U256) {
+        self.record(Usage::GasSpend, fees).await;
+    }
+
+    async fn record(&self, usage: Usage, amount: U256) {
+        for budget in self.budgets.iter().filter(|b| b.usage == usage) {
+            self.add(budget, amount).await;
+        }
+    }
+
+    /// Adds to the usage of a budget in its current window and returns the usage and the time
+    /// until the window passes.
+    async fn add(&self, budget: &Budget, amount: U256) -> (U256, Duration) {
+        let (used, resets_in) = self
+            .state
+            .add_to_window(&budget.key(), budget.length, amount)
+            .await;
+        BUDGET_USED
+            .with_label_values(&[budget.name])
+            .set(used.low_u128() as f64);
+        (used, resets_in)
+    }
+
+    /// Starts new windows for all budgets, closing the circuit breaker.
+    pub async fn reset(&self) {
+        info!("resetting spending budgets");
+        for budget in &self.budgets {
+            self.state.remove(&budget.key()).await;
+            BUDGET_USED.with_label_values(&[budget.name]).set(0.0);
+        }
+    }
+
+    /// Returns the state of every configured budget.
+    pub async fn status(&self) -> Vec<BudgetStatus> {
+        let mut status = vec![];
+        for budget in &self.budgets {
+            let (used, resets_in) = self.add(budget, U256::zero()).await;
+            status.push(BudgetStatus {
+                budget: budget.name,
+                limit: budget.limit,
+                used,
+                resets_in_secs: resets_in.as_secs(),
+            });
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::TransactionRequest;
+
+    use super::*;
+
+    fn receipt(gas_used: u64, gas_price: Option<u64>) -> TransactionReceipt {
+        TransactionReceipt {
+            gas_used: Some(U256::from(gas_used)),
+            effective_gas_price: gas_price.map(U256::from),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn trips_on_gas_spend() {
+        let budget = SpendingBudget::new(
+            BudgetConfig {
+                max_gas_spend_per_hour: Some(U256::from(1000)),
+                ..Default::default()
+            },
+            Arc::new(SharedState::memory()),
+        );
+        let tx: TypedTransaction = TransactionRequest::new().gas_price(3).into();
+        budget.record_receipt(&receipt(100, Some(5)), &tx).await;
+        assert!(budget.check().await.is_ok());
+
+        // Falls back to the gas price of the transaction.
+        budget.record_receipt(&receipt(200, None), &tx).await;
+        assert!(budget.check().await.is_err());
+        assert_eq!(budget.status().await[0].used, U256::from(1100));
+
+        budget.reset().await;
+        assert!(budget.check().await.is_ok());
+        assert_eq!(budget.status().await[0].used, U256::zero());
+    }
+
+    #[tokio::test]
+    async fn trips_on_transaction_count() {
+        let state = Arc::new(SharedState::memory());
+        let config = BudgetConfig {
+            max_txs_per_minute: Some(2),
+            ..Default::default()
+        };
+        let budget = SpendingBudget::new(config, state.clone());
+        budget.record_tx().await;
+        assert!(budget.check().await.is_ok());
+
+        // Replicas sharing the state share the budget.
+        SpendingBudget::new(config, state).record_tx().await;
+        assert!(budget.check().await.is_err());
+        assert!(budget.status().await[0].resets_in_secs <= 60);
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use ethers::prelude::Address;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, register_int_gauge, IntCounterVec, IntGauge};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::{Filter, Rejection, Reply};
+
+use crate::server::shared::with_challenges;
+use crate::server::state::SharedState;
+use crate::server::verifier::leading_zero_bits;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const TOKEN_VERSION: &str = "v1";
+
+/// Window over which drip volume is measured for adaptive difficulty.
+const VOLUME_WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref CHALLENGE_DIFFICULTY: IntGauge = register_int_gauge!(
+        "challenge_difficulty",
+        "Difficulty, in leading zero bits, of newly issued proof-of-work challenges."
+    )
+    .unwrap();
+    static ref CHALLENGE_VERIFICATIONS: IntCounterVec = register_int_counter_vec!(
+        "challenge_verifications_total",
+        "Number of proof-of-work challenge solutions checked, by result.",
+        &["result"]
+    )
+    .unwrap();
+}
+
+/// Proof-of-work challenge settings.
+#[derive(Clone, Debug)]
+pub struct ChallengeConfig {
+    /// Secret used to sign challenges. Replicas sharing it accept each other's challenges.
+    pub secret: Vec<u8>,
+    /// Difficulty, in leading zero bits, at low drip volume.
+    pub base_difficulty: u32,
+    /// Upper bound for the adaptive difficulty.
+    pub max_difficulty: u32,
+    /// Drips per minute above which the difficulty increases. Each doubling of the volume adds
+    /// one bit, which doubles the expected work. The volume is measured per replica.
+    pub target_rate: u32,
+    /// Time a challenge stays valid after it was issued.
+    pub ttl: Duration,
+}
+
+/// A signed challenge issued by `GET /challenge`.
+#[derive(Debug, Serialize)]
+struct Challenge {
+    token: String,
+    difficulty: u32,
+    /// Unix timestamp (seconds) after which the challenge is rejected.
+    expires_at: u64,
+    algorithm: &'static str,
+}
+
+/// Solved challenge sent with a drip request.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChallengeSolution {
+    /// The challenge token as issued.
+    pub token: String,
+    /// Counter such that `sha256("<token>:<address>:<nonce>")` has enough leading zero bits.
+    pub nonce: u64,
+}
+
+/// Issues and verifies hashcash-style proof-of-work challenges.
+///
+/// Challenges are self-contained and HMAC-signed, so verification only needs the secret and a
+/// record of used tokens to prevent replays. Used tokens are kept in the shared state until
+/// they expire.
+///
+/// The drip volume behind the adaptive difficulty is only counted in process, so each replica
+/// sets its difficulty from the drips it served.
+pub struct ChallengeIssuer {
+    config: ChallengeConfig,
+    state: Arc<SharedState>,
+    recent_drips: Mutex<VecDeque<Instant>>,
+}
+
+impl ChallengeIssuer {
+    pub fn new(config: ChallengeConfig, state: Arc<SharedState>) -> Self {
+        CHALLENGE_DIFFICULTY.set(config.base_difficulty as i64);
+        Self {
+            config,
+            state,
+            recent_drips: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Records an accepted drip for adaptive difficulty.
+    pub fn record_drip(&self) {
+        let mut recent = self.recent_drips.lock().unwrap();
+        let now = Instant::now();
+        recent.push_back(now);
+        prune_volume(&mut recent, now);
+    }
+
+    /// Returns the difficulty for new challenges given the recent drip volume.
+    fn difficulty(&self) -> u32 {
+        let mut recent = self.recent_drips.lock().unwrap();
+        prune_volume(&mut recent, Instant::now());
+        let volume = recent.len() as u32;
+        let target = self.config.target_rate.max(1);
+        let extra = if volume > target {
+            (volume / target).ilog2() + 1
+        } else {
+            0
+        };
+        (self.config.base_difficulty + extra).min(self.config.max_difficulty)
+    }
+
+    fn issue(&self) -> Challenge {
+        let difficulty = self.difficulty();
+        CHALLENGE_DIFFICULTY.set(difficulty as i64);
+        let expires_at = unix_now() + self.config.ttl.as_secs();
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let payload = format!(
+            "{}.{}.{}.{}",
+            TOKEN_VERSION,
+            expires_at,
+            difficulty,
+            hex::encode(salt)
+        );
+        let signature = hex::encode(self.sign(&payload).finalize().into_bytes());
+        Challenge {
+            token: format!("{}.{}", payload, signature),
+            difficulty,
+            expires_at,
+            algorithm: "sha256",
+        }
+    }
+
+    /// Checks a solved challenge for the given drip recipient and marks it as used. Errors are
+    /// short kebab-case reason codes.
+    pub async fn verify(
+        &self,
+        solution: &ChallengeSolution,
+        address: Address,
+    ) -> Result<(), &'static str> {
+        let res = self.check(solution, address).await;
+        CHALLENGE_VERIFICATIONS
+            .with_label_values(&[match &res {
+                Ok(()) => "success",
+                Err(reason) => reason,
+            }])
+            .inc();
+        res
+    }
+
+    async fn check(
+        &self,
+        solution: &ChallengeSolution,
+        address: Address,
+    ) -> Result<(), &'static str> {
+        const INVALID: &str = "invalid-challenge";
+        let (payload, signature_hex) = solution.token.rsplit_once('.').ok_or(INVALID)?;
+        let signature = hex::decode(signature_hex).map_err(|_| INVALID)?;
+        self.sign(payload)
+            .verify_slice(&signature)
+            .map_err(|_| INVALID)?;
+
+        let mut parts = payload.split('.');
+        if parts.next() != Some(TOKEN_VERSION) {
+            return Err(INVALID);
+        }
+        let expires_at: u64 = parts.next().and_then(|s| s.parse().ok()).ok_or(INVALID)?;
+        let difficulty: u32 = parts.next().and_then(|s| s.parse().ok()).ok_or(INVALID)?;
+        let now = unix_now();
+        if expires_at < now {
+            return Err("challenge-expired");
+        }
+
+        let hash = Sha256::digest(format!(
+            "{}:{:?}:{}",
+            solution.token, address, solution.nonce
+        ));
+        if leading_zero_bits(&hash) < difficulty {
+            return Err("challenge-not-solved");
+        }
+
+        let key = format!("challenge:{}", signature_hex);
+        let ttl = Duration::from_secs(expires_at - now + 1);
+        if !self.state.try_insert(&key, "used", ttl).await {
+            return Err("challenge-already-used");
+        }
+        Ok(())
+    }
+
+    fn sign(&self, payload: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.config.secret).expect("hmac accepts any key length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+/// Drops drips that fell out of the volume window.
+fn prune_volume(recent: &mut VecDeque<Instant>, now: Instant) {
+    while recent
+        .front()
+        .is_some_and(|t| now.duration_since(*t) > VOLUME_WINDOW)
+    {
+        recent.pop_front();
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Route filter for `/challenge` endpoint.
+pub fn challenge_route(
+    challenges: Option<Arc<ChallengeIssuer>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("challenge")
+        .and(warp::get())
+        .and(with_challenges(challenges))
+        .and_then(handle_challenge)
+}
+
+/// Handles the `/challenge` request.
+pub async fn handle_challenge(
+    challenges: Option<Arc<ChallengeIssuer>>,
+) -> anyhow::Result<impl Reply, Rejection> {
+    let challenges = challenges.ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&challenges.issue()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn issuer(ttl: Duration) -> ChallengeIssuer {
+        ChallengeIssuer::new(
+            ChallengeConfig {
+                secret: b"secret".to_vec(),
+                base_difficulty: 4,
+                max_difficulty: 6,
+                target_rate: 2,
+                ttl,
+            },
+            Arc::new(SharedState::memory()),
+        )
+    }
+
+    /// Finds a nonce solving the challenge for the address.
+    fn solve(challenge: &Challenge, address: Address) -> ChallengeSolution {
+        let nonce = (0u64..)
+            .find(|nonce| {
+                let hash = Sha256::digest(format!("{}:{:?}:{}", challenge.token, address, nonce));
+                leading_zero_bits(&hash) >= challenge.difficulty
+            })
+            .unwrap();
+        ChallengeSolution {
+            token: challenge.token.clone(),
+            nonce,
+        }
+    }
+
+    #[test]
+    fn adapts_difficulty_to_volume() {
+        let challenges = issuer(Duration::from_secs(60));
+        assert_eq!(challenges.difficulty(), 4);
+        for _ in 0..2 {
+            challenges.record_drip();
+        }
+        assert_eq!(challenges.difficulty(), 4);
+        challenges.record_drip();
+        assert_eq!(challenges.difficulty(), 5);
+        for _ in 0..5 {
+            challenges.record_drip();
+        }
+        assert_eq!(challenges.difficulty(), 6);
+        // Capped at the maximum.
+        for _ in 0..100 {
+            challenges.record_drip();
+        }
+        assert_eq!(challenges.difficulty(), 6);
+
+        // Drips out of the window no longer count.
+        let past = Instant::now() - VOLUME_WINDOW - Duration::from_secs(1);
+        challenges
+            .recent_drips
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .for_each(|t| *t = past);
+        assert_eq!(challenges.difficulty(), 4);
+    }
+
+    #[tokio::test]
+    async fn accepts_solution_once() {
+        let challenges = issuer(Duration::from_secs(60));
+        let address = Address::repeat_byte(0x11);
+        let challenge = challenges.issue();
+        assert_eq!(challenge.difficulty, 4);
+        assert!(challenge.expires_at >= unix_now() + 59);
+
+        let solution = solve(&challenge, address);
+        assert_eq!(challenges.verify(&solution, address).await, Ok(()));
+        assert_eq!(
+            challenges.verify(&solution, address).await,
+            Err("challenge-already-used")
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_solutions() {
+        let challenges = issuer(Duration::from_secs(60));
+        let address = Address::repeat_byte(0x11);
+        let challenge = challenges.issue();
+        let solution = solve(&challenge, address);
+
+        // Tampered tokens fail the signature check.
+        let tampered = ChallengeSolution {
+            token: challenge.token.replacen(".4.", ".0.", 1),
+            nonce: solution.nonce,
+        };
+        assert_eq!(
+            challenges.verify(&tampered, address).await,
+            Err("invalid-challenge")
+        );
+        // Tokens signed with another secret too.
+        let mut other = issuer(Duration::from_secs(60));
+        other.config.secret = b"other".to_vec();
+        let foreign = solve(&other.issue(), address);
+        assert_eq!(
+            challenges.verify(&foreign, address).await,
+            Err("invalid-challenge")
+        );
+
+        // The work is bound to the recipient.
+        let recipient = (0u8..)
+            .map(Address::repeat_byte)
+            .find(|a| {
+                let hash = Sha256::digest(format!("{}:{:?}:{}", solution.token, a, solution.nonce));
+                leading_zero_bits(&hash) < challenge.difficulty
+            })
+            .unwrap();
+        assert_eq!(
+            challenges.verify(&solution, recipient).await,
+            Err("challenge-not-solved")
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_challenges() {
+        let challenges = issuer(Duration::ZERO);
+        let address = Address::repeat_byte(0x11);
+        let solution = solve(&challenges.issue(), address);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(
+            challenges.verify(&solution, address).await,
+            Err("challenge-expired")
+        );
+    }
+}
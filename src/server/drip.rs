@@ -1,55 +1,143 @@
-use crate::server::shared::{DefaultSignerMiddleware, Faucet, FaucetEmpty, TooManyRequests};
+use crate::server::account::account_state;
+use crate::server::address::TargetAddress;
+use crate::server::batch::{
+    fail_batch, send_batch, BatchConfig, BatchOutcome, BatchRequest, BatchTicket, Batcher,
+};
+use crate::server::challenge::ChallengeIssuer;
+use crate::server::faucet::FaucetAbi;
+use crate::server::keys::{DripSignals, KeyDeriver};
+use crate::server::ledger::{EntryStatus, Ledger, LedgerEntry, RequestKind};
+use crate::server::monitor::TxMonitor;
+use crate::server::pool::{PrepareError, SignerLease, SignerPool};
+use crate::server::ratelimit::RateLimiter;
+use crate::server::revert::{replay_revert, Revert};
+use crate::server::shared::{
+    ContractReverted, DefaultSignerMiddleware, FaucetEmpty, Multicall, NotEligible,
+    TooManyRequests, Unavailable, VerificationFailed,
+};
+use crate::server::verifier::{verify_human, HumanVerifier, VerifyContext, VerifyError};
 use crate::server::{
-    shared::{with_faucet, with_turnstile, BadRequest, DripRequest},
-    util::log_request_body,
+    shared::{
+        complete_claims, drip_signals, idempotency_key, with_batcher, with_challenges, with_dedupe,
+        with_faucet_abi, with_key_deriver, with_ledger, with_max_balance, with_monitor, with_pool,
+        with_rate_limiter, with_verifier, BadRequest, Claim, DripRequest, RequestDedupe, MAX_WAIT,
+    },
+    util::{log_drip_keys, log_request_body},
+};
+use anyhow::{anyhow, bail, Context};
+use ethers::contract::multicall_contract::Call3Value;
+use ethers::prelude::{
+    Address, BlockNumber, ContractError, Middleware, TransactionReceipt, TxHash, U256,
 };
-use anyhow::anyhow;
-use cf_turnstile::{SiteVerifyRequest, TurnstileClient};
-use ethers::prelude::{Address, ContractError, TxHash};
-use ethers::utils::keccak256;
-use log::info;
-use once_cell::sync::Lazy;
 use serde_json::json;
 use std::net::IpAddr;
 use std::sync::Arc;
+use tokio::time::timeout;
 use warp::{Filter, Rejection, Reply};
 use warp_real_ip::real_ip;
 
-static TRY_LATER_SELECTOR: Lazy<Vec<u8>> = Lazy::new(|| keccak256(b"TryLater()")[0..4].into());
-static FAUCET_EMPTY_SELECTOR: Lazy<Vec<u8>> =
-    Lazy::new(|| keccak256(b"FaucetEmpty()")[0..4].into());
-
 /// Enum to handle drip results.
+#[derive(Debug)]
 enum DripResult {
     Pending(TxHash),
     Success(TxHash),
     Failure(String),
+    /// The drip call reverted with an error other than `TryLater` and `FaucetEmpty`.
+    Reverted(Revert),
+    /// The signer wallet can't pay for the drip.
+    InsufficientFunds(String),
     RateLimited,
     FaucetEmpty,
 }
 
+/// Drip call queued for batching.
+pub struct DripCall {
+    to_address: Address,
+    keys: Vec<String>,
+}
+
+impl std::fmt::Display for DripResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DripResult::RateLimited => write!(f, "rate limited"),
+            DripResult::FaucetEmpty => write!(f, "faucet empty"),
+            DripResult::Failure(message) => write!(f, "{}", message),
+            DripResult::Reverted(revert) => write!(f, "drip reverted: {}", revert),
+            DripResult::InsufficientFunds(message) => write!(f, "{}", message),
+            DripResult::Pending(tx) => write!(f, "drip pending in {:?}", tx),
+            DripResult::Success(tx) => write!(f, "drip succeeded in {:?}", tx),
+        }
+    }
+}
+
+/// Outcome of a batched drip call that was left out of its batch, e.g. because it reverted in
+/// the pre-flight simulation of the batch.
+#[derive(Debug)]
+struct DripRejected(DripResult);
+
+impl std::fmt::Display for DripRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for DripRejected {}
+
 /// Route filter for `/drip` endpoint.
+#[allow(clippy::too_many_arguments)]
 pub fn drip_route(
     trusted_proxy_ips: Vec<IpAddr>,
-    faucet: Faucet,
-    turnstile: Arc<TurnstileClient>,
+    pool: Arc<SignerPool>,
+    limiter: Arc<RateLimiter>,
+    dedupe: RequestDedupe,
+    verifier: Arc<dyn HumanVerifier>,
+    challenges: Option<Arc<ChallengeIssuer>>,
+    max_balance: Option<U256>,
+    keys: Arc<KeyDeriver>,
+    faucet_abi: Arc<FaucetAbi>,
+    ledger: Ledger,
+    monitor: TxMonitor,
+    batcher: Option<Batcher<DripCall>>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path("drip")
         .and(warp::post())
         .and(warp::header::exact("content-type", "application/json"))
         .and(warp::body::json())
+        .and(idempotency_key())
+        .and(drip_signals(trusted_proxy_ips.clone()))
         .and(real_ip(trusted_proxy_ips))
-        .and(with_faucet(faucet))
-        .and(with_turnstile(turnstile))
+        .and(with_pool(pool))
+        .and(with_rate_limiter(limiter))
+        .and(with_dedupe(dedupe))
+        .and(with_verifier(verifier))
+        .and(with_challenges(challenges))
+        .and(with_max_balance(max_balance))
+        .and(with_key_deriver(keys))
+        .and(with_faucet_abi(faucet_abi))
+        .and(with_ledger(ledger))
+        .and(with_monitor(monitor))
+        .and(with_batcher(batcher))
         .and_then(handle_drip)
 }
 
 /// Handles the `/drip` request.
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_drip(
     req: DripRequest,
+    idempotency_key: Option<String>,
+    signals: DripSignals,
     addr: Option<IpAddr>,
-    faucet: Faucet,
-    turnstile: Arc<TurnstileClient>,
+    pool: Arc<SignerPool>,
+    limiter: Arc<RateLimiter>,
+    dedupe: RequestDedupe,
+    verifier: Arc<dyn HumanVerifier>,
+    challenges: Option<Arc<ChallengeIssuer>>,
+    max_balance: Option<U256>,
+    key_deriver: Arc<KeyDeriver>,
+    faucet_abi: Arc<FaucetAbi>,
+    ledger: Ledger,
+    monitor: TxMonitor,
+    batcher: Option<Batcher<DripCall>>,
 ) -> anyhow::Result<impl Reply, Rejection> {
     log_request_body("drip", &format!("{}", req));
 
@@ -57,93 +145,498 @@ pub async fn handle_drip(
         message: "could not resolve ip address".to_string(),
     }))?;
 
-    let to_address = req.address.parse::<Address>().map_err(|e| {
-        Rejection::from(BadRequest {
-            message: format!("invalid ethereum address: {}", e),
-        })
-    })?;
-
-    let validated = turnstile
-        .siteverify(SiteVerifyRequest {
-            response: req.ts_response,
-            ..Default::default()
-        })
-        .await
+    let to_address = TargetAddress::parse(&req.address)
+        .and_then(|target| target.require_eth())
         .map_err(|e| {
             Rejection::from(BadRequest {
-                message: format!("turnstile error: {}", e),
+                message: format!("invalid address: {}", e),
+            })
+        })?;
+    let address_string = format!("{:?}", to_address);
+    let drip_keys = key_deriver
+        .drip_keys(to_address, addr, &signals)
+        .map_err(|missing| {
+            let sources: Vec<_> = missing.iter().map(|kind| kind.source()).collect();
+            Rejection::from(BadRequest {
+                message: format!("missing drip key signals: {}", sources.join(", ")),
             })
         })?;
 
-    if !validated.success {
-        return Err(Rejection::from(BadRequest {
-            message: "turnstile validation failed".to_string(),
-        }));
+    let idempotency = match dedupe
+        .claim_idempotency_key("drip", idempotency_key, Some(addr), &address_string)
+        .await?
+    {
+        Some(Claim::Done(body)) => return Ok(warp::reply::json(&body)),
+        Some(Claim::Held(guard)) => Some(guard),
+        None => None,
+    };
+
+    limiter.check_client("drip", Some(addr)).await?;
+    monitor.budget().check().await?;
+
+    if let Some(max_balance) = max_balance {
+        check_balance(&pool, to_address, max_balance).await?;
     }
 
-    let ip_string = addr.to_string();
+    if let Some(solution) = &req.challenge {
+        let challenges = challenges.as_ref().ok_or(Rejection::from(BadRequest {
+            message: "proof-of-work challenges are disabled".to_string(),
+        }))?;
+        challenges
+            .verify(solution, to_address)
+            .await
+            .map_err(|reason| {
+                Rejection::from(VerificationFailed {
+                    reason,
+                    message: format!("challenge rejected: {}", reason),
+                })
+            })?;
+    } else {
+        let context = VerifyContext {
+            client_ip: addr,
+            address: to_address,
+        };
+        verify_human(verifier.as_ref(), &req.captcha_response, &context)
+            .await
+            .map_err(|e| {
+                let message = match &e {
+                    VerifyError::Rejected(reason) => {
+                        format!("{} validation failed: {}", verifier.name(), reason)
+                    }
+                    VerifyError::Provider(e) => format!("{} error: {}", verifier.name(), e),
+                };
+                Rejection::from(VerificationFailed {
+                    reason: e.reason(),
+                    message,
+                })
+            })?;
+    }
 
-    info!(
-        "Calling drip with keys: address: {}, ip: {}",
-        req.address, ip_string
-    );
+    // Only verified requests count against the address, so that others can't use up its quota.
+    limiter.check_address("drip", &address_string).await?;
+
+    let in_flight = match dedupe.claim_address("drip", &address_string).await? {
+        Claim::Done(body) => return Ok(complete_claims(idempotency, None, body).await),
+        Claim::Held(guard) => guard,
+    };
+    if let Some(challenges) = &challenges {
+        challenges.record_drip();
+    }
+
+    log_drip_keys(&address_string, &drip_keys);
+    let keys = drip_keys.into_iter().map(|k| k.key).collect();
+
+    let mut entry = LedgerEntry::new(RequestKind::Drip, to_address, Some(addr));
+    ledger.insert(&mut entry).await;
+
+    let res = match &batcher {
+        Some(batcher) => {
+            drip_batched(
+                batcher,
+                &pool,
+                &faucet_abi,
+                &ledger,
+                to_address,
+                keys,
+                req.wait,
+                &mut entry,
+            )
+            .await
+        }
+        None => {
+            drip(
+                pool.acquire(),
+                &faucet_abi,
+                &monitor,
+                to_address,
+                keys,
+                req.wait,
+                &mut entry,
+            )
+            .await
+        }
+    };
+    // Once broadcast, the monitor keeps the ledger entry up to date.
+    if entry.tx_hash.is_none() {
+        match &res {
+            Ok(DripResult::Failure(message)) => entry.fail(message),
+            Ok(DripResult::Reverted(revert)) => entry.fail(format!("drip reverted: {}", revert)),
+            Ok(DripResult::InsufficientFunds(message)) => entry.fail(message),
+            Ok(DripResult::RateLimited) => entry.fail("rate limited"),
+            Ok(DripResult::FaucetEmpty) => entry.fail("faucet empty"),
+            Err(e) => entry.fail(e),
+            _ => {}
+        }
+        ledger.update(&entry).await;
+    }
+
+    let res = res.map_err(|e| {
+        Rejection::from(BadRequest {
+            message: format!("drip error: {}", e),
+        })
+    })?;
+    match res {
+        DripResult::Success(tx) | DripResult::Pending(tx) => {
+            let mut body = json!({"tx_hash": tx});
+            if let Some(index) = entry.batch_index {
+                body["batch_index"] = json!(index);
+            }
+            Ok(complete_claims(idempotency, Some(in_flight), body).await)
+        }
+        DripResult::Failure(message) => Err(warp::reject::custom(BadRequest { message })),
+        DripResult::Reverted(revert) => Err(warp::reject::custom(ContractReverted {
+            message: format!("drip reverted: {}", revert),
+            revert,
+        })),
+        DripResult::InsufficientFunds(message) => Err(warp::reject::custom(Unavailable {
+            reason: "signer-insufficient-funds",
+            message,
+        })),
+        DripResult::RateLimited => Err(warp::reject::custom(TooManyRequests { retry_after: None })),
+        DripResult::FaucetEmpty => Err(warp::reject::custom(FaucetEmpty {})),
+    }
+}
 
-    let res = drip(faucet, to_address, vec![req.address, ip_string], req.wait)
+/// Rejects drips to addresses that already hold more than `max_balance`.
+async fn check_balance(
+    pool: &SignerPool,
+    to_address: Address,
+    max_balance: U256,
+) -> Result<(), Rejection> {
+    let state = account_state(pool.primary().client.as_ref(), to_address)
         .await
         .map_err(|e| {
             Rejection::from(BadRequest {
                 message: format!("drip error: {}", e),
             })
         })?;
-    match res {
-        DripResult::Success(tx) | DripResult::Pending(tx) => {
-            Ok(warp::reply::json(&json!({"tx_hash": tx})))
-        }
-        DripResult::Failure(message) => Err(warp::reject::custom(BadRequest { message })),
-        DripResult::RateLimited => Err(warp::reject::custom(TooManyRequests {})),
-        DripResult::FaucetEmpty => Err(warp::reject::custom(FaucetEmpty {})),
+    if state.balance > max_balance {
+        return Err(warp::reject::custom(NotEligible {
+            reason: "balance-too-high",
+            message: format!(
+                "address balance {} exceeds the drip limit of {} wei",
+                state.balance, max_balance
+            ),
+        }));
     }
+    Ok(())
 }
 
 /// Drips a small amount of RECALL to an address on the subnet using the faucet.
 /// This will trigger the FVM to create an account for the address.
 async fn drip(
-    faucet: Faucet,
+    signer: SignerLease,
+    faucet_abi: &FaucetAbi,
+    monitor: &TxMonitor,
     to_address: Address,
     keys: Vec<String>,
     wait: Option<bool>,
+    entry: &mut LedgerEntry,
 ) -> anyhow::Result<DripResult> {
-    let tx = faucet.drip(to_address, keys);
-    let tx_pending = tx.send().await;
+    let mut tx = faucet_abi.drip_call(&signer.faucet, to_address, &keys)?;
+    if let Err(e) = signer.prepare(&mut tx.tx).await {
+        return Ok(match e {
+            PrepareError::Reverted(data) => result_from_revert(faucet_abi, &data),
+            PrepareError::InsufficientFunds { .. } => DripResult::InsufficientFunds(e.to_string()),
+            e => DripResult::Failure(e.to_string()),
+        });
+    }
+    entry.record_tx(&tx.tx);
+    let tx_pending = tx.send().await.map(|pending| pending.tx_hash());
     match tx_pending {
-        Ok(tx) => {
-            let hash = tx.tx_hash();
+        Ok(hash) => {
+            entry.tx_hash = Some(hash);
+            signer.record_sent(entry.nonce).await;
+            let client = signer.client.clone();
+            let receipt = monitor.watch(signer, tx.tx.clone(), vec![entry.clone()]);
             let wait = wait.unwrap_or(true);
             if wait {
-                tx.await?.ok_or(anyhow!("drip did not return a receipt"))?;
-                Ok(DripResult::Success(hash))
+                let Ok(receipt) = timeout(MAX_WAIT, receipt).await else {
+                    return Ok(DripResult::Pending(hash));
+                };
+                let receipt = receipt.map_err(|_| anyhow!("drip did not return a receipt"))??;
+                if receipt.status != Some(1u64.into()) {
+                    return Ok(
+                        match replay_revert(client.provider(), &tx.tx, &receipt).await {
+                            Some(data) => result_from_revert(faucet_abi, &data),
+                            None => DripResult::Failure(format!(
+                                "drip {:?} reverted",
+                                receipt.transaction_hash
+                            )),
+                        },
+                    );
+                }
+                Ok(DripResult::Success(receipt.transaction_hash))
             } else {
                 Ok(DripResult::Pending(hash))
             }
         }
-        Err(e) => Ok(result_from_error(e)),
+        Err(e) => {
+            signer
+                .release_nonce(entry.nonce, &e.to_string(), monitor)
+                .await;
+            Ok(result_from_error(faucet_abi, e))
+        }
     }
 }
 
-fn result_from_error(err: ContractError<DefaultSignerMiddleware>) -> DripResult {
-    if let Some(data) = err.as_revert() {
-        if data.len() < 4 {
-            return DripResult::Failure(err.to_string());
+/// Returns the batcher that coalesces drip requests into transactions to the drip batch
+/// contract at `batch_address`.
+pub fn drip_batcher(
+    config: BatchConfig,
+    pool: Arc<SignerPool>,
+    batch_address: Address,
+    faucet_abi: Arc<FaucetAbi>,
+    monitor: TxMonitor,
+) -> Batcher<DripCall> {
+    Batcher::spawn("drip", config, move |requests| {
+        drip_batch(
+            pool.clone(),
+            batch_address,
+            faucet_abi.clone(),
+            monitor.clone(),
+            requests,
+        )
+    })
+}
+
+/// Checks that drips can be batched through the contract at `batch_address`.
+///
+/// The faucet sees the batch contract as the caller of `drip`, so anyone who can call the batch
+/// contract can drip. It must be a contract every signer wallet can call `aggregate3Value` on
+/// while other accounts can't.
+pub async fn check_batch_contract(pool: &SignerPool, batch_address: Address) -> anyhow::Result<()> {
+    let primary = pool.primary();
+    if primary
+        .client
+        .get_code(batch_address, None)
+        .await?
+        .is_empty()
+    {
+        bail!("drip batch contract {:?} has no code", batch_address);
+    }
+    for signer in pool.signers() {
+        Multicall::new(batch_address, signer.client.clone())
+            .aggregate_3_value(vec![])
+            .call()
+            .await
+            .with_context(|| {
+                format!(
+                    "signer {:?} can't call drip batch contract {:?}",
+                    signer.address(),
+                    batch_address
+                )
+            })?;
+    }
+    let outsider = Address::repeat_byte(0x01);
+    let open = Multicall::new(batch_address, primary.client.clone())
+        .aggregate_3_value(vec![])
+        .from(outsider)
+        .call()
+        .await
+        .is_ok();
+    if open {
+        bail!(
+            "drip batch contract {:?} accepts calls from any account, it must only accept the \
+             signer wallets",
+            batch_address
+        );
+    }
+    Ok(())
+}
+
+/// Drips to an address as part of the next batched transaction.
+#[allow(clippy::too_many_arguments)]
+async fn drip_batched(
+    batcher: &Batcher<DripCall>,
+    pool: &Arc<SignerPool>,
+    faucet_abi: &Arc<FaucetAbi>,
+    ledger: &Ledger,
+    to_address: Address,
+    keys: Vec<String>,
+    wait: Option<bool>,
+    entry: &mut LedgerEntry,
+) -> anyhow::Result<DripResult> {
+    let ticket = match batcher
+        .submit(DripCall { to_address, keys }, entry.clone())
+        .await
+    {
+        Ok(ticket) => ticket,
+        Err(e) => {
+            return match e.downcast::<DripRejected>() {
+                Ok(DripRejected(result)) => Ok(result),
+                Err(e) => match e.downcast::<PrepareError>() {
+                    Ok(e @ PrepareError::InsufficientFunds { .. }) => {
+                        Ok(DripResult::InsufficientFunds(e.to_string()))
+                    }
+                    Ok(e) => Err(e.into()),
+                    Err(e) => Err(e),
+                },
+            }
         }
-        let selector = &data[..4];
-        if selector == *TRY_LATER_SELECTOR {
-            DripResult::RateLimited
-        } else if selector == *FAUCET_EMPTY_SELECTOR {
-            DripResult::FaucetEmpty
+    };
+    entry.tx_hash = Some(ticket.tx_hash);
+    entry.batch_index = Some(ticket.index as u32);
+    let tx_hash = ticket.tx_hash;
+    let resolve = resolve_batched_drip(
+        ticket,
+        pool.clone(),
+        faucet_abi.clone(),
+        ledger.clone(),
+        entry.clone(),
+    );
+    // The outcome of the call is recorded to the ledger once the batch is mined, even if the
+    // request stops waiting for it.
+    let resolved = tokio::spawn(resolve);
+    if !wait.unwrap_or(true) {
+        return Ok(DripResult::Pending(tx_hash));
+    }
+    match timeout(MAX_WAIT, resolved).await {
+        Ok(res) => res.map_err(|e| anyhow!("drip did not return a result: {}", e))?,
+        Err(_) => Ok(DripResult::Pending(tx_hash)),
+    }
+}
+
+/// Waits for a batched drip to be mined and returns the result of its call. Calls that
+/// reverted in the mined batch are recorded as reverted in the ledger.
+async fn resolve_batched_drip(
+    ticket: BatchTicket,
+    pool: Arc<SignerPool>,
+    faucet_abi: Arc<FaucetAbi>,
+    ledger: Ledger,
+    mut entry: LedgerEntry,
+) -> anyhow::Result<DripResult> {
+    let BatchOutcome { receipt, result } = ticket
+        .outcome
+        .await
+        .map_err(|_| anyhow!("drip did not return a receipt"))??;
+    let tx_hash = receipt.transaction_hash;
+    let result = if receipt.status != Some(1u64.into()) {
+        DripResult::Failure(format!("drip batch {:?} reverted", tx_hash))
+    } else {
+        match result {
+            Some(result) if result.success => DripResult::Success(tx_hash),
+            Some(result) => result_from_revert(&faucet_abi, &result.return_data),
+            // Without a trace of the batch, tell from the recipient balance whether the
+            // drip went through.
+            None if received_drip(&pool, entry.address, &receipt).await? => {
+                DripResult::Success(tx_hash)
+            }
+            None => DripResult::Failure(format!("drip reverted in batch {:?}", tx_hash)),
+        }
+    };
+    if !matches!(result, DripResult::Success(_)) {
+        entry.apply_receipt(&receipt);
+        entry.status = EntryStatus::Reverted;
+        entry.error = Some(result.to_string());
+        ledger.update(&entry).await;
+    }
+    Ok(result)
+}
+
+/// Returns whether the balance of `to_address` rose in the block of a batch receipt.
+async fn received_drip(
+    pool: &SignerPool,
+    to_address: Address,
+    receipt: &TransactionReceipt,
+) -> anyhow::Result<bool> {
+    let block = receipt
+        .block_number
+        .ok_or_else(|| anyhow!("receipt has no block number"))?;
+    let client = &pool.primary().client;
+    let before = client
+        .get_balance(to_address, Some((block - 1).into()))
+        .await?;
+    let after = client.get_balance(to_address, Some(block.into())).await?;
+    Ok(after > before)
+}
+
+/// Drips to a batch of addresses with a single transaction to the drip batch contract.
+///
+/// Inner calls are allowed to fail so that one reverting drip doesn't revert the others. The
+/// batch is simulated against the pending state first; calls that revert are rejected with
+/// their decoded result and left out of the transaction. Calls that revert once mined are
+/// mapped to their caller from the batch results.
+async fn drip_batch(
+    pool: Arc<SignerPool>,
+    batch_address: Address,
+    faucet_abi: Arc<FaucetAbi>,
+    monitor: TxMonitor,
+    requests: Vec<BatchRequest<DripCall>>,
+) {
+    let signer = pool.acquire();
+    let contract = Multicall::new(batch_address, signer.client.clone());
+    let mut encoded = Vec::with_capacity(requests.len());
+    let mut calls = Vec::with_capacity(requests.len());
+    for request in requests {
+        let call_data = faucet_abi
+            .drip_call(&signer.faucet, request.call.to_address, &request.call.keys)
+            .and_then(|call| {
+                call.calldata()
+                    .ok_or_else(|| anyhow!("failed to encode drip call"))
+            });
+        match call_data {
+            Ok(call_data) => {
+                encoded.push(request);
+                calls.push(Call3Value {
+                    target: signer.faucet.address(),
+                    allow_failure: true,
+                    value: U256::zero(),
+                    call_data,
+                });
+            }
+            Err(e) => {
+                let rejected = DripRejected(DripResult::Failure(e.to_string()));
+                let _ = request.sent.send(Err(rejected.into()));
+            }
+        }
+    }
+    if encoded.is_empty() {
+        return;
+    }
+
+    let simulation = contract
+        .aggregate_3_value(calls.clone())
+        .block(BlockNumber::Pending);
+    let results = match simulation.call().await {
+        Ok(results) => results,
+        Err(e) => return fail_batch(encoded, e.into()),
+    };
+    let mut accepted = vec![];
+    let mut accepted_calls = vec![];
+    for ((request, call), result) in encoded.into_iter().zip(calls).zip(results) {
+        if result.success {
+            accepted.push(request);
+            accepted_calls.push(call);
         } else {
-            DripResult::Failure(err.to_string())
+            let rejected = DripRejected(result_from_revert(&faucet_abi, &result.return_data));
+            let _ = request.sent.send(Err(rejected.into()));
         }
-    } else {
-        DripResult::Failure(err.to_string())
+    }
+    if !accepted.is_empty() {
+        send_batch(signer, contract, &monitor, accepted, accepted_calls).await;
+    }
+}
+
+fn result_from_error(
+    faucet_abi: &FaucetAbi,
+    err: ContractError<DefaultSignerMiddleware>,
+) -> DripResult {
+    match err.as_revert() {
+        Some(data) if data.len() >= 4 => match result_from_revert(faucet_abi, data) {
+            DripResult::Failure(_) => DripResult::Failure(err.to_string()),
+            result => result,
+        },
+        _ => DripResult::Failure(err.to_string()),
+    }
+}
+
+/// Maps the revert data of a drip call to its result, decoding it with the faucet ABI.
+fn result_from_revert(faucet_abi: &FaucetAbi, data: &[u8]) -> DripResult {
+    match faucet_abi.decode_revert(data) {
+        Some(revert) if faucet_abi.is_rate_limited(&revert) => DripResult::RateLimited,
+        Some(revert) if faucet_abi.is_empty(&revert) => DripResult::FaucetEmpty,
+        Some(revert) => DripResult::Reverted(revert),
+        None => DripResult::Failure(format!("drip reverted: 0x{}", hex::encode(data))),
     }
 }
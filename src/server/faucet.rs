@@ -0,0 +1,239 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+use clap::ValueEnum;
+use ethers::abi::{Abi, Function, ParamType, Token};
+use ethers::contract::{BaseContract, Contract, ContractCall};
+use ethers::prelude::Address;
+use ethers::utils::keccak256;
+
+use crate::server::revert::{decode_revert, Revert};
+use crate::server::shared::{DefaultSignerMiddleware, Faucet, FAUCETCONTRACT_ABI};
+
+/// Argument of the faucet drip method.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum DripArg {
+    /// Drip recipient, as `address`.
+    Recipient,
+    /// Faucet keys, as `string[]`.
+    Keys,
+    /// keccak256 hashes of the faucet keys, as `bytes32[]`.
+    KeyHashes,
+}
+
+impl DripArg {
+    fn param_type(&self) -> ParamType {
+        match self {
+            DripArg::Recipient => ParamType::Address,
+            DripArg::Keys => ParamType::Array(Box::new(ParamType::String)),
+            DripArg::KeyHashes => ParamType::Array(Box::new(ParamType::FixedBytes(32))),
+        }
+    }
+}
+
+/// Faucet contract interface settings.
+#[derive(Clone, Debug)]
+pub struct FaucetAbiConfig {
+    /// JSON ABI file of the deployed faucet, either a plain ABI or a build artifact with an
+    /// `abi` field. The compiled-in ABI is used if not set.
+    pub abi_path: Option<PathBuf>,
+    /// Name or signature, e.g. `drip(address,string[])`, of the drip method.
+    pub drip_method: String,
+    /// Arguments passed to the drip method, in order.
+    pub drip_args: Vec<DripArg>,
+    /// Errors meaning the faucet cooldown of a key hasn't passed.
+    pub rate_limited_errors: Vec<String>,
+    /// Errors meaning the faucet is out of funds.
+    pub empty_errors: Vec<String>,
+}
+
+/// Interface of the deployed faucet contract: how to call drip and how to classify its errors.
+#[derive(Clone, Debug)]
+pub struct FaucetAbi {
+    contract: BaseContract,
+    drip: Function,
+    args: Vec<DripArg>,
+    rate_limited_errors: Vec<String>,
+    empty_errors: Vec<String>,
+}
+
+impl FaucetAbi {
+    /// Loads the faucet ABI and checks that the drip method takes the configured arguments.
+    pub fn load(config: FaucetAbiConfig) -> anyhow::Result<Self> {
+        let abi = match &config.abi_path {
+            Some(path) => {
+                let json = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read faucet abi {}", path.display()))?;
+                parse_abi(&json)
+                    .with_context(|| format!("invalid faucet abi {}", path.display()))?
+            }
+            None => FAUCETCONTRACT_ABI.clone(),
+        };
+
+        let method = config.drip_method.replace(' ', "");
+        let drip = abi
+            .functions()
+            .find(|f| f.name == method || signature(f) == method)
+            .ok_or_else(|| anyhow!("faucet abi has no method {}", config.drip_method))?
+            .clone();
+        let expected: Vec<ParamType> = config.drip_args.iter().map(|a| a.param_type()).collect();
+        let actual: Vec<ParamType> = drip.inputs.iter().map(|p| p.kind.clone()).collect();
+        if expected != actual {
+            bail!(
+                "faucet method {} doesn't match the drip arguments {:?}",
+                signature(&drip),
+                config.drip_args
+            );
+        }
+        for error in config
+            .rate_limited_errors
+            .iter()
+            .chain(&config.empty_errors)
+        {
+            if !abi.errors.contains_key(error) {
+                bail!("faucet abi has no error {}", error);
+            }
+        }
+
+        Ok(Self {
+            contract: BaseContract::from(abi),
+            drip,
+            args: config.drip_args,
+            rate_limited_errors: config.rate_limited_errors,
+            empty_errors: config.empty_errors,
+        })
+    }
+
+    /// Returns the signature of the drip method.
+    pub fn drip_signature(&self) -> String {
+        signature(&self.drip)
+    }
+
+    /// Builds a drip call to `faucet`.
+    pub fn drip_call(
+        &self,
+        faucet: &Faucet,
+        to_address: Address,
+        keys: &[String],
+    ) -> anyhow::Result<ContractCall<DefaultSignerMiddleware, ()>> {
+        let tokens: Vec<Token> = self
+            .args
+            .iter()
+            .map(|arg| match arg {
+                DripArg::Recipient => Token::Address(to_address),
+                DripArg::Keys => {
+                    Token::Array(keys.iter().map(|k| Token::String(k.clone())).collect())
+                }
+                DripArg::KeyHashes => Token::Array(
+                    keys.iter()
+                        .map(|k| Token::FixedBytes(keccak256(k).to_vec()))
+                        .collect(),
+                ),
+            })
+            .collect();
+        let contract = Contract::new(faucet.address(), self.contract.clone(), faucet.client());
+        Ok(contract.method_hash(self.drip.short_signature(), tokens.as_slice())?)
+    }
+
+    /// Decodes the revert data of a faucet call.
+    pub fn decode_revert(&self, data: &[u8]) -> Option<Revert> {
+        decode_revert(self.contract.abi(), data)
+    }
+
+    /// Returns whether a revert means the faucet cooldown of a key hasn't passed.
+    pub fn is_rate_limited(&self, revert: &Revert) -> bool {
+        self.rate_limited_errors.contains(&revert.error)
+    }
+
+    /// Returns whether a revert means the faucet is out of funds.
+    pub fn is_empty(&self, revert: &Revert) -> bool {
+        self.empty_errors.contains(&revert.error)
+    }
+}
+
+/// Parses a plain JSON ABI or a build artifact holding it in `abi`.
+fn parse_abi(json: &str) -> anyhow::Result<Abi> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    let abi = match value {
+        serde_json::Value::Object(mut artifact) => artifact
+            .remove("abi")
+            .ok_or_else(|| anyhow!("expected an abi array or an artifact with an abi field"))?,
+        abi => abi,
+    };
+    Ok(serde_json::from_value(abi)?)
+}
+
+/// Formats the canonical signature of a function, e.g. `drip(address,string[])`.
+fn signature(function: &Function) -> String {
+    let inputs: Vec<String> = function.inputs.iter().map(|p| p.kind.to_string()).collect();
+    format!("{}({})", function.name, inputs.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use ethers::abi::short_signature;
+
+    use super::*;
+
+    const ARTIFACT: &str = r#"{"abi": [
+        {"type": "function", "name": "drip", "stateMutability": "nonpayable", "outputs": [],
+         "inputs": [{"name": "to", "type": "address"}, {"name": "keys", "type": "bytes32[]"}]},
+        {"type": "error", "name": "Cooldown", "inputs": []}
+    ]}"#;
+
+    static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+    fn config(drip_args: Vec<DripArg>) -> FaucetAbiConfig {
+        let path = std::env::temp_dir().join(format!(
+            "faucet-abi-{}-{}.json",
+            std::process::id(),
+            NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, ARTIFACT).unwrap();
+        FaucetAbiConfig {
+            abi_path: Some(path),
+            drip_method: "drip(address, bytes32[])".to_string(),
+            drip_args,
+            rate_limited_errors: vec!["Cooldown".to_string()],
+            empty_errors: vec![],
+        }
+    }
+
+    #[test]
+    fn loads_runtime_abi() {
+        let abi = FaucetAbi::load(config(vec![DripArg::Recipient, DripArg::KeyHashes])).unwrap();
+        assert_eq!(abi.drip_signature(), "drip(address,bytes32[])");
+
+        let revert = abi
+            .decode_revert(&short_signature("Cooldown", &[]))
+            .unwrap();
+        assert!(abi.is_rate_limited(&revert));
+        assert!(!abi.is_empty(&revert));
+    }
+
+    #[test]
+    fn rejects_mismatched_arguments() {
+        let err = FaucetAbi::load(config(vec![DripArg::Recipient, DripArg::Keys])).unwrap_err();
+        assert!(err.to_string().contains("doesn't match"));
+
+        let mut config = config(vec![DripArg::Recipient, DripArg::KeyHashes]);
+        config.empty_errors = vec!["FaucetEmpty".to_string()];
+        assert!(FaucetAbi::load(config).is_err());
+    }
+
+    #[test]
+    fn defaults_to_compiled_abi() {
+        let abi = FaucetAbi::load(FaucetAbiConfig {
+            abi_path: None,
+            drip_method: "drip".to_string(),
+            drip_args: vec![DripArg::Recipient, DripArg::Keys],
+            rate_limited_errors: vec!["TryLater".to_string()],
+            empty_errors: vec!["FaucetEmpty".to_string()],
+        })
+        .unwrap();
+        assert_eq!(abi.drip_signature(), "drip(address,string[])");
+    }
+}
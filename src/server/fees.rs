@@ -0,0 +1,658 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use ethers::prelude::{BlockNumber, Http, Middleware, Provider, U256};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::{Eip1559TransactionRequest, TransactionRequest};
+use lazy_static::lazy_static;
+use log::warn;
+use prometheus::{register_gauge, register_int_counter_vec, Gauge, IntCounterVec};
+
+lazy_static! {
+    static ref FEE_ORACLE_READS: IntCounterVec = register_int_counter_vec!(
+        "fee_oracle_reads_total",
+        "Number of fee estimates read from the fee oracle, by whether the cache was fresh.",
+        &["result"]
+    )
+    .unwrap();
+    static ref FEE_ORACLE_REFRESHES: IntCounterVec = register_int_counter_vec!(
+        "fee_oracle_refreshes_total",
+        "Number of fee estimate refreshes, by result.",
+        &["result"]
+    )
+    .unwrap();
+    static ref FEE_ORACLE_MAX_FEE: Gauge = register_gauge!(
+        "fee_oracle_max_fee_per_gas",
+        "Cached max fee per gas, or gas price of legacy transactions, in wei."
+    )
+    .unwrap();
+}
+
+/// How the fees of sent transactions are chosen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum FeeStrategyKind {
+    /// EIP-1559 fees from the reward percentiles of recent blocks, or `eth_gasPrice` on chains
+    /// without base fees.
+    #[default]
+    Percentile,
+    /// Fixed EIP-1559 fees, see `--fixed-max-fee` and `--fixed-priority-fee`.
+    Fixed,
+    /// Legacy transactions priced with `eth_gasPrice`.
+    Legacy,
+    /// EIP-1559 fees with the priority fee from `eth_maxPriorityFeePerGas`.
+    PriorityFee,
+}
+
+/// Settings for building the fee strategy.
+#[derive(Clone, Copy, Debug)]
+pub struct FeeConfig {
+    pub strategy: FeeStrategyKind,
+    /// Max fee per gas of the fixed strategy.
+    pub fixed_max_fee: Option<U256>,
+    /// Priority fee per gas of the fixed strategy.
+    pub fixed_priority_fee: Option<U256>,
+    pub min_priority_fee: Option<U256>,
+    pub max_priority_fee: Option<U256>,
+    /// Minimum max fee per gas, or gas price of legacy transactions.
+    pub min_fee_cap: Option<U256>,
+    /// Maximum max fee per gas, or gas price of legacy transactions.
+    pub max_fee_cap: Option<U256>,
+}
+
+/// Fees of a transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fees {
+    Eip1559 {
+        max_fee_per_gas: U256,
+        max_priority_fee_per_gas: U256,
+    },
+    Legacy {
+        gas_price: U256,
+    },
+}
+
+impl Fees {
+    /// Sets the fees of a transaction, converting it to the matching transaction type.
+    pub fn apply(&self, tx: &mut TypedTransaction) {
+        match (*self, &mut *tx) {
+            (
+                Fees::Eip1559 {
+                    max_fee_per_gas,
+                    max_priority_fee_per_gas,
+                },
+                TypedTransaction::Eip1559(inner),
+            ) => {
+                inner.max_fee_per_gas = Some(max_fee_per_gas);
+                inner.max_priority_fee_per_gas = Some(max_priority_fee_per_gas);
+            }
+            (Fees::Legacy { gas_price }, TypedTransaction::Legacy(inner)) => {
+                inner.gas_price = Some(gas_price);
+            }
+            (Fees::Eip1559 { .. }, _) => {
+                *tx = Eip1559TransactionRequest {
+                    from: tx.from().copied(),
+                    to: tx.to().cloned(),
+                    gas: tx.gas().copied(),
+                    value: tx.value().copied(),
+                    data: tx.data().cloned(),
+                    nonce: tx.nonce().copied(),
+                    chain_id: tx.chain_id().map(|id| id.as_u64().into()),
+                    ..Default::default()
+                }
+                .into();
+                self.apply(tx);
+            }
+            (Fees::Legacy { .. }, _) => {
+                *tx = TransactionRequest {
+                    from: tx.from().copied(),
+                    to: tx.to().cloned(),
+                    gas: tx.gas().copied(),
+                    value: tx.value().copied(),
+                    data: tx.data().cloned(),
+                    nonce: tx.nonce().copied(),
+                    chain_id: tx.chain_id().map(|id| id.as_u64().into()),
+                    ..Default::default()
+                }
+                .into();
+                self.apply(tx);
+            }
+        }
+    }
+}
+
+/// Chooses the fees of sent transactions.
+#[async_trait]
+pub trait FeeStrategy: Send + Sync {
+    /// Name of the strategy, used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Returns the fees for the next transaction.
+    async fn fees(&self, provider: &Provider<Http>) -> anyhow::Result<Fees>;
+}
+
+/// Builds the configured fee strategy, with the configured clamps applied to its fees.
+pub fn build_fee_strategy(config: FeeConfig) -> anyhow::Result<Arc<dyn FeeStrategy>> {
+    let inner: Box<dyn FeeStrategy> = match config.strategy {
+        FeeStrategyKind::Percentile => Box::new(PercentileFees {
+            floor: config.min_priority_fee.unwrap_or_default(),
+        }),
+        FeeStrategyKind::Fixed => {
+            let (Some(max_fee), Some(priority_fee)) =
+                (config.fixed_max_fee, config.fixed_priority_fee)
+            else {
+                return Err(anyhow!(
+                    "fixed fees require a fixed max fee and priority fee"
+                ));
+            };
+            if priority_fee > max_fee {
+                return Err(anyhow!("fixed priority fee exceeds the fixed max fee"));
+            }
+            Box::new(FixedFees(Fees::Eip1559 {
+                max_fee_per_gas: max_fee,
+                max_priority_fee_per_gas: priority_fee,
+            }))
+        }
+        FeeStrategyKind::Legacy => Box::new(LegacyFees),
+        FeeStrategyKind::PriorityFee => Box::new(PriorityFeeFees),
+    };
+    let inverted = |min: Option<U256>, max: Option<U256>| min.zip(max).is_some_and(|(a, b)| a > b);
+    if inverted(config.min_priority_fee, config.max_priority_fee)
+        || inverted(config.min_fee_cap, config.max_fee_cap)
+    {
+        return Err(anyhow!("minimum fee clamps must not exceed the maximums"));
+    }
+    Ok(Arc::new(ClampedFees { inner, config }))
+}
+
+/// Wraps a strategy to keep its fees within the configured bounds.
+struct ClampedFees {
+    inner: Box<dyn FeeStrategy>,
+    config: FeeConfig,
+}
+
+impl ClampedFees {
+    fn clamp(&self, fees: Fees) -> Fees {
+        let fee_cap = |fee: U256| clamp(fee, self.config.min_fee_cap, self.config.max_fee_cap);
+        match fees {
+            Fees::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            } => {
+                let max_fee_per_gas = fee_cap(max_fee_per_gas);
+                let priority_fee = clamp(
+                    max_priority_fee_per_gas,
+                    self.config.min_priority_fee,
+                    self.config.max_priority_fee,
+                );
+                Fees::Eip1559 {
+                    max_fee_per_gas,
+                    max_priority_fee_per_gas: priority_fee.min(max_fee_per_gas),
+                }
+            }
+            Fees::Legacy { gas_price } => Fees::Legacy {
+                gas_price: fee_cap(gas_price),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl FeeStrategy for ClampedFees {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn fees(&self, provider: &Provider<Http>) -> anyhow::Result<Fees> {
+        Ok(self.clamp(self.inner.fees(provider).await?))
+    }
+}
+
+/// Settings of the cached fee oracle.
+#[derive(Clone, Copy, Debug)]
+pub struct FeeOracleConfig {
+    /// Time between checks for a new block. The estimate is refreshed once per block.
+    pub refresh_interval: Duration,
+    /// Age after which the cached estimate is no longer used.
+    pub max_age: Duration,
+}
+
+/// Fee estimate cached by the fee oracle.
+#[derive(Clone, Copy, Debug)]
+struct CachedFees {
+    fees: Fees,
+    fetched_at: Instant,
+}
+
+/// Refreshes the estimate of a fee strategy in the background, so that sending a transaction
+/// reads a cached estimate instead of querying the RPC.
+///
+/// If the cache is older than the maximum age, e.g. because refreshes keep failing, the fees
+/// are estimated live as without the oracle.
+pub struct FeeOracle {
+    strategy: Arc<dyn FeeStrategy>,
+    cache: Arc<RwLock<Option<CachedFees>>>,
+    max_age: Duration,
+}
+
+impl FeeOracle {
+    /// Creates the oracle and starts refreshing its estimate.
+    pub fn spawn(
+        strategy: Arc<dyn FeeStrategy>,
+        provider: Provider<Http>,
+        config: FeeOracleConfig,
+    ) -> Arc<Self> {
+        let oracle = Arc::new(Self {
+            strategy: strategy.clone(),
+            cache: Arc::new(RwLock::new(None)),
+            max_age: config.max_age,
+        });
+        let cache = oracle.cache.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(config.refresh_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut last_block = None;
+            loop {
+                interval.tick().await;
+                let block = match provider.get_block_number().await {
+                    Ok(block) => block,
+                    Err(e) => {
+                        warn!("failed to get block number for fee estimate: {}", e);
+                        continue;
+                    }
+                };
+                if last_block == Some(block) {
+                    continue;
+                }
+                match refresh(strategy.as_ref(), &provider, &cache).await {
+                    Ok(_) => last_block = Some(block),
+                    Err(e) => warn!("failed to refresh fee estimate: {}", e),
+                }
+            }
+        });
+        oracle
+    }
+}
+
+#[async_trait]
+impl FeeStrategy for FeeOracle {
+    fn name(&self) -> &'static str {
+        self.strategy.name()
+    }
+
+    async fn fees(&self, provider: &Provider<Http>) -> anyhow::Result<Fees> {
+        let cached = *self.cache.read().unwrap();
+        if let Some(cached) = cached.filter(|c| c.fetched_at.elapsed() <= self.max_age) {
+            FEE_ORACLE_READS.with_label_values(&["fresh"]).inc();
+            return Ok(cached.fees);
+        }
+        FEE_ORACLE_READS.with_label_values(&["stale"]).inc();
+        refresh(self.strategy.as_ref(), provider, &self.cache).await
+    }
+}
+
+/// Estimates fees with a strategy and stores them in the cache.
+async fn refresh(
+    strategy: &dyn FeeStrategy,
+    provider: &Provider<Http>,
+    cache: &RwLock<Option<CachedFees>>,
+) -> anyhow::Result<Fees> {
+    let fees = match strategy.fees(provider).await {
+        Ok(fees) => fees,
+        Err(e) => {
+            FEE_ORACLE_REFRESHES.with_label_values(&["error"]).inc();
+            return Err(e);
+        }
+    };
+    FEE_ORACLE_REFRESHES.with_label_values(&["ok"]).inc();
+    let fee_cap = match fees {
+        Fees::Eip1559 {
+            max_fee_per_gas, ..
+        } => max_fee_per_gas,
+        Fees::Legacy { gas_price } => gas_price,
+    };
+    FEE_ORACLE_MAX_FEE.set(fee_cap.low_u128() as f64);
+    *cache.write().unwrap() = Some(CachedFees {
+        fees,
+        fetched_at: Instant::now(),
+    });
+    Ok(fees)
+}
+
+fn clamp(value: U256, min: Option<U256>, max: Option<U256>) -> U256 {
+    let value = min.map_or(value, |min| value.max(min));
+    max.map_or(value, |max| value.min(max))
+}
+
+/// EIP-1559 fees from the reward percentiles of recent blocks.
+struct PercentileFees {
+    /// Priority fee used if the fee history has no usable rewards.
+    floor: U256,
+}
+
+#[async_trait]
+impl FeeStrategy for PercentileFees {
+    fn name(&self) -> &'static str {
+        "percentile"
+    }
+
+    /// Returns an estimation of an optimal `gas_premium` and `gas_fee_cap`
+    /// for a transaction considering the average premium, base_fee and reward percentile from
+    /// past blocks
+    /// This is an adaptation of ethers' `eip1559_default_estimator`:
+    /// https://github.com/gakonst/ethers-rs/blob/5dcd3b7e754174448f9a8cbfc0523896609629f9/ethers-core/src/utils/mod.rs#L476
+    async fn fees(&self, provider: &Provider<Http>) -> anyhow::Result<Fees> {
+        let Some(base_fee_per_gas) = latest_base_fee(provider).await? else {
+            return LegacyFees.fees(provider).await;
+        };
+
+        let percentiles = [ethers::utils::EIP1559_FEE_ESTIMATION_REWARD_PERCENTILE];
+        let fee_history = provider
+            .fee_history(
+                ethers::utils::EIP1559_FEE_ESTIMATION_PAST_BLOCKS,
+                BlockNumber::Latest,
+                &percentiles,
+            )
+            .await?;
+
+        let max_priority_fee_per_gas =
+            match estimate_priority_fee(&fee_history.reward, percentiles.len()) {
+                Some(fee) => fee,
+                None => {
+                    warn!(
+                        "no usable rewards in fee history of {} blocks, using priority fee floor",
+                        fee_history.reward.len()
+                    );
+                    self.floor
+                }
+            };
+        Ok(eip1559_fees(base_fee_per_gas, max_priority_fee_per_gas))
+    }
+}
+
+/// Fixed fees.
+struct FixedFees(Fees);
+
+#[async_trait]
+impl FeeStrategy for FixedFees {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    async fn fees(&self, _provider: &Provider<Http>) -> anyhow::Result<Fees> {
+        Ok(self.0)
+    }
+}
+
+/// Legacy gas price from `eth_gasPrice`.
+struct LegacyFees;
+
+#[async_trait]
+impl FeeStrategy for LegacyFees {
+    fn name(&self) -> &'static str {
+        "legacy"
+    }
+
+    async fn fees(&self, provider: &Provider<Http>) -> anyhow::Result<Fees> {
+        Ok(Fees::Legacy {
+            gas_price: provider.get_gas_price().await?,
+        })
+    }
+}
+
+/// EIP-1559 fees with the priority fee suggested by `eth_maxPriorityFeePerGas`.
+struct PriorityFeeFees;
+
+#[async_trait]
+impl FeeStrategy for PriorityFeeFees {
+    fn name(&self) -> &'static str {
+        "priority_fee"
+    }
+
+    async fn fees(&self, provider: &Provider<Http>) -> anyhow::Result<Fees> {
+        let Some(base_fee_per_gas) = latest_base_fee(provider).await? else {
+            return LegacyFees.fees(provider).await;
+        };
+        let priority_fee: U256 = provider.request("eth_maxPriorityFeePerGas", ()).await?;
+        Ok(eip1559_fees(base_fee_per_gas, priority_fee))
+    }
+}
+
+/// Returns the base fee of the latest block, or `None` if the chain has no base fees.
+async fn latest_base_fee(provider: &Provider<Http>) -> anyhow::Result<Option<U256>> {
+    Ok(provider
+        .get_block(BlockNumber::Latest)
+        .await?
+        .ok_or_else(|| anyhow!("Latest block not found"))?
+        .base_fee_per_gas)
+}
+
+/// Returns EIP-1559 fees leaving room for the base fee to rise.
+fn eip1559_fees(base_fee_per_gas: U256, max_priority_fee_per_gas: U256) -> Fees {
+    let potential_max_fee = base_fee_surged(base_fee_per_gas);
+    let max_fee_per_gas = if max_priority_fee_per_gas > potential_max_fee {
+        max_priority_fee_per_gas.saturating_add(potential_max_fee)
+    } else {
+        potential_max_fee
+    };
+    Fees::Eip1559 {
+        max_fee_per_gas,
+        max_priority_fee_per_gas,
+    }
+}
+
+/// Implementation borrowed from
+/// https://github.com/gakonst/ethers-rs/blob/ethers-v2.0.8/ethers-core/src/utils/mod.rs#L582
+/// Refer to the implementation for unit tests
+fn base_fee_surged(base_fee_per_gas: U256) -> U256 {
+    let (numerator, denominator) = if base_fee_per_gas <= U256::from(40_000_000_000u64) {
+        (2, 1)
+    } else if base_fee_per_gas <= U256::from(100_000_000_000u64) {
+        (16, 10)
+    } else if base_fee_per_gas <= U256::from(200_000_000_000u64) {
+        (14, 10)
+    } else {
+        (12, 10)
+    };
+    base_fee_per_gas
+        .checked_mul(U256::from(numerator))
+        .map_or(U256::MAX, |fee| fee / denominator)
+}
+
+/// Estimates the priority fee from the rewards of a fee history requested for `percentiles`
+/// reward percentiles, using the first percentile. Returns `None` if no block has a usable
+/// nonzero reward.
+///
+/// Adapted from
+/// https://github.com/gakonst/ethers-rs/blob/ethers-v2.0.8/ethers-core/src/utils/mod.rs#L536
+/// but never panics: reward rows of the wrong length are skipped and the arithmetic saturates.
+fn estimate_priority_fee(rewards: &[Vec<U256>], percentiles: usize) -> Option<U256> {
+    let mut rewards: Vec<U256> = rewards
+        .iter()
+        .filter(|r| r.len() == percentiles)
+        .filter_map(|r| r.first().copied())
+        .filter(|r| !r.is_zero())
+        .collect();
+    // Sort the rewards as we will eventually take the median.
+    rewards.sort();
+
+    // Percentage change between subsequent rewards, which are nonzero and ascending.
+    let max_change = rewards
+        .windows(2)
+        .map(|w| (w[1] - w[0]).saturating_mul(U256::from(100)) / w[0])
+        .enumerate()
+        .fold(None, |max: Option<(usize, U256)>, (i, change)| match max {
+            Some((_, max_change)) if max_change >= change => max,
+            _ => Some((i, change)),
+        });
+
+    // If we encountered a big change in fees at a certain position, then consider only
+    // the values >= it.
+    let threshold = U256::from(ethers::utils::EIP1559_FEE_ESTIMATION_THRESHOLD_MAX_CHANGE);
+    let values = match max_change {
+        Some((index, change)) if change >= threshold && index >= rewards.len() / 2 => {
+            &rewards[index..]
+        }
+        _ => &rewards[..],
+    };
+
+    // Return the median.
+    values.get(values.len() / 2).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::prelude::Address;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn gwei(n: u64) -> U256 {
+        U256::from(n) * 1_000_000_000u64
+    }
+
+    #[test]
+    fn clamps_fees() {
+        let clamped = ClampedFees {
+            inner: Box::new(LegacyFees),
+            config: FeeConfig {
+                strategy: FeeStrategyKind::Legacy,
+                fixed_max_fee: None,
+                fixed_priority_fee: None,
+                min_priority_fee: Some(gwei(1)),
+                max_priority_fee: Some(gwei(5)),
+                min_fee_cap: Some(gwei(2)),
+                max_fee_cap: Some(gwei(10)),
+            },
+        };
+        let fees = clamped.clamp(Fees::Eip1559 {
+            max_fee_per_gas: gwei(50),
+            max_priority_fee_per_gas: U256::zero(),
+        });
+        assert_eq!(
+            fees,
+            Fees::Eip1559 {
+                max_fee_per_gas: gwei(10),
+                max_priority_fee_per_gas: gwei(1),
+            }
+        );
+        // The priority fee never exceeds the max fee.
+        let fees = clamped.clamp(Fees::Eip1559 {
+            max_fee_per_gas: gwei(1),
+            max_priority_fee_per_gas: gwei(4),
+        });
+        assert_eq!(
+            fees,
+            Fees::Eip1559 {
+                max_fee_per_gas: gwei(2),
+                max_priority_fee_per_gas: gwei(2),
+            }
+        );
+        let fees = clamped.clamp(Fees::Legacy { gas_price: gwei(1) });
+        assert_eq!(fees, Fees::Legacy { gas_price: gwei(2) });
+    }
+
+    #[test]
+    fn converts_transaction_type() {
+        let to = Address::repeat_byte(1);
+        let mut tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .to(to)
+            .value(7)
+            .gas(21_000)
+            .into();
+        Fees::Legacy { gas_price: gwei(3) }.apply(&mut tx);
+        let TypedTransaction::Legacy(inner) = &tx else {
+            panic!("expected a legacy transaction");
+        };
+        assert_eq!(inner.gas_price, Some(gwei(3)));
+        assert_eq!(tx.to_addr(), Some(&to));
+        assert_eq!(tx.value(), Some(&U256::from(7)));
+        assert_eq!(tx.gas(), Some(&U256::from(21_000)));
+
+        Fees::Eip1559 {
+            max_fee_per_gas: gwei(4),
+            max_priority_fee_per_gas: gwei(1),
+        }
+        .apply(&mut tx);
+        assert_eq!(tx.gas_price(), Some(gwei(4)));
+        assert!(matches!(tx, TypedTransaction::Eip1559(_)));
+    }
+
+    #[tokio::test]
+    async fn replaces_stale_cached_fees() {
+        let provider = Provider::<Http>::try_from("http://127.0.0.1:1").unwrap();
+        let live = Fees::Legacy { gas_price: gwei(3) };
+        let cached = Fees::Legacy { gas_price: gwei(1) };
+        let oracle = FeeOracle {
+            strategy: Arc::new(FixedFees(live)),
+            cache: Arc::new(RwLock::new(Some(CachedFees {
+                fees: cached,
+                fetched_at: Instant::now(),
+            }))),
+            max_age: Duration::from_secs(60),
+        };
+        assert_eq!(oracle.fees(&provider).await.unwrap(), cached);
+
+        oracle.cache.write().unwrap().as_mut().unwrap().fetched_at -= Duration::from_secs(61);
+        assert_eq!(oracle.fees(&provider).await.unwrap(), live);
+        assert_eq!(oracle.fees(&provider).await.unwrap(), live);
+    }
+
+    #[test]
+    fn skips_unusable_rewards() {
+        assert_eq!(estimate_priority_fee(&[], 1), None);
+        assert_eq!(
+            estimate_priority_fee(&[vec![], vec![U256::zero()], vec![gwei(1), gwei(2)]], 1),
+            None
+        );
+        assert_eq!(
+            estimate_priority_fee(&[vec![], vec![gwei(2)], vec![U256::zero()]], 1),
+            Some(gwei(2))
+        );
+        assert_eq!(
+            estimate_priority_fee(&[vec![gwei(1)], vec![gwei(2)], vec![gwei(3)]], 1),
+            Some(gwei(2))
+        );
+    }
+
+    fn reward() -> impl Strategy<Value = U256> {
+        prop_oneof![
+            Just(U256::zero()),
+            Just(U256::MAX),
+            any::<u64>().prop_map(U256::from),
+            any::<[u8; 32]>().prop_map(|bytes| U256::from_big_endian(&bytes)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn estimates_priority_fee_from_random_history(
+            rewards in prop::collection::vec(prop::collection::vec(reward(), 0..3), 0..20)
+        ) {
+            let usable: Vec<U256> = rewards
+                .iter()
+                .filter(|r| r.len() == 1 && !r[0].is_zero())
+                .map(|r| r[0])
+                .collect();
+            match estimate_priority_fee(&rewards, 1) {
+                Some(fee) => prop_assert!(usable.contains(&fee)),
+                None => prop_assert!(usable.is_empty()),
+            }
+        }
+
+        #[test]
+        fn surges_base_fee_without_overflow(base_fee in reward(), priority_fee in reward()) {
+            let Fees::Eip1559 { max_fee_per_gas, max_priority_fee_per_gas } =
+                eip1559_fees(base_fee, priority_fee)
+            else {
+                unreachable!()
+            };
+            prop_assert_eq!(max_priority_fee_per_gas, priority_fee);
+            prop_assert!(max_fee_per_gas >= base_fee);
+            prop_assert!(max_fee_per_gas >= priority_fee);
+        }
+    }
+}
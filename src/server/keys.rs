@@ -0,0 +1,332 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+use clap::ValueEnum;
+use ethers::prelude::Address;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Number of bytes of the HMAC kept in a key.
+const KEY_BYTES: usize = 16;
+
+/// Versioned secret used to derive faucet keys, given as `<version>:<secret>`.
+#[derive(Clone)]
+pub struct KeySecret {
+    version: u32,
+    secret: Vec<u8>,
+}
+
+impl FromStr for KeySecret {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (version, secret) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("expected <version>:<secret>"))?;
+        let version = version
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid key secret version {}", version))?;
+        if secret.is_empty() {
+            bail!("empty key secret for version {}", version);
+        }
+        Ok(KeySecret {
+            version,
+            secret: secret.as_bytes().to_vec(),
+        })
+    }
+}
+
+// Never print the secret itself.
+impl fmt::Debug for KeySecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KeySecret(v{})", self.version)
+    }
+}
+
+/// Signal a faucet key is derived from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum DripKeyKind {
+    /// Target address.
+    Address,
+    /// Client IP address.
+    Ip,
+    /// Client network, see `--drip-key-ipv4-prefix` and `--drip-key-ipv6-prefix`.
+    IpPrefix,
+    /// `User-Agent` header.
+    UserAgent,
+    /// Client-supplied `X-Device-Fingerprint` header.
+    Device,
+    /// Authenticated user ID, from the `X-User-Id` header set by a trusted proxy.
+    User,
+    /// API key ID, from the `X-Api-Key-Id` header set by a trusted proxy.
+    ApiKey,
+}
+
+impl DripKeyKind {
+    /// Describes where the signal of the key type comes from, for error messages.
+    pub fn source(&self) -> &'static str {
+        match self {
+            DripKeyKind::Address => "target address",
+            DripKeyKind::Ip | DripKeyKind::IpPrefix => "client IP",
+            DripKeyKind::UserAgent => "User-Agent header",
+            DripKeyKind::Device => "X-Device-Fingerprint header",
+            DripKeyKind::User => "X-User-Id header from a trusted proxy",
+            DripKeyKind::ApiKey => "X-Api-Key-Id header from a trusted proxy",
+        }
+    }
+}
+
+/// Request signals faucet keys can be derived from, besides the target address and client IP.
+#[derive(Clone, Debug, Default)]
+pub struct DripSignals {
+    pub user_agent: Option<String>,
+    pub device: Option<String>,
+    pub user_id: Option<String>,
+    pub api_key_id: Option<String>,
+}
+
+/// Faucet key derived from a signal.
+#[derive(Clone, Debug, Serialize)]
+pub struct DripKey {
+    #[serde(rename = "type")]
+    pub kind: DripKeyKind,
+    pub key: String,
+}
+
+/// Faucet key derivation settings.
+#[derive(Clone, Debug)]
+pub struct KeyConfig {
+    /// Signals each drip gets a key for.
+    pub kinds: Vec<DripKeyKind>,
+    /// Secrets keys are derived with. Each one yields its own set of keys, so a new secret can be
+    /// added while cooldowns recorded under the previous one still apply.
+    pub secrets: Vec<KeySecret>,
+    /// Prefix length of `ip_prefix` keys for IPv4 clients.
+    pub ipv4_prefix: u8,
+    /// Prefix length of `ip_prefix` keys for IPv6 clients.
+    pub ipv6_prefix: u8,
+}
+
+/// Derives the keys passed to the faucet contract, which enforces a cooldown per key.
+///
+/// Keys are `v<version>:<hex>`, where `<hex>` is a truncated HMAC-SHA256 of the signal, so no
+/// plaintext IP address ends up on chain.
+#[derive(Clone, Debug)]
+pub struct KeyDeriver {
+    config: KeyConfig,
+}
+
+impl KeyDeriver {
+    pub fn new(config: KeyConfig) -> anyhow::Result<Self> {
+        if config.secrets.is_empty() {
+            bail!("at least one drip key secret is required");
+        }
+        if config.kinds.is_empty() {
+            bail!("at least one drip key type is required");
+        }
+        if config.ipv4_prefix > 32 || config.ipv6_prefix > 128 {
+            bail!("invalid drip key ip prefix length");
+        }
+        Ok(Self { config })
+    }
+
+    /// Returns the keys for a drip to `address` requested from `ip`, or the configured key
+    /// types the request has no signal for. A drip missing a signal must be rejected, as it
+    /// would otherwise escape the cooldown of that key type.
+    pub fn drip_keys(
+        &self,
+        address: Address,
+        ip: IpAddr,
+        signals: &DripSignals,
+    ) -> Result<Vec<DripKey>, Vec<DripKeyKind>> {
+        let mut keys = vec![];
+        let mut missing = vec![];
+        for &kind in &self.config.kinds {
+            let (domain, value) = match kind {
+                DripKeyKind::Address => ("address", Some(format!("{:?}", address))),
+                DripKeyKind::Ip => ("ip", Some(truncate(ip, 32, 128))),
+                DripKeyKind::IpPrefix => (
+                    "ip_prefix",
+                    Some(truncate(
+                        ip,
+                        self.config.ipv4_prefix,
+                        self.config.ipv6_prefix,
+                    )),
+                ),
+                DripKeyKind::UserAgent => ("user_agent", signals.user_agent.clone()),
+                DripKeyKind::Device => ("device", signals.device.clone()),
+                DripKeyKind::User => ("user", signals.user_id.clone()),
+                DripKeyKind::ApiKey => ("api_key", signals.api_key_id.clone()),
+            };
+            match value {
+                Some(value) => keys.extend(
+                    self.derive(domain, &value)
+                        .into_iter()
+                        .map(|key| DripKey { kind, key }),
+                ),
+                None => missing.push(kind),
+            }
+        }
+        if missing.is_empty() {
+            Ok(keys)
+        } else {
+            Err(missing)
+        }
+    }
+
+    /// Returns one key per configured secret for a signal value.
+    fn derive(&self, domain: &str, value: &str) -> Vec<String> {
+        self.config
+            .secrets
+            .iter()
+            .map(|secret| {
+                let mut mac = HmacSha256::new_from_slice(&secret.secret)
+                    .expect("hmac accepts any key length");
+                mac.update(domain.as_bytes());
+                mac.update(b":");
+                mac.update(value.as_bytes());
+                let hash = mac.finalize().into_bytes();
+                format!("v{}:{}", secret.version, hex::encode(&hash[..KEY_BYTES]))
+            })
+            .collect()
+    }
+}
+
+/// Truncates an IP address to a network prefix, in CIDR notation.
+fn truncate(ip: IpAddr, ipv4_bits: u8, ipv6_bits: u8) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - ipv4_bits as u32).unwrap_or(0);
+            format!("{}/{}", Ipv4Addr::from(u32::from(ip) & mask), ipv4_bits)
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - ipv6_bits as u32).unwrap_or(0);
+            format!("{}/{}", Ipv6Addr::from(u128::from(ip) & mask), ipv6_bits)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deriver(kinds: Vec<DripKeyKind>, secrets: &[&str]) -> KeyDeriver {
+        KeyDeriver::new(KeyConfig {
+            kinds,
+            secrets: secrets.iter().map(|s| s.parse().unwrap()).collect(),
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+        })
+        .unwrap()
+    }
+
+    fn keys(keys: Vec<DripKey>) -> Vec<String> {
+        keys.into_iter().map(|k| k.key).collect()
+    }
+
+    #[test]
+    fn derives_known_keys() {
+        let deriver = deriver(
+            vec![DripKeyKind::Address, DripKeyKind::Ip, DripKeyKind::IpPrefix],
+            &["1:first"],
+        );
+        let derived = deriver.drip_keys(
+            Address::repeat_byte(0x11),
+            [192, 0, 2, 1].into(),
+            &DripSignals::default(),
+        );
+        assert_eq!(
+            keys(derived.unwrap()),
+            vec![
+                "v1:eb3223bf7756a1eb91fb5ae54b7612a8",
+                "v1:7d6301d8a4bfd1744b7ee30e83d8a28e",
+                "v1:d5fa78deb7ac5dba106d19bdd06c0cb8",
+            ]
+        );
+    }
+
+    #[test]
+    fn derives_keys_for_every_secret() {
+        // During a rotation both the old and the new key are sent.
+        let deriver = deriver(vec![DripKeyKind::Address], &["1:first", "2:second"]);
+        let derived = deriver.drip_keys(
+            Address::repeat_byte(0x11),
+            [192, 0, 2, 1].into(),
+            &DripSignals::default(),
+        );
+        assert_eq!(
+            keys(derived.unwrap()),
+            vec![
+                "v1:eb3223bf7756a1eb91fb5ae54b7612a8",
+                "v2:fd6b20b7b5a3f8c4fcaca3645b4ad37c",
+            ]
+        );
+    }
+
+    #[test]
+    fn requires_configured_signals() {
+        let deriver = deriver(
+            vec![
+                DripKeyKind::Device,
+                DripKeyKind::UserAgent,
+                DripKeyKind::User,
+            ],
+            &["1:first"],
+        );
+        let address = Address::repeat_byte(0x11);
+        let ip: IpAddr = [192, 0, 2, 1].into();
+        let mut signals = DripSignals {
+            user_agent: Some("curl/8.0".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            deriver.drip_keys(address, ip, &signals).unwrap_err(),
+            vec![DripKeyKind::Device, DripKeyKind::User]
+        );
+
+        signals.device = Some("device".to_string());
+        signals.user_id = Some("user".to_string());
+        let derived = deriver.drip_keys(address, ip, &signals).unwrap();
+        let kinds: Vec<_> = derived.iter().map(|k| k.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                DripKeyKind::Device,
+                DripKeyKind::UserAgent,
+                DripKeyKind::User
+            ]
+        );
+    }
+
+    #[test]
+    fn truncates_ips() {
+        let v4: IpAddr = [192, 0, 2, 171].into();
+        let v6: IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        assert_eq!(truncate(v4, 32, 128), "192.0.2.171/32");
+        assert_eq!(truncate(v4, 24, 64), "192.0.2.0/24");
+        assert_eq!(truncate(v4, 20, 64), "192.0.0.0/20");
+        assert_eq!(truncate(v4, 0, 0), "0.0.0.0/0");
+        assert_eq!(truncate(v6, 32, 128), "2001:db8:1:2:3:4:5:6/128");
+        assert_eq!(truncate(v6, 24, 64), "2001:db8:1:2::/64");
+        assert_eq!(truncate(v6, 24, 0), "::/0");
+    }
+
+    #[test]
+    fn parses_secrets() {
+        let secret: KeySecret = "3:a:b".parse().unwrap();
+        assert_eq!(secret.version, 3);
+        assert_eq!(secret.secret, b"a:b");
+        assert_eq!(format!("{:?}", secret), "KeySecret(v3)");
+        for s in ["secret", "x:secret", "1:", "-1:secret"] {
+            assert!(s.parse::<KeySecret>().is_err(), "{}", s);
+        }
+    }
+}
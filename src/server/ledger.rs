@@ -0,0 +1,798 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context};
+use clap::ValueEnum;
+use ethers::prelude::{Address, TransactionReceipt, TxHash, U256};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use log::warn;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::server::lotus::MsgLookup;
+
+/// Embedded database used to persist the ledger.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LedgerBackend {
+    /// SQLite database file.
+    #[default]
+    Sqlite,
+    /// Sled database directory.
+    Sled,
+}
+
+/// Kind of request that produced a ledger entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestKind {
+    Register,
+    Drip,
+    /// No-op self-transfer sent by a signer wallet to fill a nonce gap.
+    NonceFill,
+}
+
+impl RequestKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RequestKind::Register => "register",
+            RequestKind::Drip => "drip",
+            RequestKind::NonceFill => "nonce_fill",
+        }
+    }
+
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "register" => Ok(RequestKind::Register),
+            "drip" => Ok(RequestKind::Drip),
+            "nonce_fill" => Ok(RequestKind::NonceFill),
+            _ => Err(anyhow!("unknown request kind: {}", s)),
+        }
+    }
+}
+
+/// Status of the transaction recorded in a ledger entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryStatus {
+    /// The transaction was broadcast but no receipt has been seen yet.
+    Pending,
+    /// The transaction was mined successfully.
+    Success,
+    /// The transaction was mined but reverted.
+    Reverted,
+    /// The transaction was never broadcast, see the entry error.
+    Failed,
+    /// The transaction was broadcast but the node no longer knows it or any replacement.
+    Dropped,
+}
+
+impl EntryStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EntryStatus::Pending => "pending",
+            EntryStatus::Success => "success",
+            EntryStatus::Reverted => "reverted",
+            EntryStatus::Failed => "failed",
+            EntryStatus::Dropped => "dropped",
+        }
+    }
+
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "pending" => Ok(EntryStatus::Pending),
+            "success" => Ok(EntryStatus::Success),
+            "reverted" => Ok(EntryStatus::Reverted),
+            "failed" => Ok(EntryStatus::Failed),
+            "dropped" => Ok(EntryStatus::Dropped),
+            _ => Err(anyhow!("unknown entry status: {}", s)),
+        }
+    }
+}
+
+/// A single registration or drip request as seen by the service.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// Ledger-assigned identifier. Zero until the entry has been inserted.
+    pub id: u64,
+    pub kind: RequestKind,
+    /// Unix timestamp (seconds) of the request.
+    pub requested_at: u64,
+    /// Target EVM address, zero for native registrations.
+    pub address: Address,
+    /// Target Filecoin address of a native registration.
+    #[serde(default)]
+    pub native_address: Option<String>,
+    pub client_ip: Option<IpAddr>,
+    /// Signer wallet that sent the transaction.
+    #[serde(default)]
+    pub signer: Option<Address>,
+    /// Hash of the latest (or mined) transaction for the request.
+    pub tx_hash: Option<TxHash>,
+    /// Hashes of earlier transactions for the request that were replaced with bumped fees.
+    #[serde(default)]
+    pub replaced_tx_hashes: Vec<TxHash>,
+    /// Position of the request in a batched transaction shared with other requests.
+    #[serde(default)]
+    pub batch_index: Option<u32>,
+    pub nonce: Option<U256>,
+    pub max_fee_per_gas: Option<U256>,
+    pub max_priority_fee_per_gas: Option<U256>,
+    pub status: EntryStatus,
+    pub block_number: Option<u64>,
+    pub gas_used: Option<U256>,
+    pub error: Option<String>,
+    /// CID of the message sent for a native registration.
+    #[serde(default)]
+    pub message_cid: Option<String>,
+}
+
+impl LedgerEntry {
+    /// Creates a new pending entry stamped with the current time.
+    pub fn new(kind: RequestKind, address: Address, client_ip: Option<IpAddr>) -> Self {
+        let requested_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self {
+            id: 0,
+            kind,
+            requested_at,
+            address,
+            native_address: None,
+            client_ip,
+            signer: None,
+            tx_hash: None,
+            replaced_tx_hashes: vec![],
+            batch_index: None,
+            nonce: None,
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: None,
+            status: EntryStatus::Pending,
+            block_number: None,
+            gas_used: None,
+            error: None,
+            message_cid: None,
+        }
+    }
+
+    /// Creates a new pending entry for a native registration of a Filecoin address.
+    pub fn native(native_address: String, client_ip: Option<IpAddr>) -> Self {
+        let mut entry = Self::new(RequestKind::Register, Address::zero(), client_ip);
+        entry.native_address = Some(native_address);
+        entry
+    }
+
+    /// Marks the entry as failed before broadcast.
+    pub fn fail(&mut self, error: impl ToString) {
+        self.status = EntryStatus::Failed;
+        self.error = Some(error.to_string());
+    }
+
+    /// Sets the current transaction hash, moving the previous one to the replacement chain.
+    pub fn set_tx_hash(&mut self, tx_hash: TxHash) {
+        if let Some(previous) = self.tx_hash.filter(|h| *h != tx_hash) {
+            if !self.replaced_tx_hashes.contains(&previous) {
+                self.replaced_tx_hashes.push(previous);
+            }
+        }
+        self.replaced_tx_hashes.retain(|h| *h != tx_hash);
+        self.tx_hash = Some(tx_hash);
+    }
+
+    /// Returns all transaction hashes sent for the request, latest first.
+    pub fn tx_hashes(&self) -> impl Iterator<Item = TxHash> + '_ {
+        self.tx_hash
+            .into_iter()
+            .chain(self.replaced_tx_hashes.iter().rev().copied())
+    }
+
+    /// Updates the entry from a mined transaction receipt.
+    pub fn apply_receipt(&mut self, receipt: &TransactionReceipt) {
+        self.set_tx_hash(receipt.transaction_hash);
+        self.status = if receipt.status == Some(1u64.into()) {
+            EntryStatus::Success
+        } else {
+            EntryStatus::Reverted
+        };
+        self.block_number = receipt.block_number.map(|n| n.as_u64());
+        self.gas_used = receipt.gas_used;
+    }
+
+    /// Updates the entry from the lookup of an included native message.
+    pub fn apply_message_lookup(&mut self, lookup: &MsgLookup) {
+        self.status = if lookup.receipt.exit_code == 0 {
+            EntryStatus::Success
+        } else {
+            EntryStatus::Reverted
+        };
+        self.block_number = Some(lookup.height as u64);
+        self.gas_used = Some(U256::from(lookup.receipt.gas_used));
+    }
+
+    /// Records the sender, nonce and fee values of a filled transaction.
+    pub fn record_tx(&mut self, tx: &TypedTransaction) {
+        self.signer = tx.from().copied();
+        self.nonce = tx.nonce().copied();
+        match tx {
+            TypedTransaction::Eip1559(tx) => {
+                self.max_fee_per_gas = tx.max_fee_per_gas;
+                self.max_priority_fee_per_gas = tx.max_priority_fee_per_gas;
+            }
+            _ => self.max_fee_per_gas = tx.gas_price(),
+        }
+    }
+}
+
+/// Storage backend for ledger entries.
+trait LedgerStore: Send + Sync {
+    fn insert(&self, entry: &LedgerEntry) -> anyhow::Result<u64>;
+    fn update(&self, entry: &LedgerEntry) -> anyhow::Result<()>;
+    fn find_by_tx_hash(&self, tx_hash: TxHash) -> anyhow::Result<Vec<LedgerEntry>>;
+    fn max_nonce(&self, signer: Address) -> anyhow::Result<Option<U256>>;
+    fn find_registration(&self, address: Address) -> anyhow::Result<Option<LedgerEntry>>;
+}
+
+/// Persistent record of every registration and drip handled by the service.
+///
+/// A disabled ledger accepts writes and drops them, so handlers don't need to care whether
+/// one is configured. Write failures are logged and never fail the request. Store calls block
+/// on disk I/O, so they run on the blocking thread pool rather than a runtime worker.
+#[derive(Clone, Default)]
+pub struct Ledger {
+    store: Option<Arc<dyn LedgerStore>>,
+}
+
+impl Ledger {
+    /// Opens (or creates) a ledger at `path` using the given backend.
+    pub fn open(backend: LedgerBackend, path: &Path) -> anyhow::Result<Self> {
+        let store: Arc<dyn LedgerStore> = match backend {
+            LedgerBackend::Sqlite => Arc::new(SqliteStore::open(path)?),
+            LedgerBackend::Sled => Arc::new(SledStore::open(path)?),
+        };
+        Ok(Self { store: Some(store) })
+    }
+
+    /// Returns a ledger that doesn't persist anything.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Runs a store call on the blocking thread pool. Returns `None` if the ledger is disabled.
+    async fn run<T, F>(&self, f: F) -> Option<anyhow::Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn LedgerStore) -> anyhow::Result<T> + Send + 'static,
+    {
+        let store = self.store.clone()?;
+        let res = tokio::task::spawn_blocking(move || f(store.as_ref()))
+            .await
+            .map_err(|e| anyhow!("ledger task failed: {}", e));
+        Some(res.and_then(|res| res))
+    }
+
+    /// Inserts a new entry, assigning its id.
+    pub async fn insert(&self, entry: &mut LedgerEntry) {
+        let new = entry.clone();
+        match self.run(move |store| store.insert(&new)).await {
+            Some(Ok(id)) => entry.id = id,
+            Some(Err(e)) => warn!("failed to insert ledger entry: {:#}", e),
+            None => {}
+        }
+    }
+
+    /// Persists the current state of a previously inserted entry.
+    pub async fn update(&self, entry: &LedgerEntry) {
+        let id = entry.id;
+        let entry = entry.clone();
+        if let Some(Err(e)) = self.run(move |store| store.update(&entry)).await {
+            warn!("failed to update ledger entry {}: {:#}", id, e);
+        }
+    }
+
+    /// Returns the entries of the requests a transaction hash was sent for, oldest first.
+    /// Batched transactions serve several requests.
+    pub async fn find_by_tx_hash(&self, tx_hash: TxHash) -> anyhow::Result<Vec<LedgerEntry>> {
+        self.run(move |store| store.find_by_tx_hash(tx_hash))
+            .await
+            .unwrap_or(Ok(vec![]))
+    }
+
+    /// Returns the highest nonce of any broadcast transaction sent by `signer`.
+    pub async fn max_nonce(&self, signer: Address) -> anyhow::Result<Option<U256>> {
+        self.run(move |store| store.max_nonce(signer))
+            .await
+            .unwrap_or(Ok(None))
+    }
+
+    /// Returns the most recent registration of `address` that was mined or is still pending.
+    pub async fn find_registration(&self, address: Address) -> anyhow::Result<Option<LedgerEntry>> {
+        self.run(move |store| store.find_registration(address))
+            .await
+            .unwrap_or(Ok(None))
+    }
+}
+
+/// SQLite-backed store.
+struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+const SQLITE_COLUMNS: &str = "id, kind, requested_at, address, client_ip, tx_hash, nonce, \
+    max_fee_per_gas, max_priority_fee_per_gas, status, block_number, gas_used, error, \
+    replaced_tx_hashes, signer, batch_index, native_address, message_cid";
+
+impl SqliteStore {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("failed to open sqlite ledger at {}", path.display()))?;
+        // Ledger writes don't need to survive a power loss, only a crash of the service, so
+        // commits aren't synced to disk one by one.
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS ledger (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                requested_at INTEGER NOT NULL,
+                address TEXT NOT NULL,
+                native_address TEXT,
+                client_ip TEXT,
+                signer TEXT,
+                tx_hash TEXT,
+                replaced_tx_hashes TEXT NOT NULL DEFAULT '',
+                batch_index INTEGER,
+                nonce TEXT,
+                max_fee_per_gas TEXT,
+                max_priority_fee_per_gas TEXT,
+                status TEXT NOT NULL,
+                block_number INTEGER,
+                gas_used TEXT,
+                error TEXT,
+                message_cid TEXT
+            );
+            CREATE INDEX IF NOT EXISTS ledger_address ON ledger (address);
+            CREATE TABLE IF NOT EXISTS ledger_tx_hashes (
+                tx_hash TEXT NOT NULL,
+                entry_id INTEGER NOT NULL,
+                PRIMARY KEY (tx_hash, entry_id)
+            );",
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Maps every transaction hash of an entry, including replaced ones, to its id.
+    fn index_tx_hashes(conn: &Connection, entry: &LedgerEntry) -> anyhow::Result<()> {
+        for tx_hash in entry.tx_hashes() {
+            conn.execute(
+                "INSERT OR IGNORE INTO ledger_tx_hashes (tx_hash, entry_id) VALUES (?1, ?2)",
+                params![format!("{:?}", tx_hash), entry.id as i64],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<anyhow::Result<LedgerEntry>> {
+        let id: i64 = row.get(0)?;
+        let kind: String = row.get(1)?;
+        let requested_at: i64 = row.get(2)?;
+        let address: String = row.get(3)?;
+        let client_ip: Option<String> = row.get(4)?;
+        let tx_hash: Option<String> = row.get(5)?;
+        let nonce: Option<String> = row.get(6)?;
+        let max_fee_per_gas: Option<String> = row.get(7)?;
+        let max_priority_fee_per_gas: Option<String> = row.get(8)?;
+        let status: String = row.get(9)?;
+        let block_number: Option<i64> = row.get(10)?;
+        let gas_used: Option<String> = row.get(11)?;
+        let error: Option<String> = row.get(12)?;
+        let replaced_tx_hashes: String = row.get(13)?;
+        let signer: Option<String> = row.get(14)?;
+        let batch_index: Option<i64> = row.get(15)?;
+        let native_address: Option<String> = row.get(16)?;
+        let message_cid: Option<String> = row.get(17)?;
+
+        let parse_u256 = |s: Option<String>| -> anyhow::Result<Option<U256>> {
+            s.map(|s| U256::from_dec_str(&s).map_err(|e| anyhow!("{}", e)))
+                .transpose()
+        };
+        let entry = || -> anyhow::Result<LedgerEntry> {
+            Ok(LedgerEntry {
+                id: id as u64,
+                kind: RequestKind::parse(&kind)?,
+                requested_at: requested_at as u64,
+                address: address.parse()?,
+                native_address,
+                client_ip: client_ip.map(|s| s.parse()).transpose()?,
+                signer: signer.map(|s| s.parse()).transpose()?,
+                tx_hash: tx_hash.map(|s| s.parse()).transpose()?,
+                replaced_tx_hashes: replaced_tx_hashes
+                    .split(',')
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse())
+                    .collect::<Result<_, _>>()?,
+                batch_index: batch_index.map(|i| i as u32),
+                nonce: parse_u256(nonce)?,
+                max_fee_per_gas: parse_u256(max_fee_per_gas)?,
+                max_priority_fee_per_gas: parse_u256(max_priority_fee_per_gas)?,
+                status: EntryStatus::parse(&status)?,
+                block_number: block_number.map(|n| n as u64),
+                gas_used: parse_u256(gas_used)?,
+                error,
+                message_cid,
+            })
+        };
+        Ok(entry())
+    }
+}
+
+impl LedgerStore for SqliteStore {
+    fn insert(&self, entry: &LedgerEntry) -> anyhow::Result<u64> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|_| anyhow!("ledger lock poisoned"))?;
+        conn.execute(
+            "INSERT INTO ledger (kind, requested_at, address, client_ip, tx_hash, nonce,
+                max_fee_per_gas, max_priority_fee_per_gas, status, block_number, gas_used, error,
+                replaced_tx_hashes, signer, batch_index, native_address, message_cid)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+            params![
+                entry.kind.as_str(),
+                entry.requested_at as i64,
+                format!("{:?}", entry.address),
+                entry.client_ip.map(|ip| ip.to_string()),
+                entry.tx_hash.map(|h| format!("{:?}", h)),
+                entry.nonce.map(|n| n.to_string()),
+                entry.max_fee_per_gas.map(|n| n.to_string()),
+                entry.max_priority_fee_per_gas.map(|n| n.to_string()),
+                entry.status.as_str(),
+                entry.block_number.map(|n| n as i64),
+                entry.gas_used.map(|n| n.to_string()),
+                entry.error,
+                join_hashes(&entry.replaced_tx_hashes),
+                entry.signer.map(|a| format!("{:?}", a)),
+                entry.batch_index.map(|i| i as i64),
+                entry.native_address,
+                entry.message_cid,
+            ],
+        )?;
+        let id = conn.last_insert_rowid() as u64;
+        let mut entry = entry.clone();
+        entry.id = id;
+        Self::index_tx_hashes(&conn, &entry)?;
+        Ok(id)
+    }
+
+    fn update(&self, entry: &LedgerEntry) -> anyhow::Result<()> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|_| anyhow!("ledger lock poisoned"))?;
+        conn.execute(
+            "UPDATE ledger SET tx_hash = ?2, nonce = ?3, max_fee_per_gas = ?4,
+                max_priority_fee_per_gas = ?5, status = ?6, block_number = ?7, gas_used = ?8,
+                error = ?9, replaced_tx_hashes = ?10, signer = ?11, batch_index = ?12,
+                message_cid = ?13
+             WHERE id = ?1",
+            params![
+                entry.id as i64,
+                entry.tx_hash.map(|h| format!("{:?}", h)),
+                entry.nonce.map(|n| n.to_string()),
+                entry.max_fee_per_gas.map(|n| n.to_string()),
+                entry.max_priority_fee_per_gas.map(|n| n.to_string()),
+                entry.status.as_str(),
+                entry.block_number.map(|n| n as i64),
+                entry.gas_used.map(|n| n.to_string()),
+                entry.error,
+                join_hashes(&entry.replaced_tx_hashes),
+                entry.signer.map(|a| format!("{:?}", a)),
+                entry.batch_index.map(|i| i as i64),
+                entry.message_cid,
+            ],
+        )?;
+        Self::index_tx_hashes(&conn, entry)?;
+        Ok(())
+    }
+
+    fn find_by_tx_hash(&self, tx_hash: TxHash) -> anyhow::Result<Vec<LedgerEntry>> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|_| anyhow!("ledger lock poisoned"))?;
+        let mut statement = conn.prepare(&format!(
+            "SELECT {SQLITE_COLUMNS} FROM ledger
+             WHERE id IN (SELECT entry_id FROM ledger_tx_hashes WHERE tx_hash = ?1)
+             ORDER BY id"
+        ))?;
+        let entries = statement
+            .query_map(params![format!("{:?}", tx_hash)], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.into_iter().collect()
+    }
+
+    fn max_nonce(&self, signer: Address) -> anyhow::Result<Option<U256>> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|_| anyhow!("ledger lock poisoned"))?;
+        let nonce: Option<i64> = conn.query_row(
+            "SELECT MAX(CAST(nonce AS INTEGER)) FROM ledger
+             WHERE signer = ?1 AND tx_hash IS NOT NULL",
+            params![format!("{:?}", signer)],
+            |row| row.get(0),
+        )?;
+        Ok(nonce.map(|n| U256::from(n as u64)))
+    }
+
+    fn find_registration(&self, address: Address) -> anyhow::Result<Option<LedgerEntry>> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|_| anyhow!("ledger lock poisoned"))?;
+        conn.query_row(
+            &format!(
+                "SELECT {SQLITE_COLUMNS} FROM ledger
+                 WHERE address = ?1 AND kind = 'register' AND tx_hash IS NOT NULL
+                    AND status IN ('pending', 'success')
+                 ORDER BY id DESC"
+            ),
+            params![format!("{:?}", address)],
+            Self::from_row,
+        )
+        .optional()?
+        .transpose()
+    }
+}
+
+/// Joins transaction hashes into a comma-separated column value.
+fn join_hashes(hashes: &[TxHash]) -> String {
+    hashes
+        .iter()
+        .map(|h| format!("{:?}", h))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Sled-backed store.
+///
+/// Entries are stored as JSON keyed by their big-endian id, with secondary trees mapping
+/// `address ++ id`, `signer ++ nonce ++ id` of broadcast transactions and
+/// `tx_hash ++ id` of every transaction hash (including replaced ones) back to the entry id.
+/// Sled flushes to disk in the background, so writes don't wait for a sync.
+struct SledStore {
+    db: sled::Db,
+    entries: sled::Tree,
+    by_address: sled::Tree,
+    by_signer_nonce: sled::Tree,
+    by_tx_hash: sled::Tree,
+}
+
+impl SledStore {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let db = sled::open(path)
+            .with_context(|| format!("failed to open sled ledger at {}", path.display()))?;
+        Ok(Self {
+            entries: db.open_tree("entries")?,
+            by_address: db.open_tree("by_address")?,
+            by_signer_nonce: db.open_tree("by_signer_nonce")?,
+            by_tx_hash: db.open_tree("by_tx_hash")?,
+            db,
+        })
+    }
+
+    fn index_signer_nonce(&self, entry: &LedgerEntry) -> anyhow::Result<()> {
+        if let (Some(signer), Some(nonce), Some(_)) = (entry.signer, entry.nonce, entry.tx_hash) {
+            let id = entry.id.to_be_bytes();
+            let mut key = signer.as_bytes().to_vec();
+            let mut nonce_bytes = [0u8; 32];
+            nonce.to_big_endian(&mut nonce_bytes);
+            key.extend_from_slice(&nonce_bytes);
+            key.extend_from_slice(&id);
+            self.by_signer_nonce.insert(key, &id)?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, id: &[u8]) -> anyhow::Result<Option<LedgerEntry>> {
+        self.entries
+            .get(id)?
+            .map(|v| serde_json::from_slice(&v).map_err(Into::into))
+            .transpose()
+    }
+
+    fn write(&self, entry: &LedgerEntry) -> anyhow::Result<()> {
+        let id = entry.id.to_be_bytes();
+        let mut address_key = entry.address.as_bytes().to_vec();
+        address_key.extend_from_slice(&id);
+        self.entries.insert(id, serde_json::to_vec(entry)?)?;
+        self.by_address.insert(address_key, &id)?;
+        for tx_hash in entry.tx_hashes() {
+            let mut tx_hash_key = tx_hash.as_bytes().to_vec();
+            tx_hash_key.extend_from_slice(&id);
+            self.by_tx_hash.insert(tx_hash_key, &id)?;
+        }
+        self.index_signer_nonce(entry)?;
+        Ok(())
+    }
+}
+
+impl LedgerStore for SledStore {
+    fn insert(&self, entry: &LedgerEntry) -> anyhow::Result<u64> {
+        // Sled ids start at zero; shift by one so zero keeps meaning "not inserted".
+        let id = self.db.generate_id()? + 1;
+        let mut entry = entry.clone();
+        entry.id = id;
+        self.write(&entry)?;
+        Ok(id)
+    }
+
+    fn update(&self, entry: &LedgerEntry) -> anyhow::Result<()> {
+        self.write(entry)
+    }
+
+    fn find_by_tx_hash(&self, tx_hash: TxHash) -> anyhow::Result<Vec<LedgerEntry>> {
+        let mut entries = vec![];
+        for item in self.by_tx_hash.scan_prefix(tx_hash.as_bytes()) {
+            let (_, id) = item?;
+            entries.extend(self.get(&id)?);
+        }
+        Ok(entries)
+    }
+
+    fn max_nonce(&self, signer: Address) -> anyhow::Result<Option<U256>> {
+        // Keys sort by nonce within a signer, so the last one holds the highest nonce.
+        let Some(item) = self
+            .by_signer_nonce
+            .scan_prefix(signer.as_bytes())
+            .next_back()
+        else {
+            return Ok(None);
+        };
+        let (key, _) = item?;
+        let nonce = key
+            .get(Address::len_bytes()..Address::len_bytes() + 32)
+            .ok_or_else(|| anyhow!("invalid signer nonce key"))?;
+        Ok(Some(U256::from_big_endian(nonce)))
+    }
+
+    fn find_registration(&self, address: Address) -> anyhow::Result<Option<LedgerEntry>> {
+        for item in self.by_address.scan_prefix(address.as_bytes()).rev() {
+            let (_, id) = item?;
+            if let Some(entry) = self.get(&id)? {
+                if entry.kind == RequestKind::Register
+                    && entry.tx_hash.is_some()
+                    && matches!(entry.status, EntryStatus::Pending | EntryStatus::Success)
+                {
+                    return Ok(Some(entry));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use ethers::types::Eip1559TransactionRequest;
+
+    use super::*;
+
+    static NEXT_LEDGER: AtomicUsize = AtomicUsize::new(0);
+
+    fn open(backend: LedgerBackend) -> Ledger {
+        let path = std::env::temp_dir().join(format!(
+            "ledger-{}-{}",
+            std::process::id(),
+            NEXT_LEDGER.fetch_add(1, Ordering::Relaxed)
+        ));
+        Ledger::open(backend, &path).unwrap()
+    }
+
+    async fn sent_entry(
+        ledger: &Ledger,
+        address: Address,
+        signer: Address,
+        nonce: u64,
+    ) -> LedgerEntry {
+        let mut entry = LedgerEntry::new(RequestKind::Register, address, None);
+        ledger.insert(&mut entry).await;
+        let tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .from(signer)
+            .nonce(nonce)
+            .max_fee_per_gas(2)
+            .max_priority_fee_per_gas(1)
+            .into();
+        entry.record_tx(&tx);
+        entry.set_tx_hash(TxHash::from_low_u64_be(nonce + 1));
+        ledger.update(&entry).await;
+        entry
+    }
+
+    async fn round_trip(backend: LedgerBackend) {
+        let ledger = open(backend);
+        let address = Address::repeat_byte(0xaa);
+        let signer = Address::repeat_byte(0x51);
+
+        let mut entry = sent_entry(&ledger, address, signer, 7).await;
+        assert!(entry.id > 0);
+        entry.set_tx_hash(TxHash::from_low_u64_be(100));
+        ledger.update(&entry).await;
+        sent_entry(&ledger, Address::repeat_byte(0xbb), signer, 3).await;
+
+        // Both the replaced and the latest hash find the entry, and only exact hashes match.
+        for hash in [TxHash::from_low_u64_be(8), TxHash::from_low_u64_be(100)] {
+            let found = ledger.find_by_tx_hash(hash).await.unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].id, entry.id);
+            assert_eq!(found[0].tx_hash, Some(TxHash::from_low_u64_be(100)));
+            assert_eq!(
+                found[0].replaced_tx_hashes,
+                vec![TxHash::from_low_u64_be(8)]
+            );
+            assert_eq!(found[0].max_fee_per_gas, Some(U256::from(2)));
+        }
+        assert!(ledger
+            .find_by_tx_hash(TxHash::from_low_u64_be(9))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Every request served by a batched transaction is found.
+        let batch_hash = TxHash::from_low_u64_be(200);
+        let mut batch = vec![];
+        for index in 0..3 {
+            let mut member = LedgerEntry::new(RequestKind::Drip, Address::repeat_byte(index), None);
+            ledger.insert(&mut member).await;
+            member.batch_index = Some(index as u32);
+            member.set_tx_hash(batch_hash);
+            ledger.update(&member).await;
+            batch.push(member.id);
+        }
+        let found = ledger.find_by_tx_hash(batch_hash).await.unwrap();
+        assert_eq!(found.iter().map(|e| e.id).collect::<Vec<_>>(), batch);
+
+        assert_eq!(ledger.max_nonce(signer).await.unwrap(), Some(U256::from(7)));
+        assert_eq!(ledger.max_nonce(address).await.unwrap(), None);
+
+        let found = ledger.find_registration(address).await.unwrap().unwrap();
+        assert_eq!(found.id, entry.id);
+        entry.status = EntryStatus::Reverted;
+        ledger.update(&entry).await;
+        assert!(ledger.find_registration(address).await.unwrap().is_none());
+
+        let mut native = LedgerEntry::native("f1abc".to_string(), None);
+        ledger.insert(&mut native).await;
+        native.message_cid = Some("bafy".to_string());
+        ledger.update(&native).await;
+        assert!(native.id > entry.id);
+    }
+
+    #[tokio::test]
+    async fn sqlite_round_trip() {
+        round_trip(LedgerBackend::Sqlite).await;
+    }
+
+    #[tokio::test]
+    async fn sled_round_trip() {
+        round_trip(LedgerBackend::Sled).await;
+    }
+
+    #[tokio::test]
+    async fn disabled_ledger_drops_writes() {
+        let ledger = Ledger::disabled();
+        let mut entry = LedgerEntry::new(RequestKind::Drip, Address::zero(), None);
+        ledger.insert(&mut entry).await;
+        assert_eq!(entry.id, 0);
+        assert!(ledger
+            .find_by_tx_hash(TxHash::zero())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,490 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use data_encoding::BASE64;
+use ethers::core::k256::ecdsa::SigningKey;
+use ethers::prelude::U256;
+use lazy_static::lazy_static;
+use log::warn;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::server::address::{AddressKind, TargetAddress, BASE32};
+
+/// Timeout for Lotus API calls other than waiting for a message.
+const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Timeout for waiting for a message to be included in a block.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Secp256k1 signature type in Lotus signed messages.
+const SIG_TYPE_SECP256K1: u8 = 1;
+
+lazy_static! {
+    static ref NATIVE_MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "native_messages_total",
+        "Number of native FVM messages sent through the Lotus API, by result.",
+        &["result"]
+    )
+    .unwrap();
+}
+
+/// Lotus-compatible JSON-RPC endpoint settings.
+#[derive(Clone, Debug)]
+pub struct LotusConfig {
+    /// JSON-RPC URL, e.g. `http://127.0.0.1:1234/rpc/v1`.
+    pub url: String,
+    /// Optional API token sent as a bearer token.
+    pub token: Option<String>,
+}
+
+/// Unsigned FVM message in the Lotus JSON format.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Message {
+    pub version: u64,
+    pub to: String,
+    pub from: String,
+    pub nonce: u64,
+    pub value: String,
+    pub gas_limit: i64,
+    pub gas_fee_cap: String,
+    pub gas_premium: String,
+    pub method: u64,
+    /// Base64 encoded parameters.
+    pub params: Option<String>,
+}
+
+impl Message {
+    /// Returns the CID of the DAG-CBOR encoded message, which is what gets signed.
+    pub fn cid(&self) -> anyhow::Result<Vec<u8>> {
+        let mut cbor = vec![];
+        cbor_header(&mut cbor, 4, 10);
+        cbor_header(&mut cbor, 0, self.version);
+        cbor_bytes(&mut cbor, &TargetAddress::parse(&self.to)?.to_bytes());
+        cbor_bytes(&mut cbor, &TargetAddress::parse(&self.from)?.to_bytes());
+        cbor_header(&mut cbor, 0, self.nonce);
+        cbor_bytes(&mut cbor, &bigint_bytes(&self.value)?);
+        if self.gas_limit < 0 {
+            bail!("negative gas limit");
+        }
+        cbor_header(&mut cbor, 0, self.gas_limit as u64);
+        cbor_bytes(&mut cbor, &bigint_bytes(&self.gas_fee_cap)?);
+        cbor_bytes(&mut cbor, &bigint_bytes(&self.gas_premium)?);
+        cbor_header(&mut cbor, 0, self.method);
+        let params = match &self.params {
+            Some(params) => BASE64.decode(params.as_bytes())?,
+            None => vec![],
+        };
+        cbor_bytes(&mut cbor, &params);
+
+        // CIDv1, DAG-CBOR codec, blake2b-256 multihash.
+        let mut cid = vec![0x01, 0x71, 0xa0, 0xe4, 0x02, 0x20];
+        cid.extend_from_slice(blake2b(&cbor, 32).as_slice());
+        Ok(cid)
+    }
+}
+
+/// Outcome of an included message.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MsgLookup {
+    pub receipt: MsgReceipt,
+    pub height: i64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MsgReceipt {
+    pub exit_code: i64,
+    pub gas_used: i64,
+}
+
+/// Sends native FVM messages, signed by a secp256k1 key, through a Lotus-compatible API.
+///
+/// Used to create accounts for f1 and f3 addresses, which EVM transactions can't reach: a
+/// zero-value `Send` to an unknown key address makes the FVM create its account actor. The
+/// messages are paid for by the key's own f1 address, which must be funded separately from
+/// its EVM address.
+pub struct NativeSender {
+    http: reqwest::Client,
+    config: LotusConfig,
+    key: SigningKey,
+    from: TargetAddress,
+    /// Serializes nonce assignment and pushes.
+    send_lock: tokio::sync::Mutex<()>,
+    request_id: AtomicU64,
+}
+
+impl NativeSender {
+    pub fn new(config: LotusConfig, key: SigningKey) -> Self {
+        let public_key = key.verifying_key().to_encoded_point(false);
+        let from = TargetAddress::Native {
+            kind: AddressKind::Secp256k1,
+            payload: blake2b(public_key.as_bytes(), 20),
+        };
+        Self {
+            http: reqwest::Client::new(),
+            config,
+            key,
+            from,
+            send_lock: tokio::sync::Mutex::new(()),
+            request_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Returns the f1 address messages are sent from.
+    pub fn address(&self) -> String {
+        self.from.to_filecoin('f')
+    }
+
+    /// Returns the actor ID of an address, or `None` if no actor exists for it yet.
+    pub async fn lookup_id(&self, address: &TargetAddress) -> anyhow::Result<Option<u64>> {
+        let params = json!([address.to_filecoin('f'), []]);
+        match self
+            .call::<String>("Filecoin.StateLookupID", params, CALL_TIMEOUT)
+            .await
+        {
+            Ok(id) => match TargetAddress::parse(&id)? {
+                TargetAddress::Id(id) => Ok(Some(id)),
+                other => Err(anyhow!("unexpected id address {:?}", other)),
+            },
+            Err(e) if e.to_string().contains("not found") => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends a zero-value `Send` message to an address and returns the message CID and its gas
+    /// fee cap, in attoFIL.
+    pub async fn send(&self, to: &TargetAddress) -> anyhow::Result<(String, U256)> {
+        let res = self.push_send(to).await;
+        NATIVE_MESSAGES
+            .with_label_values(&[if res.is_ok() { "pushed" } else { "failed" }])
+            .inc();
+        res
+    }
+
+    async fn push_send(&self, to: &TargetAddress) -> anyhow::Result<(String, U256)> {
+        let _guard = self.send_lock.lock().await;
+        let from = self.address();
+        let nonce: u64 = self
+            .call("Filecoin.MpoolGetNonce", json!([from]), CALL_TIMEOUT)
+            .await?;
+        let message = Message {
+            version: 0,
+            to: to.to_filecoin('f'),
+            from,
+            nonce,
+            value: "0".to_string(),
+            gas_limit: 0,
+            gas_fee_cap: "0".to_string(),
+            gas_premium: "0".to_string(),
+            method: 0,
+            params: None,
+        };
+        let message: Message = self
+            .call(
+                "Filecoin.GasEstimateMessageGas",
+                json!([message, {"MaxFee": "0"}, []]),
+                CALL_TIMEOUT,
+            )
+            .await
+            .context("failed to estimate message gas")?;
+        let fee_cap = U256::from_dec_str(&message.gas_fee_cap)
+            .map_err(|e| anyhow!("invalid gas fee cap: {}", e))?;
+
+        let (cid, signature) = self.sign(&message)?;
+        let signed = json!({
+            "Message": message,
+            "Signature": {"Type": SIG_TYPE_SECP256K1, "Data": BASE64.encode(&signature)},
+        });
+        let pushed: Value = self
+            .call("Filecoin.MpoolPush", json!([signed]), CALL_TIMEOUT)
+            .await?;
+        let cid = format_cid(&cid);
+        if pushed["/"].as_str() != Some(cid.as_str()) {
+            warn!(
+                "lotus returned message cid {} for message {}",
+                pushed["/"], cid
+            );
+        }
+        Ok((cid, fee_cap))
+    }
+
+    /// Signs a message: a recoverable secp256k1 signature over the blake2b-256 hash of the
+    /// message CID. Returns the CID and the 65-byte signature.
+    fn sign(&self, message: &Message) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let cid = message.cid()?;
+        let (signature, recovery_id) = self
+            .key
+            .sign_prehash_recoverable(blake2b(&cid, 32).as_slice())
+            .map_err(|e| anyhow!("failed to sign message: {}", e))?;
+        let mut signature = signature.to_bytes().to_vec();
+        signature.push(recovery_id.to_byte());
+        Ok((cid, signature))
+    }
+
+    /// Waits until a message is included in a block and returns its receipt.
+    pub async fn wait(&self, cid: &str) -> anyhow::Result<MsgLookup> {
+        self.call(
+            "Filecoin.StateWaitMsg",
+            json!([{"/": cid}, 1, -1, true]),
+            WAIT_TIMEOUT,
+        )
+        .await
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+        timeout: Duration,
+    ) -> anyhow::Result<T> {
+        let mut request = self
+            .http
+            .post(&self.config.url)
+            .timeout(timeout)
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "method": method,
+                "params": params,
+                "id": self.request_id.fetch_add(1, Ordering::Relaxed),
+            }));
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+        let mut res: Value = request
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("invalid {} response", method))?;
+        if let Some(error) = res.get("error") {
+            bail!(
+                "{} failed: {}",
+                method,
+                error["message"].as_str().unwrap_or("unknown error")
+            );
+        }
+        serde_json::from_value(res["result"].take())
+            .with_context(|| format!("invalid {} result", method))
+    }
+}
+
+/// Encodes a CBOR header with the given major type and argument.
+fn cbor_header(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    match n {
+        0..=23 => out.push(major | n as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, n as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+fn cbor_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    cbor_header(out, 2, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Encodes a non-negative decimal token amount as a Filecoin big integer: empty for zero,
+/// otherwise a zero sign byte followed by the big-endian magnitude.
+fn bigint_bytes(amount: &str) -> anyhow::Result<Vec<u8>> {
+    let amount = U256::from_dec_str(amount).map_err(|e| anyhow!("invalid amount: {}", e))?;
+    if amount.is_zero() {
+        return Ok(vec![]);
+    }
+    let mut be = [0u8; 32];
+    amount.to_big_endian(&mut be);
+    let start = be.iter().position(|b| *b != 0).unwrap_or(be.len());
+    let mut bytes = vec![0];
+    bytes.extend_from_slice(&be[start..]);
+    Ok(bytes)
+}
+
+/// Formats a CID in its multibase base32 string form.
+fn format_cid(cid: &[u8]) -> String {
+    format!("b{}", BASE32.encode(cid))
+}
+
+fn blake2b(data: &[u8], len: usize) -> Vec<u8> {
+    blake2b_simd::Params::new()
+        .hash_length(len)
+        .hash(data)
+        .as_bytes()
+        .to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use ethers::core::k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+    use warp::Filter;
+
+    use super::*;
+
+    /// Serves the Lotus methods used by `NativeSender`. Pushed messages are only accepted if
+    /// their signature recovers to the sender address, and their CIDs are recorded.
+    async fn mock_lotus(known: String, pushed: Arc<Mutex<Vec<String>>>) -> String {
+        let route = warp::post()
+            .and(warp::header::exact("authorization", "Bearer secret"))
+            .and(warp::body::json())
+            .map(move |req: Value| {
+                let params = &req["params"];
+                let result = match req["method"].as_str().unwrap() {
+                    "Filecoin.StateLookupID" if params[0] == known.as_str() => Ok(json!("f01001")),
+                    "Filecoin.StateLookupID" => Err("resolution lookup failed: actor not found"),
+                    "Filecoin.MpoolGetNonce" => Ok(json!(7)),
+                    "Filecoin.GasEstimateMessageGas" => {
+                        let mut message = params[0].clone();
+                        message["GasLimit"] = json!(1_526_221);
+                        message["GasFeeCap"] = json!("100954");
+                        message["GasPremium"] = json!("99900");
+                        Ok(message)
+                    }
+                    "Filecoin.MpoolPush" => {
+                        let message: Message =
+                            serde_json::from_value(params[0]["Message"].clone()).unwrap();
+                        let data = BASE64
+                            .decode(params[0]["Signature"]["Data"].as_str().unwrap().as_bytes())
+                            .unwrap();
+                        let cid = message.cid().unwrap();
+                        let key = VerifyingKey::recover_from_prehash(
+                            &blake2b(&cid, 32),
+                            &Signature::from_slice(&data[..64]).unwrap(),
+                            RecoveryId::from_byte(data[64]).unwrap(),
+                        )
+                        .unwrap();
+                        let signer = TargetAddress::Native {
+                            kind: AddressKind::Secp256k1,
+                            payload: blake2b(key.to_encoded_point(false).as_bytes(), 20),
+                        };
+                        if signer.to_filecoin('f') == message.from && message.nonce == 7 {
+                            pushed.lock().unwrap().push(format_cid(&cid));
+                            Ok(json!({"/": format_cid(&cid)}))
+                        } else {
+                            Err("invalid signature")
+                        }
+                    }
+                    "Filecoin.StateWaitMsg" => Ok(json!({
+                        "Message": params[0],
+                        "Receipt": {"ExitCode": 0, "Return": null, "GasUsed": 1_222_122},
+                        "Height": 1234,
+                    })),
+                    _ => Err("method not found"),
+                };
+                warp::reply::json(&match result {
+                    Ok(result) => json!({"jsonrpc": "2.0", "id": req["id"], "result": result}),
+                    Err(message) => json!({
+                        "jsonrpc": "2.0",
+                        "id": req["id"],
+                        "error": {"code": 1, "message": message},
+                    }),
+                })
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}/rpc/v1", addr)
+    }
+
+    #[tokio::test]
+    async fn registers_native_address() {
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let from = NativeSender::new(
+            LotusConfig {
+                url: String::new(),
+                token: None,
+            },
+            key.clone(),
+        )
+        .address();
+        let pushed = Arc::new(Mutex::new(vec![]));
+        let url = mock_lotus(from.clone(), pushed.clone()).await;
+        let sender = NativeSender::new(
+            LotusConfig {
+                url,
+                token: Some("secret".to_string()),
+            },
+            key,
+        );
+
+        let sender_address = TargetAddress::parse(&from).unwrap();
+        assert_eq!(sender.lookup_id(&sender_address).await.unwrap(), Some(1001));
+        let to = TargetAddress::parse("f1abjxfbp274xpdqcpuaykwkfb43omjotacm2p3za").unwrap();
+        assert_eq!(sender.lookup_id(&to).await.unwrap(), None);
+
+        let (cid, fee_cap) = sender.send(&to).await.unwrap();
+        assert_eq!(fee_cap, U256::from(100954));
+        assert!(cid.starts_with("bafy2bzace"), "{}", cid);
+        assert_eq!(*pushed.lock().unwrap(), vec![cid.clone()]);
+
+        let lookup = sender.wait(&cid).await.unwrap();
+        assert_eq!(lookup.receipt.exit_code, 0);
+        assert_eq!(lookup.height, 1234);
+    }
+
+    /// The expected CID and signature were computed with an implementation independent of this
+    /// one: DAG-CBOR and blake2b-256 for the CID, RFC 6979 secp256k1 for the signature.
+    #[test]
+    fn signs_known_message() {
+        let sender = NativeSender::new(
+            LotusConfig {
+                url: String::new(),
+                token: None,
+            },
+            SigningKey::from_slice(&[7u8; 32]).unwrap(),
+        );
+        assert_eq!(
+            sender.address(),
+            "f1ick6svk3i23qdgn74hz2yu4wsoryzuaw4h3jk4y"
+        );
+        let message = Message {
+            version: 0,
+            to: "f1abjxfbp274xpdqcpuaykwkfb43omjotacm2p3za".to_string(),
+            from: sender.address(),
+            nonce: 7,
+            value: "0".to_string(),
+            gas_limit: 1_526_221,
+            gas_fee_cap: "100954".to_string(),
+            gas_premium: "99900".to_string(),
+            method: 0,
+            params: None,
+        };
+        let (cid, signature) = sender.sign(&message).unwrap();
+        assert_eq!(
+            format_cid(&cid),
+            "bafy2bzacecagqzo4face3tevpm4uwak4hfxiwvlbpbzmuxrzh4sadruuleac4"
+        );
+        assert_eq!(
+            BASE64.encode(&signature),
+            concat!(
+                "WFmpMWdmGni+hKTLZkd9fM8ItaO5E7QrQ2GA00S321Mw318Ga7QgATzGK0Undyfhgfuu3+m/",
+                "CEpJ0qGHjbXqVgA="
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_unauthorized_calls() {
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let url = mock_lotus(String::new(), Arc::default()).await;
+        let sender = NativeSender::new(LotusConfig { url, token: None }, key);
+        let to = TargetAddress::parse("f1abjxfbp274xpdqcpuaykwkfb43omjotacm2p3za").unwrap();
+        assert!(sender.send(&to).await.is_err());
+    }
+}
@@ -0,0 +1,335 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use ethers::prelude::{Middleware, TransactionReceipt, U256};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use tokio::sync::oneshot;
+use tokio::time::{sleep, Instant};
+
+use crate::server::budget::SpendingBudget;
+use crate::server::ledger::{EntryStatus, Ledger, LedgerEntry};
+use crate::server::pool::SignerLease;
+
+/// Interval between receipt checks for in-flight transactions.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Minimum fee increase, in percent, nodes accept for a replacement transaction.
+const MIN_FEE_BUMP_PERCENT: u64 = 10;
+
+lazy_static! {
+    static ref INFLIGHT_TRANSACTIONS: IntGauge = register_int_gauge!(
+        "monitor_inflight_transactions",
+        "Number of sent transactions waiting for a receipt."
+    )
+    .unwrap();
+    static ref REPLACED_TRANSACTIONS: IntCounter = register_int_counter!(
+        "monitor_replaced_transactions_total",
+        "Number of stuck transactions rebroadcast with bumped fees."
+    )
+    .unwrap();
+}
+
+/// Stuck transaction handling settings.
+#[derive(Clone, Debug)]
+pub struct MonitorConfig {
+    /// Time a transaction may stay unmined before it's replaced.
+    pub stuck_timeout: Duration,
+    /// Fee increase, in percent, applied to each replacement.
+    pub fee_bump_percent: u64,
+    /// Maximum `max_fee_per_gas` (or legacy gas price) a replacement may use.
+    pub fee_ceiling: U256,
+}
+
+/// Background monitor for transactions sent by the signer pool.
+///
+/// Every broadcast transaction is watched until one transaction of its replacement chain is
+/// mined. Transactions that stay unmined for longer than the stuck timeout are rebroadcast
+/// with the same nonce and bumped fees, up to the fee ceiling. Transactions and the gas fees of
+/// their receipts are counted against the spending budget.
+#[derive(Clone)]
+pub struct TxMonitor {
+    config: Arc<MonitorConfig>,
+    ledger: Ledger,
+    budget: Arc<SpendingBudget>,
+}
+
+impl TxMonitor {
+    /// Creates a new monitor recording outcomes to the given ledger.
+    pub fn new(config: MonitorConfig, ledger: Ledger, budget: Arc<SpendingBudget>) -> Self {
+        Self {
+            config: Arc::new(config),
+            ledger,
+            budget,
+        }
+    }
+
+    /// Returns the ledger transaction outcomes are recorded to.
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// Returns the budget sent transactions are counted against.
+    pub fn budget(&self) -> &SpendingBudget {
+        &self.budget
+    }
+
+    /// Starts watching a broadcast transaction.
+    ///
+    /// `entries` are the ledger entries of all requests served by the transaction, more than one
+    /// for batched transactions. The signer stays leased until the transaction is resolved. The
+    /// returned channel yields the receipt of whichever transaction in the replacement chain gets
+    /// mined, or an error once the transaction is stuck at the fee ceiling.
+    pub fn watch(
+        &self,
+        signer: SignerLease,
+        tx: TypedTransaction,
+        entries: Vec<LedgerEntry>,
+    ) -> oneshot::Receiver<anyhow::Result<TransactionReceipt>> {
+        let (sender, receiver) = oneshot::channel();
+        let monitor = self.clone();
+        INFLIGHT_TRANSACTIONS.inc();
+        tokio::spawn(async move {
+            monitor.budget.record_tx().await;
+            for entry in &entries {
+                monitor.ledger.update(entry).await;
+            }
+            monitor.run(signer, tx, entries, sender).await;
+            INFLIGHT_TRANSACTIONS.dec();
+        });
+        receiver
+    }
+
+    async fn run(
+        &self,
+        signer: SignerLease,
+        mut tx: TypedTransaction,
+        mut entries: Vec<LedgerEntry>,
+        sender: oneshot::Sender<anyhow::Result<TransactionReceipt>>,
+    ) {
+        // All entries share the same replacement chain; the first one tracks it.
+        let Some(mut entry) = entries.first().cloned() else {
+            return;
+        };
+        let mut sender = Some(sender);
+        let mut last_sent = Instant::now();
+        loop {
+            sleep(POLL_INTERVAL).await;
+
+            match find_receipt(&signer, &entry).await {
+                Ok(Some(receipt)) => {
+                    self.budget.record_receipt(&receipt, &tx).await;
+                    self.update_all(&mut entries, |e| e.apply_receipt(&receipt))
+                        .await;
+                    if let Some(sender) = sender.take() {
+                        let _ = sender.send(Ok(receipt));
+                    }
+                    return;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("failed to check receipts for {:?}: {}", entry.tx_hash, e);
+                    continue;
+                }
+            }
+
+            if last_sent.elapsed() < self.config.stuck_timeout {
+                continue;
+            }
+            last_sent = Instant::now();
+
+            let Some(bumped) =
+                bump_fees(&tx, self.config.fee_bump_percent, self.config.fee_ceiling)
+            else {
+                if let Some(sender) = sender.take() {
+                    let _ = sender.send(Err(anyhow!(
+                        "transaction {:?} is stuck at the fee ceiling",
+                        entry.tx_hash.unwrap_or_default()
+                    )));
+                }
+                // Keep watching while the node still knows the transaction; it may get mined
+                // once fees come down.
+                match is_known(&signer, &entry).await {
+                    Ok(false) => {
+                        warn!("transaction {:?} was dropped", entry.tx_hash);
+                        self.update_all(&mut entries, |e| {
+                            e.status = EntryStatus::Dropped;
+                            e.error = Some("transaction dropped".to_string());
+                        })
+                        .await;
+                        return;
+                    }
+                    Ok(true) => {}
+                    Err(e) => warn!("failed to look up {:?}: {}", entry.tx_hash, e),
+                }
+                continue;
+            };
+
+            match signer.client.send_transaction(bumped.clone(), None).await {
+                Ok(pending) => {
+                    let hash = pending.tx_hash();
+                    info!(
+                        "replaced stuck transaction {:?} with {:?}",
+                        entry.tx_hash.unwrap_or_default(),
+                        hash
+                    );
+                    REPLACED_TRANSACTIONS.inc();
+                    self.budget.record_tx().await;
+                    entry.set_tx_hash(hash);
+                    self.update_all(&mut entries, |e| {
+                        e.set_tx_hash(hash);
+                        e.record_tx(&bumped);
+                    })
+                    .await;
+                    tx = bumped;
+                }
+                // The original may have been mined in the meantime, which the next receipt check
+                // will pick up.
+                Err(e) => warn!(
+                    "failed to replace stuck transaction {:?}: {}",
+                    entry.tx_hash, e
+                ),
+            }
+        }
+    }
+
+    /// Applies a change to every entry and persists it.
+    async fn update_all(&self, entries: &mut [LedgerEntry], f: impl Fn(&mut LedgerEntry)) {
+        for entry in entries {
+            f(entry);
+            self.ledger.update(entry).await;
+        }
+    }
+}
+
+/// Returns the receipt of any transaction in the entry's replacement chain.
+async fn find_receipt(
+    signer: &SignerLease,
+    entry: &LedgerEntry,
+) -> anyhow::Result<Option<TransactionReceipt>> {
+    let provider = signer.client.provider();
+    for hash in entry.tx_hashes() {
+        if let Some(receipt) = provider.get_transaction_receipt(hash).await? {
+            return Ok(Some(receipt));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns whether the node knows any transaction in the entry's replacement chain.
+async fn is_known(signer: &SignerLease, entry: &LedgerEntry) -> anyhow::Result<bool> {
+    let provider = signer.client.provider();
+    for hash in entry.tx_hashes() {
+        if provider.get_transaction(hash).await?.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns a copy of `tx` with fees bumped by `percent`, or `None` if the minimum replacement
+/// bump would exceed `ceiling` or overflow.
+fn bump_fees(tx: &TypedTransaction, percent: u64, ceiling: U256) -> Option<TypedTransaction> {
+    let percent = percent.max(MIN_FEE_BUMP_PERCENT);
+    let bump = |fee: U256, percent: u64| {
+        fee.checked_mul(U256::from(100u64.saturating_add(percent)))
+            .map(|fee| fee / 100 + 1)
+    };
+    // Full bumps that overflow are capped at the ceiling like any other.
+    let full_bump = |fee: U256| bump(fee, percent).unwrap_or(U256::MAX).min(ceiling);
+
+    let mut bumped = tx.clone();
+    match &mut bumped {
+        TypedTransaction::Eip1559(inner) => {
+            let max_fee = inner.max_fee_per_gas?;
+            let priority_fee = inner.max_priority_fee_per_gas.unwrap_or_default();
+            if bump(max_fee, MIN_FEE_BUMP_PERCENT)? > ceiling {
+                return None;
+            }
+            let new_max_fee = full_bump(max_fee);
+            let new_priority_fee = full_bump(priority_fee).min(new_max_fee);
+            if new_priority_fee < bump(priority_fee, MIN_FEE_BUMP_PERCENT)? {
+                return None;
+            }
+            inner.max_fee_per_gas = Some(new_max_fee);
+            inner.max_priority_fee_per_gas = Some(new_priority_fee);
+        }
+        _ => {
+            let gas_price = tx.gas_price()?;
+            if bump(gas_price, MIN_FEE_BUMP_PERCENT)? > ceiling {
+                return None;
+            }
+            bumped.set_gas_price(full_bump(gas_price));
+        }
+    }
+    Some(bumped)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{Eip1559TransactionRequest, TransactionRequest};
+
+    use super::*;
+
+    fn eip1559(max_fee: u64, priority_fee: u64) -> TypedTransaction {
+        Eip1559TransactionRequest::new()
+            .max_fee_per_gas(max_fee)
+            .max_priority_fee_per_gas(priority_fee)
+            .into()
+    }
+
+    fn fees(tx: &TypedTransaction) -> (U256, Option<U256>) {
+        match tx {
+            TypedTransaction::Eip1559(tx) => {
+                (tx.max_fee_per_gas.unwrap(), tx.max_priority_fee_per_gas)
+            }
+            tx => (tx.gas_price().unwrap(), None),
+        }
+    }
+
+    #[test]
+    fn bumps_fees_by_percent() {
+        let bumped = bump_fees(&eip1559(1000, 100), 20, U256::from(10_000)).unwrap();
+        assert_eq!(fees(&bumped), (U256::from(1201), Some(U256::from(121))));
+
+        // Bumps below the minimum replacement increase are raised to it.
+        let bumped = bump_fees(&eip1559(1000, 100), 5, U256::from(10_000)).unwrap();
+        assert_eq!(fees(&bumped), (U256::from(1101), Some(U256::from(111))));
+
+        let legacy: TypedTransaction = TransactionRequest::new().gas_price(1000).into();
+        let bumped = bump_fees(&legacy, 20, U256::from(10_000)).unwrap();
+        assert_eq!(fees(&bumped), (U256::from(1201), None));
+    }
+
+    #[test]
+    fn caps_fees_at_ceiling() {
+        // The full bump is capped, but still meets the minimum replacement increase.
+        let bumped = bump_fees(&eip1559(1000, 100), 50, U256::from(1200)).unwrap();
+        assert_eq!(fees(&bumped), (U256::from(1200), Some(U256::from(151))));
+
+        // Not even the minimum increase fits under the ceiling.
+        assert!(bump_fees(&eip1559(1000, 100), 20, U256::from(1100)).is_none());
+        let legacy: TypedTransaction = TransactionRequest::new().gas_price(1000).into();
+        assert!(bump_fees(&legacy, 20, U256::from(1100)).is_none());
+    }
+
+    #[test]
+    fn caps_overflowing_bumps() {
+        // The minimum bump fits, the full one overflows and is capped.
+        let fee = U256::MAX / 115;
+        let tx = TypedTransaction::Eip1559(
+            Eip1559TransactionRequest::new()
+                .max_fee_per_gas(fee)
+                .max_priority_fee_per_gas(fee),
+        );
+        let bumped = bump_fees(&tx, 100, U256::MAX).unwrap();
+        assert_eq!(fees(&bumped), (U256::MAX, Some(U256::MAX)));
+        // Fees at the maximum can't be bumped any further.
+        assert!(bump_fees(&bumped, 100, U256::MAX).is_none());
+        let legacy: TypedTransaction = TransactionRequest::new().gas_price(U256::MAX).into();
+        assert!(bump_fees(&legacy, u64::MAX, U256::MAX).is_none());
+    }
+}
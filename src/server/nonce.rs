@@ -0,0 +1,254 @@
+use std::collections::BTreeSet;
+
+use ethers::prelude::{
+    Address, BlockNumber, Http, Middleware, Provider, ProviderError, TxHash, U256,
+};
+use lazy_static::lazy_static;
+use log::info;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+lazy_static! {
+    static ref NONCE_RESYNCS: IntCounterVec = register_int_counter_vec!(
+        "signer_nonce_resyncs_total",
+        "Number of nonce resynchronisations of a signer wallet.",
+        &["address"]
+    )
+    .unwrap();
+}
+
+/// Returns whether a send error was caused by a nonce that's out of sync with the chain.
+pub fn is_nonce_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+    error.contains("nonce too low")
+        || error.contains("nonce too high")
+        || error.contains("invalid nonce")
+}
+
+/// Returns whether a send error means a transaction with the same nonce is already pending.
+pub fn is_nonce_taken(error: &str) -> bool {
+    let error = error.to_lowercase();
+    error.contains("already known")
+        || error.contains("underpriced")
+        || error.contains("nonce too low")
+}
+
+/// Outcome of a nonce repair for one signer wallet.
+#[derive(Debug, Serialize)]
+pub struct NonceReport {
+    pub address: Address,
+    /// Next nonce the service would have used before the repair.
+    pub local_nonce: Option<U256>,
+    /// Pending transaction count reported by the node.
+    pub chain_nonce: U256,
+    /// Next nonce the service will use after the repair.
+    pub next_nonce: U256,
+    /// No-op self-transfers sent to fill nonce gaps.
+    pub filled: Vec<TxHash>,
+    pub error: Option<String>,
+}
+
+/// Nonces the chain never saw, found by a resynchronisation.
+#[derive(Debug)]
+pub struct NonceGaps {
+    /// Next nonce of the local sequence before the resynchronisation.
+    pub local_nonce: Option<U256>,
+    /// Pending transaction count reported by the node.
+    pub chain_nonce: U256,
+    /// Nonces below the local sequence that are neither pending on the node nor leased. They're
+    /// leased to the caller, which must mark each one as sent or release it.
+    pub gaps: Vec<U256>,
+}
+
+#[derive(Debug, Default)]
+struct NonceState {
+    /// Next nonce of the sequence, `None` until it's initialized from the chain.
+    next: Option<U256>,
+    /// Nonces handed out whose transactions haven't been broadcast yet.
+    leased: BTreeSet<U256>,
+    /// Nonces given back after a send failed, handed out again before the sequence advances.
+    released: BTreeSet<U256>,
+}
+
+impl NonceState {
+    /// Moves the sequence to at least `chain_nonce` and leases the nonces below it that the
+    /// chain never saw and that aren't leased already.
+    fn resync(&mut self, chain_nonce: U256) -> Vec<U256> {
+        let next = self.next.unwrap_or(chain_nonce).max(chain_nonce);
+        let mut gaps = vec![];
+        let mut nonce = chain_nonce;
+        while nonce < next {
+            if !self.leased.contains(&nonce) {
+                gaps.push(nonce);
+            }
+            nonce += U256::one();
+        }
+        // Released nonces are either gaps now or were used since.
+        self.released.clear();
+        self.leased.extend(gaps.iter().copied());
+        self.next = Some(next);
+        gaps
+    }
+}
+
+/// Local nonce sequence for a signer wallet.
+///
+/// Unlike ethers' `NonceManagerMiddleware`, the sequence can be resynchronised with the chain
+/// when it drifts, e.g. after a send failed once a nonce was taken or when another process
+/// uses the same key. Nonces are leased until their transaction is broadcast, so a
+/// resynchronisation never mistakes a transaction that's about to be sent for a gap.
+pub struct NonceManager {
+    address: Address,
+    state: Mutex<NonceState>,
+}
+
+impl NonceManager {
+    pub fn new(address: Address) -> Self {
+        Self {
+            address,
+            state: Mutex::new(NonceState::default()),
+        }
+    }
+
+    /// Leases the next nonce, preferring nonces released by failed sends and initializing the
+    /// sequence from the pending transaction count on first use.
+    pub async fn next(&self, provider: &Provider<Http>) -> Result<U256, ProviderError> {
+        let mut state = self.state.lock().await;
+        let nonce = match state.released.pop_first() {
+            Some(nonce) => nonce,
+            None => {
+                let nonce = match state.next {
+                    Some(nonce) => nonce,
+                    None => self.chain_nonce(provider).await?,
+                };
+                state.next = Some(nonce + 1);
+                nonce
+            }
+        };
+        state.leased.insert(nonce);
+        Ok(nonce)
+    }
+
+    /// Ends the lease of a nonce whose transaction was broadcast, or that the node already has a
+    /// transaction for.
+    pub async fn sent(&self, nonce: U256) {
+        self.state.lock().await.leased.remove(&nonce);
+    }
+
+    /// Ends the lease of a nonce whose transaction failed to send, handing it out again.
+    pub async fn release(&self, nonce: U256) {
+        let mut state = self.state.lock().await;
+        if state.leased.remove(&nonce) {
+            state.released.insert(nonce);
+        }
+    }
+
+    /// Returns the nonce the next call to [`NonceManager::next`] hands out, if the sequence is
+    /// initialized.
+    pub async fn peek(&self) -> Option<U256> {
+        let state = self.state.lock().await;
+        state.released.first().copied().or(state.next)
+    }
+
+    /// Raises the local sequence to at least `nonce`.
+    pub async fn seed(&self, nonce: U256) {
+        let mut state = self.state.lock().await;
+        state.next = Some(state.next.map_or(nonce, |n| n.max(nonce)));
+    }
+
+    /// Compares the local sequence against the chain and returns the gaps to fill.
+    ///
+    /// If the chain is ahead (another process used the key), the local sequence jumps forward.
+    /// If the local sequence is ahead, the nonces the chain never saw, other than those leased
+    /// to transactions that are about to be sent, are gaps that later transactions would be
+    /// stuck behind. The gaps are leased to the caller so they aren't handed out again.
+    pub async fn resync(&self, provider: &Provider<Http>) -> Result<NonceGaps, ProviderError> {
+        let mut state = self.state.lock().await;
+        let chain_nonce = self.chain_nonce(provider).await?;
+        let local_nonce = state.next;
+        let gaps = state.resync(chain_nonce);
+        let next = state.next.unwrap_or(chain_nonce);
+
+        if local_nonce != Some(next) || !gaps.is_empty() {
+            NONCE_RESYNCS
+                .with_label_values(&[&format!("{:?}", self.address)])
+                .inc();
+            info!(
+                "resynced nonce of {:?} from {:?} to {} (chain: {}, gaps: {})",
+                self.address,
+                local_nonce,
+                next,
+                chain_nonce,
+                gaps.len()
+            );
+        }
+        Ok(NonceGaps {
+            local_nonce,
+            chain_nonce,
+            gaps,
+        })
+    }
+
+    async fn chain_nonce(&self, provider: &Provider<Http>) -> Result<U256, ProviderError> {
+        provider
+            .get_transaction_count(self.address, Some(BlockNumber::Pending.into()))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nonces(values: &[u64]) -> Vec<U256> {
+        values.iter().copied().map(U256::from).collect()
+    }
+
+    #[test]
+    fn resync_skips_leased_nonces() {
+        let mut state = NonceState {
+            next: Some(U256::from(10)),
+            leased: nonces(&[8]).into_iter().collect(),
+            released: nonces(&[6]).into_iter().collect(),
+        };
+        assert_eq!(state.resync(U256::from(5)), nonces(&[5, 6, 7, 9]));
+        assert_eq!(state.next, Some(U256::from(10)));
+        assert!(state.released.is_empty());
+        assert_eq!(
+            state.leased.into_iter().collect::<Vec<_>>(),
+            nonces(&[5, 6, 7, 8, 9])
+        );
+    }
+
+    #[test]
+    fn resync_jumps_to_chain_nonce() {
+        let mut state = NonceState {
+            next: Some(U256::from(3)),
+            leased: BTreeSet::new(),
+            released: nonces(&[2]).into_iter().collect(),
+        };
+        assert!(state.resync(U256::from(7)).is_empty());
+        assert_eq!(state.next, Some(U256::from(7)));
+        assert!(state.released.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hands_out_released_nonces_first() {
+        let manager = NonceManager::new(Address::zero());
+        manager.seed(U256::from(4)).await;
+        let provider = Provider::<Http>::try_from("http://localhost:1").unwrap();
+        assert_eq!(manager.next(&provider).await.unwrap(), U256::from(4));
+        assert_eq!(manager.next(&provider).await.unwrap(), U256::from(5));
+        manager.release(U256::from(4)).await;
+        manager.sent(U256::from(5)).await;
+        assert_eq!(manager.peek().await, Some(U256::from(4)));
+        assert_eq!(manager.next(&provider).await.unwrap(), U256::from(4));
+        assert_eq!(manager.next(&provider).await.unwrap(), U256::from(6));
+        let state = manager.state.lock().await;
+        assert_eq!(
+            state.leased.iter().copied().collect::<Vec<_>>(),
+            nonces(&[4, 6])
+        );
+    }
+}
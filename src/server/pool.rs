@@ -0,0 +1,566 @@
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use ethers::prelude::{
+    Address, BlockNumber, Bytes, Eip1559TransactionRequest, Http, LocalWallet, Middleware,
+    Provider, ProviderError, RpcError, Signer, SignerMiddleware, TransactionReceipt, TxHash, U256,
+};
+use ethers::signers::{coins_bip39::English, MnemonicBuilder};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use tokio::sync::oneshot;
+
+use crate::server::fees::FeeStrategy;
+use crate::server::ledger::{LedgerEntry, RequestKind};
+use crate::server::monitor::TxMonitor;
+use crate::server::nonce::{is_nonce_error, is_nonce_taken, NonceManager, NonceReport};
+use crate::server::shared::{DefaultSignerMiddleware, Faucet, FaucetContract, Multicall};
+
+lazy_static! {
+    static ref SIGNER_PENDING: IntGaugeVec = register_int_gauge_vec!(
+        "signer_pending_requests",
+        "Number of in-flight requests assigned to a signer wallet.",
+        &["address"]
+    )
+    .unwrap();
+    static ref SIGNER_TRANSACTIONS: IntCounterVec = register_int_counter_vec!(
+        "signer_transactions_total",
+        "Number of transactions sent by a signer wallet.",
+        &["address"]
+    )
+    .unwrap();
+    static ref TX_SIMULATIONS: IntCounterVec = register_int_counter_vec!(
+        "tx_simulations_total",
+        "Number of transactions simulated against the pending state before sending, by result.",
+        &["result"]
+    )
+    .unwrap();
+    static ref NONCE_GAPS_FILLED: IntCounterVec = register_int_counter_vec!(
+        "signer_nonce_gaps_filled_total",
+        "Number of nonce gaps filled with no-op self-transfers.",
+        &["address"]
+    )
+    .unwrap();
+    static ref SIGNER_NONCE: IntGaugeVec = register_int_gauge_vec!(
+        "signer_nonce",
+        "Nonce of the last transaction sent by a signer wallet.",
+        &["address"]
+    )
+    .unwrap();
+}
+
+/// Channel yielding the receipt of a transaction watched by the monitor.
+pub type TxReceiver = oneshot::Receiver<anyhow::Result<TransactionReceipt>>;
+
+/// Strategy used to pick a signer wallet for a request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SignerStrategy {
+    /// Cycle through the wallets in order.
+    #[default]
+    RoundRobin,
+    /// Pick the wallet with the fewest in-flight requests.
+    LeastPending,
+}
+
+/// Gas limit settings for transactions sent by the pool.
+#[derive(Clone, Copy, Debug)]
+pub struct GasConfig {
+    /// Factor the gas estimate is multiplied by to get the gas limit.
+    pub multiplier: f64,
+    /// Maximum gas limit. Transactions needing more are rejected before they're sent.
+    pub cap: Option<u64>,
+}
+
+impl GasConfig {
+    /// Returns the gas limit for a gas estimate.
+    fn limit(&self, estimate: U256) -> Result<U256, PrepareError> {
+        let limit = if self.multiplier == 1.0 {
+            estimate
+        } else {
+            U256::from((estimate.low_u128() as f64 * self.multiplier).ceil() as u128)
+        };
+        match self.cap.map(U256::from) {
+            Some(cap) if estimate > cap => Err(PrepareError::GasCapExceeded { estimate, cap }),
+            Some(cap) => Ok(limit.min(cap)),
+            None => Ok(limit),
+        }
+    }
+}
+
+/// Parses a gas limit multiplier, which must be a finite number of at least 1.0 so that gas
+/// limits never fall below the estimate.
+pub fn parse_gas_limit_multiplier(s: &str) -> Result<f64, String> {
+    let multiplier: f64 = s.trim().parse().map_err(|e| format!("{}", e))?;
+    if !multiplier.is_finite() || multiplier < 1.0 {
+        return Err(format!("{} is not a finite number of at least 1.0", s));
+    }
+    Ok(multiplier)
+}
+
+/// Reason a transaction was rejected before a nonce was assigned to it.
+#[derive(Debug)]
+pub enum PrepareError {
+    /// The simulation reverted, with the revert data.
+    Reverted(Bytes),
+    /// The signer can't pay for the gas and value of the transaction.
+    InsufficientFunds {
+        required: U256,
+        balance: U256,
+    },
+    /// The gas estimate exceeds the configured cap.
+    GasCapExceeded {
+        estimate: U256,
+        cap: U256,
+    },
+    /// The fee strategy failed.
+    Fees(anyhow::Error),
+    Provider(ProviderError),
+}
+
+impl PrepareError {
+    fn from_provider(e: ProviderError) -> Self {
+        match e.as_error_response().and_then(|r| r.as_revert_data()) {
+            Some(data) => PrepareError::Reverted(data),
+            None => PrepareError::Provider(e),
+        }
+    }
+
+    /// Returns a copy of the error, unless it wraps a fee strategy or provider error.
+    pub fn try_clone(&self) -> Option<Self> {
+        match self {
+            PrepareError::Reverted(data) => Some(PrepareError::Reverted(data.clone())),
+            PrepareError::InsufficientFunds { required, balance } => {
+                Some(PrepareError::InsufficientFunds {
+                    required: *required,
+                    balance: *balance,
+                })
+            }
+            PrepareError::GasCapExceeded { estimate, cap } => Some(PrepareError::GasCapExceeded {
+                estimate: *estimate,
+                cap: *cap,
+            }),
+            PrepareError::Fees(_) | PrepareError::Provider(_) => None,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            PrepareError::Reverted(_) => "reverted",
+            PrepareError::InsufficientFunds { .. } => "insufficient_funds",
+            PrepareError::GasCapExceeded { .. } => "gas_cap_exceeded",
+            PrepareError::Fees(_) | PrepareError::Provider(_) => "error",
+        }
+    }
+}
+
+impl fmt::Display for PrepareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrepareError::Reverted(data) => write!(f, "simulation reverted: {}", data),
+            PrepareError::InsufficientFunds { required, balance } => write!(
+                f,
+                "signer balance {} is below the {} wei the transaction may cost",
+                balance, required
+            ),
+            PrepareError::GasCapExceeded { estimate, cap } => {
+                write!(f, "gas estimate {} exceeds the cap of {}", estimate, cap)
+            }
+            PrepareError::Fees(e) => write!(f, "fee estimation failed: {}", e),
+            PrepareError::Provider(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PrepareError {}
+
+impl From<ProviderError> for PrepareError {
+    fn from(e: ProviderError) -> Self {
+        PrepareError::Provider(e)
+    }
+}
+
+/// Loads the signer wallets from raw private keys and an optional HD mnemonic.
+pub fn load_wallets(
+    private_keys: &[String],
+    mnemonic: Option<&str>,
+    mnemonic_count: u32,
+    chain_id: u64,
+) -> anyhow::Result<Vec<LocalWallet>> {
+    let mut wallets = vec![];
+    for key in private_keys {
+        let key = hex::decode(key.strip_prefix("0x").unwrap_or(key))?;
+        wallets.push(LocalWallet::from_bytes(&key)?);
+    }
+    if let Some(mnemonic) = mnemonic {
+        for index in 0..mnemonic_count {
+            let wallet = MnemonicBuilder::<English>::default()
+                .phrase(mnemonic)
+                .index(index)?
+                .build()?;
+            wallets.push(wallet);
+        }
+    }
+    if wallets.is_empty() {
+        return Err(anyhow!("no signer wallets configured"));
+    }
+    Ok(wallets
+        .into_iter()
+        .map(|w| w.with_chain_id(chain_id))
+        .collect())
+}
+
+/// A wallet in the signer pool with its own nonce sequence and contract bindings.
+pub struct PoolSigner {
+    pub client: Arc<DefaultSignerMiddleware>,
+    pub faucet: Faucet,
+    pub multicall: Multicall,
+    address: Address,
+    nonces: NonceManager,
+    fees: Arc<dyn FeeStrategy>,
+    gas: GasConfig,
+    label: String,
+    pending: AtomicUsize,
+}
+
+impl PoolSigner {
+    /// Returns the address of the signer wallet.
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Sets the fees and gas of a transaction, simulates it against the pending state, then
+    /// assigns it the signer's next nonce.
+    ///
+    /// The nonce is taken last so that transactions that would revert, or that the signer can't
+    /// pay for, don't leave gaps in the nonce sequence.
+    pub async fn prepare(&self, tx: &mut TypedTransaction) -> Result<(), PrepareError> {
+        let result = self.simulate(tx).await;
+        TX_SIMULATIONS
+            .with_label_values(&[result.as_ref().map_or_else(|e| e.label(), |_| "ok")])
+            .inc();
+        result?;
+        let nonce = self.nonces.next(self.client.provider()).await?;
+        tx.set_nonce(nonce);
+        Ok(())
+    }
+
+    /// Sets the fees and gas limit of a transaction and checks with `eth_call` that it succeeds
+    /// against the pending state.
+    async fn simulate(&self, tx: &mut TypedTransaction) -> Result<(), PrepareError> {
+        tx.set_from(self.address);
+        tx.set_chain_id(self.client.signer().chain_id());
+        let provider = self.client.provider();
+        let pending = Some(BlockNumber::Pending.into());
+
+        let fees = self.fees.fees(provider).await.map_err(PrepareError::Fees)?;
+        fees.apply(tx);
+        let estimate = provider
+            .estimate_gas(tx, pending)
+            .await
+            .map_err(PrepareError::from_provider)?;
+        let gas_limit = self.gas.limit(estimate)?;
+        tx.set_gas(gas_limit);
+
+        let fee_cap = tx.gas_price().unwrap_or_default();
+        let value = tx.value().copied().unwrap_or_default();
+        let required = max_cost(gas_limit, fee_cap, value).ok_or_else(|| {
+            PrepareError::Fees(anyhow!(
+                "gas limit {} at {} wei per gas overflows the transaction cost",
+                gas_limit,
+                fee_cap
+            ))
+        })?;
+        let balance = provider.get_balance(self.address, pending).await?;
+        if balance < required {
+            return Err(PrepareError::InsufficientFunds { required, balance });
+        }
+
+        provider
+            .call(tx, pending)
+            .await
+            .map_err(PrepareError::from_provider)?;
+        Ok(())
+    }
+
+    /// Raises the signer's local nonce sequence to at least `nonce`.
+    pub async fn seed_nonce(&self, nonce: U256) {
+        self.nonces.seed(nonce).await
+    }
+
+    /// Resynchronises the signer's nonce sequence with the chain, filling any gaps.
+    ///
+    /// Gaps are filled with no-op self-transfers, priced like any other transaction and handed
+    /// to `monitor`, so they're counted against the spending budget and replaced if they get
+    /// stuck. Gaps that can't be filled are handed out to the next transactions instead. The
+    /// returned channels yield the receipts of the fills.
+    pub async fn repair_nonce(
+        self: &Arc<Self>,
+        monitor: &TxMonitor,
+    ) -> anyhow::Result<(NonceReport, Vec<TxReceiver>)> {
+        let resync = self.nonces.resync(self.client.provider()).await?;
+        let mut report = NonceReport {
+            address: self.address,
+            local_nonce: resync.local_nonce,
+            chain_nonce: resync.chain_nonce,
+            next_nonce: resync.chain_nonce,
+            filled: vec![],
+            error: None,
+        };
+        let mut receipts = vec![];
+        let mut gaps = resync.gaps.into_iter();
+        for nonce in gaps.by_ref() {
+            match self.fill_gap(monitor, nonce).await {
+                Ok(Some((hash, receipt))) => {
+                    NONCE_GAPS_FILLED.with_label_values(&[&self.label]).inc();
+                    report.filled.push(hash);
+                    receipts.push(receipt);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!(
+                        "failed to fill nonce gap {} of {:?}: {}",
+                        nonce, self.address, e
+                    );
+                    report.error = Some(e.to_string());
+                    break;
+                }
+            }
+        }
+        for nonce in gaps {
+            self.nonces.release(nonce).await;
+        }
+        if let Some(next) = self.nonces.peek().await {
+            report.next_nonce = next;
+        }
+        Ok((report, receipts))
+    }
+
+    /// Sends a no-op self-transfer with a leased nonce. Returns `None` if the node already has a
+    /// transaction with that nonce. The nonce is released if the fill isn't sent.
+    async fn fill_gap(
+        self: &Arc<Self>,
+        monitor: &TxMonitor,
+        nonce: U256,
+    ) -> anyhow::Result<Option<(TxHash, TxReceiver)>> {
+        let mut tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .to(self.address)
+            .value(U256::zero())
+            .into();
+        let prepared = match monitor.budget().check().await {
+            Ok(()) => self.simulate(&mut tx).await.map_err(anyhow::Error::from),
+            Err(_) => Err(anyhow!("spending budget is used up")),
+        };
+        if let Err(e) = prepared {
+            self.nonces.release(nonce).await;
+            return Err(e);
+        }
+        tx.set_nonce(nonce);
+
+        let mut entry = LedgerEntry::new(RequestKind::NonceFill, self.address, None);
+        entry.record_tx(&tx);
+        match self.client.send_transaction(tx.clone(), None).await {
+            Ok(pending) => {
+                let hash = pending.tx_hash();
+                self.record_sent(Some(nonce)).await;
+                entry.tx_hash = Some(hash);
+                monitor.ledger().insert(&mut entry).await;
+                let receipt = monitor.watch(SignerLease::new(self.clone()), tx, vec![entry]);
+                Ok(Some((hash, receipt)))
+            }
+            Err(e) if is_nonce_taken(&e.to_string()) => {
+                self.nonces.sent(nonce).await;
+                Ok(None)
+            }
+            Err(e) => {
+                self.nonces.release(nonce).await;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Records a transaction sent by this signer, ending the lease of its nonce.
+    pub async fn record_sent(&self, nonce: Option<U256>) {
+        SIGNER_TRANSACTIONS.with_label_values(&[&self.label]).inc();
+        if let Some(nonce) = nonce {
+            self.nonces.sent(nonce).await;
+            SIGNER_NONCE
+                .with_label_values(&[&self.label])
+                .set(nonce.low_u64() as i64);
+        }
+    }
+
+    fn set_pending(&self, pending: usize) {
+        SIGNER_PENDING
+            .with_label_values(&[&self.label])
+            .set(pending as i64);
+    }
+}
+
+/// Pool of signer wallets that requests are spread over.
+///
+/// Each wallet has its own nonce sequence, so a slow or stuck transaction from one wallet
+/// doesn't hold up requests assigned to the others.
+pub struct SignerPool {
+    signers: Vec<Arc<PoolSigner>>,
+    strategy: SignerStrategy,
+    next: AtomicUsize,
+}
+
+impl SignerPool {
+    /// Creates a pool with one signer per wallet.
+    pub fn new(
+        provider: Provider<Http>,
+        wallets: Vec<LocalWallet>,
+        faucet_address: Address,
+        multicall_address: Address,
+        strategy: SignerStrategy,
+        fees: Arc<dyn FeeStrategy>,
+        gas: GasConfig,
+    ) -> anyhow::Result<Self> {
+        if wallets.is_empty() {
+            return Err(anyhow!("signer pool requires at least one wallet"));
+        }
+        let signers = wallets
+            .into_iter()
+            .map(|wallet| {
+                let address = wallet.address();
+                let client: DefaultSignerMiddleware =
+                    SignerMiddleware::new(provider.clone(), wallet);
+                let client = Arc::new(client);
+                let faucet: Faucet = FaucetContract::new(faucet_address, client.clone());
+                let multicall: Multicall = Multicall::new(multicall_address, client.clone());
+                info!("added signer {:?} to pool", address);
+                Arc::new(PoolSigner {
+                    client,
+                    faucet,
+                    multicall,
+                    address,
+                    nonces: NonceManager::new(address),
+                    fees: fees.clone(),
+                    gas,
+                    label: format!("{:?}", address),
+                    pending: AtomicUsize::new(0),
+                })
+            })
+            .collect();
+        Ok(Self {
+            signers,
+            strategy,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns all signers in the pool.
+    pub fn signers(&self) -> &[Arc<PoolSigner>] {
+        &self.signers
+    }
+
+    /// Returns the first signer in the pool.
+    pub fn primary(&self) -> &Arc<PoolSigner> {
+        &self.signers[0]
+    }
+
+    /// Picks a signer for a request. The signer counts as busy until the lease is dropped.
+    pub fn acquire(&self) -> SignerLease {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.signers.len();
+        let signer = match self.strategy {
+            SignerStrategy::RoundRobin => &self.signers[start % len],
+            SignerStrategy::LeastPending => (0..len)
+                .map(|i| &self.signers[(start + i) % len])
+                .min_by_key(|s| s.pending.load(Ordering::Relaxed))
+                .unwrap_or(&self.signers[0]),
+        };
+        SignerLease::new(signer.clone())
+    }
+}
+
+/// A signer assigned to a request.
+pub struct SignerLease(Arc<PoolSigner>);
+
+impl SignerLease {
+    fn new(signer: Arc<PoolSigner>) -> Self {
+        let pending = signer.pending.fetch_add(1, Ordering::Relaxed) + 1;
+        signer.set_pending(pending);
+        Self(signer)
+    }
+
+    /// Gives back the nonce of a transaction that failed to send.
+    ///
+    /// A nonce the node already has a transaction for is dropped. On a nonce mismatch the
+    /// nonce is dropped too and the sequence is resynchronised in the background, which fills
+    /// it if the chain never saw it. Otherwise, e.g. after an RPC timeout, the nonce is handed
+    /// out again to the next transaction.
+    pub async fn release_nonce(&self, nonce: Option<U256>, error: &str, monitor: &TxMonitor) {
+        let Some(nonce) = nonce else {
+            return;
+        };
+        if !is_nonce_error(error) && !is_nonce_taken(error) {
+            self.0.nonces.release(nonce).await;
+            return;
+        }
+        self.0.nonces.sent(nonce).await;
+        if !is_nonce_error(error) {
+            return;
+        }
+        let signer = self.0.clone();
+        let monitor = monitor.clone();
+        tokio::spawn(async move {
+            if let Err(e) = signer.repair_nonce(&monitor).await {
+                warn!("failed to resync nonce of {:?}: {}", signer.address, e);
+            }
+        });
+    }
+}
+
+impl Deref for SignerLease {
+    type Target = PoolSigner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for SignerLease {
+    fn drop(&mut self) {
+        let pending = self.0.pending.fetch_sub(1, Ordering::Relaxed) - 1;
+        self.0.set_pending(pending);
+    }
+}
+
+/// Returns the most a transaction can cost, gas at the fee cap plus value, or `None` if that
+/// overflows.
+fn max_cost(gas_limit: U256, fee_cap: U256, value: U256) -> Option<U256> {
+    gas_limit.checked_mul(fee_cap)?.checked_add(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gas_limit_multiplier() {
+        assert_eq!(parse_gas_limit_multiplier("1.25"), Ok(1.25));
+        assert_eq!(parse_gas_limit_multiplier("1"), Ok(1.0));
+        for invalid in ["0.9", "-2", "NaN", "inf", "x"] {
+            assert!(parse_gas_limit_multiplier(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn computes_max_cost_without_overflow() {
+        let gwei = U256::exp10(9);
+        assert_eq!(
+            max_cost(U256::from(21_000), gwei, U256::one()),
+            Some(U256::from(21_000) * gwei + 1)
+        );
+        assert_eq!(max_cost(U256::from(21_000), U256::MAX, U256::zero()), None);
+        assert_eq!(max_cost(U256::one(), U256::MAX, U256::one()), None);
+    }
+}
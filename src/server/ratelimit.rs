@@ -0,0 +1,240 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use warp::Rejection;
+
+use crate::server::shared::TooManyRequests;
+use crate::server::state::SharedState;
+
+lazy_static! {
+    static ref RATE_LIMITED_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "rate_limited_requests_total",
+        "Number of requests rejected by the rate limiter, by route and bucket.",
+        &["route", "bucket"]
+    )
+    .unwrap();
+}
+
+/// Token bucket quota, written as `<requests>/<seconds>`, e.g. `10/60`.
+///
+/// A bucket holds up to `requests` tokens and refills at `requests` per window. A quota with
+/// zero requests disables the bucket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub requests: u32,
+    pub window: Duration,
+}
+
+impl Quota {
+    fn is_enabled(&self) -> bool {
+        self.requests > 0 && !self.window.is_zero()
+    }
+
+    /// Tokens added per second.
+    pub(crate) fn rate(&self) -> f64 {
+        self.requests as f64 / self.window.as_secs_f64()
+    }
+}
+
+impl FromStr for Quota {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (requests, seconds) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("expected <requests>/<seconds>, got {}", s))?;
+        Ok(Quota {
+            requests: requests.trim().parse()?,
+            window: Duration::from_secs(seconds.trim().parse()?),
+        })
+    }
+}
+
+/// Rate limit quotas per kind of bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitConfig {
+    /// Per client IP address.
+    pub ip: Quota,
+    /// Per client network: the IPv4 /24 or IPv6 /64 prefix.
+    pub prefix: Quota,
+    /// Per target address.
+    pub address: Quota,
+}
+
+/// Token bucket rate limiter.
+///
+/// Requests are counted per route against buckets for the client IP, the client's network
+/// prefix and the target address. A request is only admitted, and only then charged, if every
+/// bucket checked at once has a token left, so rejected requests don't drain the other buckets.
+/// Buckets live in the shared state, so with the Redis backend the limits apply across replicas.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    state: Arc<SharedState>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, state: Arc<SharedState>) -> Self {
+        Self { config, state }
+    }
+
+    /// Admits a request for a target address, in canonical form, or rejects it with
+    /// `TooManyRequests` and a retry delay.
+    pub async fn check(
+        &self,
+        route: &'static str,
+        client_ip: Option<IpAddr>,
+        address: &str,
+    ) -> Result<(), Rejection> {
+        let mut keys = self.client_keys(client_ip);
+        keys.push(("address", self.config.address, address.to_string()));
+        self.take(route, keys).await
+    }
+
+    /// Admits a request from a client against its IP and network prefix buckets only.
+    pub async fn check_client(
+        &self,
+        route: &'static str,
+        client_ip: Option<IpAddr>,
+    ) -> Result<(), Rejection> {
+        self.take(route, self.client_keys(client_ip)).await
+    }
+
+    /// Admits a request for a target address, in canonical form, against its address bucket
+    /// only. Routes that verify the client first call this after verification, so that
+    /// unverified requests can't use up the bucket of somebody else's address.
+    pub async fn check_address(&self, route: &'static str, address: &str) -> Result<(), Rejection> {
+        let keys = vec![("address", self.config.address, address.to_string())];
+        self.take(route, keys).await
+    }
+
+    fn client_keys(&self, client_ip: Option<IpAddr>) -> Vec<(&'static str, Quota, String)> {
+        match client_ip {
+            Some(ip) => vec![
+                ("ip", self.config.ip, ip.to_string()),
+                ("prefix", self.config.prefix, network_prefix(ip)),
+            ],
+            None => vec![],
+        }
+    }
+
+    async fn take(
+        &self,
+        route: &'static str,
+        mut keys: Vec<(&'static str, Quota, String)>,
+    ) -> Result<(), Rejection> {
+        keys.retain(|(_, quota, _)| quota.is_enabled());
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let buckets: Vec<_> = keys
+            .iter()
+            .map(|(bucket, quota, key)| (format!("ratelimit:{}:{}:{}", route, bucket, key), *quota))
+            .collect();
+        if let Err((index, wait)) = self.state.take_tokens(&buckets).await {
+            RATE_LIMITED_REQUESTS
+                .with_label_values(&[route, keys[index].0])
+                .inc();
+            return Err(warp::reject::custom(TooManyRequests {
+                retry_after: Some(wait.as_secs() + 1),
+            }));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the IPv4 /24 or IPv6 /64 network of an address.
+fn network_prefix(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            format!("{:x}:{:x}:{:x}:{:x}::/64", s[0], s[1], s[2], s[3])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(requests: u32, seconds: u64) -> Quota {
+        Quota {
+            requests,
+            window: Duration::from_secs(seconds),
+        }
+    }
+
+    #[test]
+    fn parses_quotas() {
+        assert_eq!("10/60".parse::<Quota>().unwrap(), quota(10, 60));
+        assert_eq!(" 3 / 1 ".parse::<Quota>().unwrap(), quota(3, 1));
+        assert!(!"0/60".parse::<Quota>().unwrap().is_enabled());
+        assert!(!"10/0".parse::<Quota>().unwrap().is_enabled());
+        for s in ["", "10", "10/", "/60", "-1/60", "10/1.5", "a/b"] {
+            assert!(s.parse::<Quota>().is_err(), "{:?}", s);
+        }
+        assert_eq!(quota(30, 60).rate(), 0.5);
+    }
+
+    #[test]
+    fn groups_clients_by_network() {
+        assert_eq!(network_prefix([192, 0, 2, 17].into()), "192.0.2.0/24");
+        let ip: IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        assert_eq!(network_prefix(ip), "2001:db8:1:2::/64");
+    }
+
+    #[tokio::test]
+    async fn limits_each_bucket() {
+        let limiter = RateLimiter::new(
+            RateLimitConfig {
+                ip: quota(3, 60),
+                prefix: quota(4, 60),
+                address: quota(1, 60),
+            },
+            Arc::new(SharedState::memory()),
+        );
+        let ip: IpAddr = [192, 0, 2, 1].into();
+        let neighbour: IpAddr = [192, 0, 2, 2].into();
+
+        assert!(limiter.check("test", Some(ip), "0xa").await.is_ok());
+        assert!(limiter.check("test", Some(ip), "0xa").await.is_err());
+        // The rejected request wasn't charged to the client buckets.
+        assert!(limiter.check("test", Some(ip), "0xb").await.is_ok());
+        assert!(limiter.check_client("test", Some(ip)).await.is_ok());
+        assert!(limiter.check_client("test", Some(ip)).await.is_err());
+        // The neighbour shares the prefix bucket.
+        assert!(limiter.check_client("test", Some(neighbour)).await.is_ok());
+        assert!(limiter.check_client("test", Some(neighbour)).await.is_err());
+
+        // Address buckets are independent of the client.
+        assert!(limiter.check_address("test", "0xc").await.is_ok());
+        assert!(limiter.check_address("test", "0xc").await.is_err());
+        // Buckets are per route.
+        assert!(limiter.check_address("other", "0xc").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn skips_disabled_buckets() {
+        let limiter = RateLimiter::new(
+            RateLimitConfig {
+                ip: quota(0, 60),
+                prefix: quota(1, 0),
+                address: quota(0, 60),
+            },
+            Arc::new(SharedState::memory()),
+        );
+        let ip: IpAddr = [192, 0, 2, 1].into();
+        for _ in 0..10 {
+            assert!(limiter.check("test", Some(ip), "0xa").await.is_ok());
+        }
+    }
+}
@@ -1,191 +1,465 @@
-use crate::server::shared::DefaultSignerMiddleware;
+use crate::server::account::account_state;
+use crate::server::address::{AddressKind, TargetAddress};
+use crate::server::batch::{send_batch, BatchConfig, BatchRequest, Batcher};
+use crate::server::ledger::{Ledger, LedgerEntry, RequestKind};
+use crate::server::lotus::NativeSender;
+use crate::server::monitor::TxMonitor;
+use crate::server::pool::{PrepareError, SignerLease, SignerPool};
+use crate::server::ratelimit::RateLimiter;
+use crate::server::revert::{decode_revert, replay_revert};
 use crate::server::{
-    shared::{with_client, BadRequest, RegisterRequest},
+    shared::{
+        complete_claims, idempotency_key, with_batcher, with_dedupe, with_ledger, with_monitor,
+        with_native, with_pool, with_rate_limiter, BadRequest, Claim, RegisterRequest,
+        RequestDedupe, Unavailable, MAX_WAIT,
+    },
     util::log_request_body,
 };
 use anyhow::anyhow;
 use ethers::{
-    core::types::Eip1559TransactionRequest,
-    prelude::{Address, TxHash, I256, U256},
+    abi::Abi,
+    contract::multicall_contract::Call3Value,
+    core::types::{transaction::eip2718::TypedTransaction, Eip1559TransactionRequest},
+    prelude::{Address, Bytes, TxHash, U256},
     providers::Middleware,
 };
+use log::{info, warn};
 use serde_json::json;
+use std::net::IpAddr;
 use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
 use warp::{Filter, Rejection, Reply};
+use warp_real_ip::real_ip;
 
 /// Enum to handle register results.
 enum RegisterResult {
     Pending(TxHash),
     Success(TxHash),
     Failure(String),
+    /// The signer wallet can't pay for the registration.
+    InsufficientFunds(String),
 }
 
 /// Route filter for `/register` endpoint.
+#[allow(clippy::too_many_arguments)]
 pub fn register_route(
-    client: Arc<DefaultSignerMiddleware>,
+    trusted_proxy_ips: Vec<IpAddr>,
+    pool: Arc<SignerPool>,
+    limiter: Arc<RateLimiter>,
+    dedupe: RequestDedupe,
+    native: Option<Arc<NativeSender>>,
+    ledger: Ledger,
+    monitor: TxMonitor,
+    batcher: Option<Batcher<Address>>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path("register")
         .and(warp::post())
         .and(warp::header::exact("content-type", "application/json"))
         .and(warp::body::json())
-        .and(with_client(client))
+        .and(idempotency_key())
+        .and(real_ip(trusted_proxy_ips))
+        .and(with_pool(pool))
+        .and(with_rate_limiter(limiter))
+        .and(with_dedupe(dedupe))
+        .and(with_native(native))
+        .and(with_ledger(ledger))
+        .and(with_monitor(monitor))
+        .and(with_batcher(batcher))
         .and_then(handle_register)
 }
 
 /// Handles the `/register` request.
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_register(
     req: RegisterRequest,
-    client: Arc<DefaultSignerMiddleware>,
+    idempotency_key: Option<String>,
+    addr: Option<IpAddr>,
+    pool: Arc<SignerPool>,
+    limiter: Arc<RateLimiter>,
+    dedupe: RequestDedupe,
+    native: Option<Arc<NativeSender>>,
+    ledger: Ledger,
+    monitor: TxMonitor,
+    batcher: Option<Batcher<Address>>,
 ) -> anyhow::Result<impl Reply, Rejection> {
     log_request_body("register", &format!("{}", req));
 
-    let to_address = req.address.parse::<Address>().map_err(|e| {
+    let target = TargetAddress::parse(&req.address).map_err(|e| {
         Rejection::from(BadRequest {
-            message: format!("invalid ethereum address: {}", e),
+            message: format!("invalid address: {}", e),
         })
     })?;
+    let target_key = target.canonical();
 
-    let res = register(client, to_address, req.wait).await.map_err(|e| {
+    let idempotency = match dedupe
+        .claim_idempotency_key("register", idempotency_key, addr, &target_key)
+        .await?
+    {
+        Some(Claim::Done(body)) => return Ok(warp::reply::json(&body)),
+        Some(Claim::Held(guard)) => Some(guard),
+        None => None,
+    };
+
+    limiter.check("register", addr, &target_key).await?;
+
+    let in_flight = match dedupe.claim_address("register", &target_key).await? {
+        Claim::Done(body) => return Ok(complete_claims(idempotency, None, body).await),
+        Claim::Held(guard) => guard,
+    };
+
+    let body = match target.eth_address() {
+        Some(to_address) => {
+            register_evm(
+                to_address,
+                addr,
+                req.wait,
+                &pool,
+                &ledger,
+                &monitor,
+                batcher.as_ref(),
+            )
+            .await?
+        }
+        None => register_native(native, &target, addr, req.wait, &ledger, &monitor).await?,
+    };
+    Ok(complete_claims(idempotency, Some(in_flight), body).await)
+}
+
+/// Registers an address reachable through the EVM, unless it already exists.
+async fn register_evm(
+    to_address: Address,
+    addr: Option<IpAddr>,
+    wait: Option<bool>,
+    pool: &SignerPool,
+    ledger: &Ledger,
+    monitor: &TxMonitor,
+    batcher: Option<&Batcher<Address>>,
+) -> Result<serde_json::Value, Rejection> {
+    if let Some(body) = existing_registration(pool, ledger, to_address).await? {
+        return Ok(body);
+    }
+
+    monitor.budget().check().await?;
+
+    let mut entry = LedgerEntry::new(RequestKind::Register, to_address, addr);
+    ledger.insert(&mut entry).await;
+
+    let res = match batcher {
+        Some(batcher) => register_batched(batcher, to_address, wait, &mut entry).await,
+        None => register(pool.acquire(), monitor, to_address, wait, &mut entry).await,
+    };
+    // Once broadcast, the monitor keeps the ledger entry up to date.
+    if entry.tx_hash.is_none() {
+        match &res {
+            Ok(RegisterResult::Failure(message)) => entry.fail(message),
+            Ok(RegisterResult::InsufficientFunds(message)) => entry.fail(message),
+            Err(e) => entry.fail(e),
+            _ => {}
+        }
+        ledger.update(&entry).await;
+    }
+
+    let res = res.map_err(|e| {
         Rejection::from(BadRequest {
             message: format!("register error: {}", e),
         })
     })?;
     match res {
         RegisterResult::Success(tx) | RegisterResult::Pending(tx) => {
-            Ok(warp::reply::json(&json!({"tx_hash": tx})))
+            let mut body = json!({"tx_hash": tx});
+            if let Some(index) = entry.batch_index {
+                body["batch_index"] = json!(index);
+            }
+            Ok(body)
         }
         RegisterResult::Failure(message) => Err(warp::reject::custom(BadRequest { message })),
+        RegisterResult::InsufficientFunds(message) => Err(warp::reject::custom(Unavailable {
+            reason: "signer-insufficient-funds",
+            message,
+        })),
+    }
+}
+
+/// Registers an f1 or f3 address by sending a native message through the Lotus API.
+async fn register_native(
+    native: Option<Arc<NativeSender>>,
+    target: &TargetAddress,
+    addr: Option<IpAddr>,
+    wait: Option<bool>,
+    ledger: &Ledger,
+    monitor: &TxMonitor,
+) -> Result<serde_json::Value, Rejection> {
+    let bad_request = |message: String| Rejection::from(BadRequest { message });
+    if target.kind() == AddressKind::Actor {
+        return Err(bad_request(format!(
+            "invalid address: {}",
+            target.require_eth().unwrap_err()
+        )));
+    }
+    let native = native.ok_or_else(|| {
+        bad_request("f1 and f3 addresses require the native backend (LOTUS_RPC_URL)".to_string())
+    })?;
+    let address = target.to_filecoin('f');
+
+    let id = native
+        .lookup_id(target)
+        .await
+        .map_err(|e| bad_request(format!("register error: {}", e)))?;
+    if let Some(id) = id {
+        info!("{} is already registered", address);
+        return Ok(json!({
+            "status": "already_registered",
+            "id_address": TargetAddress::Id(id).to_filecoin('f'),
+        }));
+    }
+
+    monitor.budget().check().await?;
+
+    let mut entry = LedgerEntry::native(address.clone(), addr);
+    ledger.insert(&mut entry).await;
+    let (cid, fee_cap) = match native.send(target).await {
+        Ok(sent) => sent,
+        Err(e) => {
+            entry.fail(&e);
+            ledger.update(&entry).await;
+            return Err(bad_request(format!("register error: {}", e)));
+        }
+    };
+    info!("sent native register message {} for {}", cid, address);
+    monitor.budget().record_tx().await;
+    entry.message_cid = Some(cid.clone());
+    ledger.update(&entry).await;
+
+    // Keep the ledger entry and the spending budget up to date once the message is included,
+    // even if the request stops waiting for it.
+    let (sender, included) = oneshot::channel();
+    let ledger = ledger.clone();
+    let monitor = monitor.clone();
+    let message_cid = cid.clone();
+    tokio::spawn(async move {
+        let res = native.wait(&message_cid).await;
+        match &res {
+            Ok(lookup) => {
+                entry.apply_message_lookup(lookup);
+                ledger.update(&entry).await;
+                // The fee cap bounds what was paid per unit of gas.
+                let gas_used = U256::from(lookup.receipt.gas_used.max(0));
+                monitor
+                    .budget()
+                    .record_fees(gas_used.saturating_mul(fee_cap))
+                    .await;
+            }
+            Err(e) => warn!("failed to wait for native message {}: {}", message_cid, e),
+        }
+        let _ = sender.send(res);
+    });
+    if !wait.unwrap_or(true) {
+        return Ok(json!({"cid": cid}));
+    }
+    let lookup = match timeout(MAX_WAIT, included).await {
+        Ok(Ok(res)) => res.map_err(|e| bad_request(format!("register error: {}", e)))?,
+        Ok(Err(_)) => return Err(bad_request("register did not return a receipt".to_string())),
+        Err(_) => return Ok(json!({"cid": cid})),
+    };
+    if lookup.receipt.exit_code != 0 {
+        return Err(bad_request(format!(
+            "register message {} failed with exit code {}",
+            cid, lookup.receipt.exit_code
+        )));
+    }
+    Ok(json!({
+        "cid": cid,
+        "height": lookup.height,
+        "gas_used": lookup.receipt.gas_used,
+    }))
+}
+
+/// Returns the `already_registered` response if the address exists on chain or has a mined or
+/// pending registration in the ledger.
+async fn existing_registration(
+    pool: &SignerPool,
+    ledger: &Ledger,
+    to_address: Address,
+) -> Result<Option<serde_json::Value>, Rejection> {
+    let state = account_state(pool.primary().client.as_ref(), to_address)
+        .await
+        .map_err(|e| {
+            Rejection::from(BadRequest {
+                message: format!("register error: {}", e),
+            })
+        })?;
+    let registration = if state.exists() {
+        None
+    } else {
+        match ledger.find_registration(to_address).await {
+            Ok(Some(entry)) => Some(entry),
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                warn!(
+                    "failed to look up registration of {:?}: {:#}",
+                    to_address, e
+                );
+                return Ok(None);
+            }
+        }
+    };
+    info!("{:?} is already registered", to_address);
+    let mut body = json!({
+        "status": "already_registered",
+        "nonce": state.nonce,
+        "balance": state.balance,
+        "has_code": state.has_code,
+    });
+    if let Some(entry) = registration {
+        body["tx_hash"] = json!(entry.tx_hash);
+        body["tx_status"] = json!(entry.status);
     }
+    Ok(Some(body))
 }
 
 /// Registers an address on the subnet by sending a transaction.
 /// This will trigger the FVM to create an account for the address.
 async fn register(
-    client: Arc<DefaultSignerMiddleware>,
+    signer: SignerLease,
+    monitor: &TxMonitor,
     to_address: Address,
     wait: Option<bool>,
+    entry: &mut LedgerEntry,
 ) -> anyhow::Result<RegisterResult> {
-    let (fee, fee_cap) = premium_estimation(client.clone()).await?;
-    let tx = Eip1559TransactionRequest::new()
+    let client = signer.client.clone();
+    let mut tx: TypedTransaction = Eip1559TransactionRequest::new()
         .to(to_address)
         .value(U256::zero())
-        .max_priority_fee_per_gas(fee)
-        .max_fee_per_gas(fee_cap);
-    let tx_pending = client.send_transaction(tx, None).await;
+        .into();
+    if let Err(e) = signer.prepare(&mut tx).await {
+        return result_from_prepare(e);
+    }
+    entry.record_tx(&tx);
+    let tx_pending = client.send_transaction(tx.clone(), None).await;
     match tx_pending {
-        Ok(tx) => {
-            let hash = tx.tx_hash();
+        Ok(pending) => {
+            let hash = pending.tx_hash();
+            entry.tx_hash = Some(hash);
+            signer.record_sent(entry.nonce).await;
+            let receipt = monitor.watch(signer, tx.clone(), vec![entry.clone()]);
             let wait = wait.unwrap_or(true);
             if wait {
-                tx.await?
-                    .ok_or(anyhow!("register did not return a receipt"))?;
-                Ok(RegisterResult::Success(hash))
+                let Ok(receipt) = timeout(MAX_WAIT, receipt).await else {
+                    return Ok(RegisterResult::Pending(hash));
+                };
+                let receipt =
+                    receipt.map_err(|_| anyhow!("register did not return a receipt"))??;
+                if receipt.status != Some(1u64.into()) {
+                    return match replay_revert(client.provider(), &tx, &receipt).await {
+                        Some(data) => result_from_prepare(PrepareError::Reverted(data)),
+                        None => Ok(RegisterResult::Failure(format!(
+                            "register {:?} reverted",
+                            receipt.transaction_hash
+                        ))),
+                    };
+                }
+                Ok(RegisterResult::Success(receipt.transaction_hash))
             } else {
                 Ok(RegisterResult::Pending(hash))
             }
         }
-        Err(e) => Ok(RegisterResult::Failure(e.to_string())),
+        Err(e) => {
+            signer
+                .release_nonce(entry.nonce, &e.to_string(), monitor)
+                .await;
+            Ok(RegisterResult::Failure(e.to_string()))
+        }
     }
 }
 
-/// Returns an estimation of an optimal `gas_premium` and `gas_fee_cap`
-/// for a transaction considering the average premium, base_fee and reward percentile from
-/// past blocks
-/// This is an adaptation of ethers' `eip1559_default_estimator`:
-/// https://github.com/gakonst/ethers-rs/blob/5dcd3b7e754174448f9a8cbfc0523896609629f9/ethers-core/src/utils/mod.rs#L476
-async fn premium_estimation(signer: Arc<DefaultSignerMiddleware>) -> anyhow::Result<(U256, U256)> {
-    let base_fee_per_gas = signer
-        .get_block(ethers::types::BlockNumber::Latest)
-        .await?
-        .ok_or_else(|| anyhow!("Latest block not found"))?
-        .base_fee_per_gas
-        .ok_or_else(|| anyhow!("EIP-1559 not activated"))?;
-
-    let fee_history = signer
-        .fee_history(
-            ethers::utils::EIP1559_FEE_ESTIMATION_PAST_BLOCKS,
-            ethers::types::BlockNumber::Latest,
-            &[ethers::utils::EIP1559_FEE_ESTIMATION_REWARD_PERCENTILE],
-        )
-        .await?;
-
-    let max_priority_fee_per_gas = estimate_priority_fee(fee_history.reward); //overestimate?
-    let potential_max_fee = base_fee_surged(base_fee_per_gas);
-    let max_fee_per_gas = if max_priority_fee_per_gas > potential_max_fee {
-        max_priority_fee_per_gas + potential_max_fee
-    } else {
-        potential_max_fee
-    };
+/// Maps a transaction that was rejected before it got a nonce, or whose revert was replayed, to
+/// the result of the registration.
+fn result_from_prepare(e: PrepareError) -> anyhow::Result<RegisterResult> {
+    match e {
+        PrepareError::Reverted(data) => {
+            let reason = match decode_revert(&Abi::default(), &data) {
+                Some(revert) => revert.to_string(),
+                None => data.to_string(),
+            };
+            Ok(RegisterResult::Failure(format!(
+                "register reverted: {}",
+                reason
+            )))
+        }
+        e @ PrepareError::InsufficientFunds { .. } => {
+            Ok(RegisterResult::InsufficientFunds(e.to_string()))
+        }
+        e => Err(e.into()),
+    }
+}
 
-    Ok((max_priority_fee_per_gas, max_fee_per_gas))
+/// Returns the batcher that coalesces register requests into multicall transactions.
+pub fn register_batcher(
+    config: BatchConfig,
+    pool: Arc<SignerPool>,
+    monitor: TxMonitor,
+) -> Batcher<Address> {
+    Batcher::spawn("register", config, move |requests| {
+        register_batch(pool.clone(), monitor.clone(), requests)
+    })
 }
 
-/// Implementation borrowed from
-/// https://github.com/gakonst/ethers-rs/blob/ethers-v2.0.8/ethers-core/src/utils/mod.rs#L582
-/// Refer to the implementation for unit tests
-fn base_fee_surged(base_fee_per_gas: U256) -> U256 {
-    if base_fee_per_gas <= U256::from(40_000_000_000u64) {
-        base_fee_per_gas * 2
-    } else if base_fee_per_gas <= U256::from(100_000_000_000u64) {
-        base_fee_per_gas * 16 / 10
-    } else if base_fee_per_gas <= U256::from(200_000_000_000u64) {
-        base_fee_per_gas * 14 / 10
+/// Registers an address as part of the next batched transaction.
+async fn register_batched(
+    batcher: &Batcher<Address>,
+    to_address: Address,
+    wait: Option<bool>,
+    entry: &mut LedgerEntry,
+) -> anyhow::Result<RegisterResult> {
+    let ticket = match batcher.submit(to_address, entry.clone()).await {
+        Ok(ticket) => ticket,
+        Err(e) => {
+            return match e.downcast::<PrepareError>() {
+                Ok(e) => result_from_prepare(e),
+                Err(e) => Err(e),
+            }
+        }
+    };
+    entry.tx_hash = Some(ticket.tx_hash);
+    entry.batch_index = Some(ticket.index as u32);
+    if wait.unwrap_or(true) {
+        let Ok(outcome) = timeout(MAX_WAIT, ticket.outcome).await else {
+            return Ok(RegisterResult::Pending(ticket.tx_hash));
+        };
+        let receipt = outcome
+            .map_err(|_| anyhow!("register did not return a receipt"))??
+            .receipt;
+        // Batch calls can't fail on their own, so a reverted batch failed every registration.
+        if receipt.status != Some(1u64.into()) {
+            return Ok(RegisterResult::Failure(format!(
+                "register batch {:?} reverted",
+                receipt.transaction_hash
+            )));
+        }
+        Ok(RegisterResult::Success(receipt.transaction_hash))
     } else {
-        base_fee_per_gas * 12 / 10
+        Ok(RegisterResult::Pending(ticket.tx_hash))
     }
 }
 
-/// Implementation borrowed from
-/// https://github.com/gakonst/ethers-rs/blob/ethers-v2.0.8/ethers-core/src/utils/mod.rs#L536
-/// Refer to the implementation for unit tests
-fn estimate_priority_fee(rewards: Vec<Vec<U256>>) -> U256 {
-    let mut rewards: Vec<U256> = rewards
+/// Registers a batch of addresses with a single Multicall3 transaction that sends a zero-value
+/// call to each of them.
+async fn register_batch(
+    pool: Arc<SignerPool>,
+    monitor: TxMonitor,
+    requests: Vec<BatchRequest<Address>>,
+) {
+    let calls = requests
         .iter()
-        .map(|r| r[0])
-        .filter(|r| *r > U256::zero())
-        .collect();
-    if rewards.is_empty() {
-        return U256::zero();
-    }
-    if rewards.len() == 1 {
-        return rewards[0];
-    }
-    // Sort the rewards as we will eventually take the median.
-    rewards.sort();
-
-    // A copy of the same vector is created for convenience to calculate percentage change
-    // between subsequent fee values.
-    let mut rewards_copy = rewards.clone();
-    rewards_copy.rotate_left(1);
-
-    let mut percentage_change: Vec<I256> = rewards
-        .iter()
-        .zip(rewards_copy.iter())
-        .map(|(a, b)| {
-            let a = I256::try_from(*a).expect("priority fee overflow");
-            let b = I256::try_from(*b).expect("priority fee overflow");
-            ((b - a) * 100) / a
+        .map(|request| Call3Value {
+            target: request.call,
+            allow_failure: false,
+            value: U256::zero(),
+            call_data: Bytes::new(),
         })
         .collect();
-    percentage_change.pop();
-
-    // Fetch the max of the percentage change, and that element's index.
-    let max_change = percentage_change.iter().max().unwrap();
-    let max_change_index = percentage_change
-        .iter()
-        .position(|&c| c == *max_change)
-        .unwrap();
-
-    // If we encountered a big change in fees at a certain position, then consider only
-    // the values >= it.
-    let values = if *max_change >= ethers::utils::EIP1559_FEE_ESTIMATION_THRESHOLD_MAX_CHANGE.into()
-        && (max_change_index >= (rewards.len() / 2))
-    {
-        rewards[max_change_index..].to_vec()
-    } else {
-        rewards
-    };
-
-    // Return the median.
-    values[values.len() / 2]
+    let signer = pool.acquire();
+    let multicall = signer.multicall.clone();
+    send_batch(signer, multicall, &monitor, requests, calls).await
 }
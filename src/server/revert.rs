@@ -0,0 +1,191 @@
+use std::fmt;
+
+use ethers::abi::ethabi::AbiError;
+use ethers::abi::{Abi, Param, ParamType, Token};
+use ethers::prelude::{Bytes, Http, Middleware, Provider, RpcError, TransactionReceipt};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+/// `Error(string)`, raised by `require` and `revert` with a message.
+static ERROR_STRING: Lazy<AbiError> = Lazy::new(|| builtin("Error", "message", ParamType::String));
+
+/// `Panic(uint256)`, raised by failed assertions, overflows and similar.
+static PANIC: Lazy<AbiError> = Lazy::new(|| builtin("Panic", "code", ParamType::Uint(256)));
+
+fn builtin(name: &str, param: &str, kind: ParamType) -> AbiError {
+    AbiError {
+        name: name.to_string(),
+        inputs: vec![Param {
+            name: param.to_string(),
+            kind,
+            internal_type: None,
+        }],
+    }
+}
+
+/// Decoded revert of a contract call.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Revert {
+    /// Name of the error, e.g. `TryLater` or `Error`.
+    pub error: String,
+    /// Error arguments by parameter name, or by position for unnamed parameters.
+    pub args: Map<String, Value>,
+}
+
+impl fmt::Display for Revert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args: Vec<String> = self
+            .args
+            .iter()
+            .map(|(name, value)| match value {
+                Value::String(value) => format!("{}: {}", name, value),
+                value => format!("{}: {}", name, value),
+            })
+            .collect();
+        write!(f, "{}({})", self.error, args.join(", "))?;
+        if self.error == PANIC.name {
+            if let Some(reason) = self.args.get("code").and_then(panic_reason) {
+                write!(f, ": {}", reason)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes revert data with the custom errors of `abi`, `Error(string)` and `Panic(uint256)`.
+/// Returns `None` if the selector is unknown or the arguments don't decode.
+pub fn decode_revert(abi: &Abi, data: &[u8]) -> Option<Revert> {
+    let (selector, encoded) = (data.get(..4)?, &data[4..]);
+    let error = [&*ERROR_STRING, &*PANIC]
+        .into_iter()
+        .chain(abi.errors())
+        .find(|error| &error.signature()[..4] == selector)?;
+    let tokens = error.decode(encoded).ok()?;
+    let args = error
+        .inputs
+        .iter()
+        .zip(tokens)
+        .enumerate()
+        .map(|(i, (param, token))| {
+            let name = if param.name.is_empty() {
+                i.to_string()
+            } else {
+                param.name.clone()
+            };
+            (name, token_to_json(token))
+        })
+        .collect();
+    Some(Revert {
+        error: error.name.clone(),
+        args,
+    })
+}
+
+/// Returns the revert data of a mined transaction that reverted, by replaying it with
+/// `eth_call` against the state of its block. Returns `None` if the replay doesn't revert or
+/// the node doesn't report revert data.
+pub async fn replay_revert(
+    provider: &Provider<Http>,
+    tx: &TypedTransaction,
+    receipt: &TransactionReceipt,
+) -> Option<Bytes> {
+    let block = receipt.block_number?;
+    let err = provider.call(tx, Some(block.into())).await.err()?;
+    err.as_error_response()?.as_revert_data()
+}
+
+/// Converts an ABI token to JSON. Integers are decimal strings, since they may not fit in a
+/// JSON number, and bytes are `0x` hex strings.
+fn token_to_json(token: Token) -> Value {
+    match token {
+        Token::Address(address) => json!(address),
+        Token::FixedBytes(bytes) | Token::Bytes(bytes) => {
+            json!(format!("0x{}", hex::encode(bytes)))
+        }
+        Token::Int(n) => json!(ethers::types::I256::from_raw(n).to_string()),
+        Token::Uint(n) => json!(n.to_string()),
+        Token::Bool(b) => json!(b),
+        Token::String(s) => json!(s),
+        Token::FixedArray(tokens) | Token::Array(tokens) | Token::Tuple(tokens) => {
+            Value::Array(tokens.into_iter().map(token_to_json).collect())
+        }
+    }
+}
+
+/// Describes the panic codes emitted by the Solidity compiler.
+fn panic_reason(code: &Value) -> Option<&'static str> {
+    let reason = match code.as_str()?.parse::<u64>().ok()? {
+        0x01 => "assertion failed",
+        0x11 => "arithmetic overflow or underflow",
+        0x12 => "division or modulo by zero",
+        0x21 => "invalid enum value",
+        0x22 => "invalid storage byte array encoding",
+        0x31 => "pop on empty array",
+        0x32 => "array index out of bounds",
+        0x41 => "out of memory",
+        0x51 => "call to uninitialized function",
+        _ => return None,
+    };
+    Some(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::abi::{encode, short_signature};
+    use ethers::prelude::{Address, U256};
+
+    use super::*;
+    use crate::server::shared::FAUCETCONTRACT_ABI;
+
+    fn revert_data(name: &str, params: &[ParamType], tokens: &[Token]) -> Vec<u8> {
+        let mut data = short_signature(name, params).to_vec();
+        data.extend(encode(tokens));
+        data
+    }
+
+    #[test]
+    fn decodes_faucet_errors() {
+        let revert = decode_revert(&FAUCETCONTRACT_ABI, &revert_data("TryLater", &[], &[]));
+        assert_eq!(revert.unwrap().to_string(), "TryLater()");
+
+        let account = Address::repeat_byte(0xab);
+        let data = revert_data(
+            "OwnableUnauthorizedAccount",
+            &[ParamType::Address],
+            &[Token::Address(account)],
+        );
+        let revert = decode_revert(&FAUCETCONTRACT_ABI, &data).unwrap();
+        assert_eq!(revert.error, "OwnableUnauthorizedAccount");
+        assert_eq!(revert.args["account"], json!(account));
+    }
+
+    #[test]
+    fn decodes_builtin_errors() {
+        let data = revert_data(
+            "Error",
+            &[ParamType::String],
+            &[Token::String("not allowed".to_string())],
+        );
+        let revert = decode_revert(&FAUCETCONTRACT_ABI, &data).unwrap();
+        assert_eq!(revert.to_string(), "Error(message: not allowed)");
+
+        let data = revert_data(
+            "Panic",
+            &[ParamType::Uint(256)],
+            &[Token::Uint(U256::from(0x11))],
+        );
+        let revert = decode_revert(&FAUCETCONTRACT_ABI, &data).unwrap();
+        assert_eq!(
+            revert.to_string(),
+            "Panic(code: 17): arithmetic overflow or underflow"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_errors() {
+        assert_eq!(decode_revert(&FAUCETCONTRACT_ABI, &[1, 2, 3, 4]), None);
+        assert_eq!(decode_revert(&FAUCETCONTRACT_ABI, &[1, 2]), None);
+    }
+}
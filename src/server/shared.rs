@@ -1,30 +1,78 @@
 use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use cf_turnstile::TurnstileClient;
+use ethers::contract::multicall_contract::Multicall3;
 use ethers::prelude::{
-    abigen, k256::ecdsa::SigningKey, Http, NonceManagerMiddleware, Provider, SignerMiddleware,
-    Wallet,
+    abigen, k256::ecdsa::SigningKey, Http, Provider, SignerMiddleware, Wallet, U256,
 };
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
 use serde::{Deserialize, Serialize};
+
+use crate::server::batch::Batcher;
+use crate::server::budget::SpendingBudget;
+use crate::server::challenge::{ChallengeIssuer, ChallengeSolution};
+use crate::server::faucet::FaucetAbi;
+use crate::server::keys::{DripSignals, KeyDeriver};
+use crate::server::ledger::Ledger;
+use crate::server::lotus::NativeSender;
+use crate::server::monitor::TxMonitor;
+use crate::server::pool::SignerPool;
+use crate::server::ratelimit::RateLimiter;
+use crate::server::revert::Revert;
+use crate::server::state::SharedState;
+use crate::server::verifier::HumanVerifier;
+use warp::http::header::{HeaderValue, RETRY_AFTER};
 use warp::{http::StatusCode, Filter, Rejection, Reply};
 
-abigen!(
-    FaucetContract,
-    r#"[{"name": "drip","type": "function","inputs": [{"name": "recipient","type": "address","internalType": "address payable"}, {"internalType":"string[]","name":"keys","type":"string[]"}],"outputs": [],"stateMutability": "nonpayable"}]"#
-);
+abigen!(FaucetContract, "abi/Faucet.json");
 
-pub type DefaultSignerMiddleware =
-    SignerMiddleware<NonceManagerMiddleware<Provider<Http>>, Wallet<SigningKey>>;
+pub type DefaultSignerMiddleware = SignerMiddleware<Provider<Http>, Wallet<SigningKey>>;
 pub type Faucet = FaucetContract<DefaultSignerMiddleware>;
+pub type Multicall = Multicall3<DefaultSignerMiddleware>;
+
+/// Time an idempotency key keeps the response of its first successful request.
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Time the response of an in-flight request stays available to requests that attached to it.
+const IN_FLIGHT_RESULT_TTL: Duration = Duration::from_secs(5);
+
+/// Time a claim is held if its request never completes, e.g. because the replica stopped.
+const CLAIM_TTL: Duration = Duration::from_secs(300);
+
+/// Maximum time to wait for the request holding a claim before giving up.
+const CLAIM_WAIT: Duration = Duration::from_secs(120);
+
+const CLAIM_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Maximum time a request waits for its transaction or message to be included before it's
+/// answered as pending. Below `CLAIM_WAIT`, so requests attached to it get its response.
+pub const MAX_WAIT: Duration = Duration::from_secs(90);
+
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+lazy_static! {
+    static ref DEDUPLICATED_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "deduplicated_requests_total",
+        "Number of requests answered with the response of an earlier or concurrent request, by route and kind.",
+        &["route", "kind"]
+    )
+    .unwrap();
+}
 
 /// Drip request.
 #[derive(Deserialize)]
 pub struct DripRequest {
     /// The address to send the drip to.
     pub address: String,
-    /// The Cloudflare Turnstile response to validate.
-    pub ts_response: String,
+    /// The captcha (or proof-of-work) response to validate. Accepts the Cloudflare Turnstile
+    /// specific `ts_response` for compatibility.
+    #[serde(alias = "ts_response", default)]
+    pub captcha_response: String,
+    /// A solved proof-of-work challenge, accepted instead of a captcha response.
+    pub challenge: Option<ChallengeSolution>,
     /// Whether to wait for the transaction to complete.
     /// Default is true.
     pub wait: Option<bool>,
@@ -34,9 +82,10 @@ impl std::fmt::Display for DripRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "address: {}, ts_response: {}, wait: {}",
+            "address: {}, captcha_response: {}, challenge: {}, wait: {}",
             self.address,
-            self.ts_response,
+            self.captcha_response,
+            self.challenge.is_some(),
             self.wait.unwrap_or(true)
         )
     }
@@ -71,12 +120,75 @@ pub struct BadRequest {
 
 impl warp::reject::Reject for BadRequest {}
 
+/// Human verification (captcha or proof-of-work) failure.
+#[derive(Clone, Debug)]
+pub struct VerificationFailed {
+    /// Short kebab-case reason code, e.g. `hostname-mismatch`.
+    pub reason: &'static str,
+    pub message: String,
+}
+
+impl warp::reject::Reject for VerificationFailed {}
+
 /// Too many requests error.
 #[derive(Clone, Debug)]
-pub struct TooManyRequests {}
+pub struct TooManyRequests {
+    /// Seconds until the request may be retried, sent as `Retry-After`.
+    pub retry_after: Option<u64>,
+}
 
 impl warp::reject::Reject for TooManyRequests {}
 
+/// Rejection for requests the target address isn't eligible for.
+#[derive(Debug)]
+pub struct NotEligible {
+    /// Short kebab-case reason code, e.g. `balance-too-high`.
+    pub reason: &'static str,
+    pub message: String,
+}
+
+impl warp::reject::Reject for NotEligible {}
+
+/// Conflicting request error, e.g. a duplicate of a request that is still in progress.
+#[derive(Clone, Debug)]
+pub struct Conflict {
+    pub message: String,
+}
+
+impl warp::reject::Reject for Conflict {}
+
+/// Unauthorized error.
+#[derive(Clone, Debug)]
+pub struct Unauthorized {}
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Reverted contract call, with its decoded error.
+#[derive(Clone, Debug)]
+pub struct ContractReverted {
+    pub revert: Revert,
+    pub message: String,
+}
+
+impl warp::reject::Reject for ContractReverted {}
+
+/// The service can't serve requests for now, with a machine-readable reason.
+#[derive(Clone, Debug)]
+pub struct Unavailable {
+    pub reason: &'static str,
+    pub message: String,
+}
+
+impl warp::reject::Reject for Unavailable {}
+
+/// An upstream node or service failed or returned something unexpected.
+#[derive(Clone, Debug)]
+pub struct BadGateway {
+    pub message: String,
+}
+
+impl warp::reject::Reject for BadGateway {}
+
 /// Faucet empty error.
 #[derive(Clone, Debug)]
 pub struct FaucetEmpty {}
@@ -88,19 +200,48 @@ impl warp::reject::Reject for FaucetEmpty {}
 struct ErrorMessage {
     code: u16,
     message: String,
+    /// Machine-readable error reason, for errors that have one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Arguments of a decoded contract error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<serde_json::Value>,
 }
 
 /// Rejection handler.
 pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let mut error = None;
+    let mut args = None;
+    let mut retry_after = None;
     let (code, message) = if err.is_not_found() {
         (StatusCode::NOT_FOUND, "not found".to_string())
+    } else if let Some(e) = err.find::<VerificationFailed>() {
+        error = Some(e.reason.to_string());
+        (StatusCode::BAD_REQUEST, e.message.clone())
+    } else if let Some(e) = err.find::<NotEligible>() {
+        error = Some(e.reason.to_string());
+        (StatusCode::FORBIDDEN, e.message.clone())
+    } else if let Some(e) = err.find::<ContractReverted>() {
+        error = Some(e.revert.error.clone());
+        args = Some(serde_json::Value::Object(e.revert.args.clone()));
+        (StatusCode::BAD_REQUEST, e.message.clone())
     } else if let Some(e) = err.find::<BadRequest>() {
         (StatusCode::BAD_REQUEST, e.message.clone())
-    } else if err.find::<TooManyRequests>().is_some() {
+    } else if let Some(e) = err.find::<TooManyRequests>() {
+        retry_after = e.retry_after;
         (
             StatusCode::TOO_MANY_REQUESTS,
             "too many requests".to_string(),
         )
+    } else if let Some(e) = err.find::<Conflict>() {
+        (StatusCode::CONFLICT, e.message.clone())
+    } else if err.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "unauthorized".to_string())
+    } else if let Some(e) = err.find::<Unavailable>() {
+        error = Some(e.reason.to_string());
+        (StatusCode::SERVICE_UNAVAILABLE, e.message.clone())
+    } else if let Some(e) = err.find::<BadGateway>() {
+        (StatusCode::BAD_GATEWAY, e.message.clone())
     } else if err.find::<FaucetEmpty>().is_some() {
         (StatusCode::SERVICE_UNAVAILABLE, "faucet empty".to_string())
     } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
@@ -125,8 +266,15 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
     let reply = warp::reply::json(&ErrorMessage {
         code: code.as_u16(),
         message,
+        error,
+        args,
     });
-    Ok(warp::reply::with_status(reply, code))
+    let mut res = warp::reply::with_status(reply, code).into_response();
+    if let Some(retry_after) = retry_after {
+        res.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+    Ok(res)
 }
 
 /// Filter to pass the client to the request handler.
@@ -136,14 +284,392 @@ pub fn with_client(
     warp::any().map(move || client.clone())
 }
 
-/// Filter to pass the faucet to the request handler.
-pub fn with_faucet(faucet: Faucet) -> impl Filter<Extract = (Faucet,), Error = Infallible> + Clone {
-    warp::any().map(move || faucet.clone())
+/// Filter to pass the signer pool to the request handler.
+pub fn with_pool(
+    pool: Arc<SignerPool>,
+) -> impl Filter<Extract = (Arc<SignerPool>,), Error = Infallible> + Clone {
+    warp::any().map(move || pool.clone())
+}
+
+/// Filter to pass the proof-of-work challenge issuer to the request handler.
+pub fn with_challenges(
+    challenges: Option<Arc<ChallengeIssuer>>,
+) -> impl Filter<Extract = (Option<Arc<ChallengeIssuer>>,), Error = Infallible> + Clone {
+    warp::any().map(move || challenges.clone())
+}
+
+/// Filter to pass the rate limiter to the request handler.
+pub fn with_rate_limiter(
+    limiter: Arc<RateLimiter>,
+) -> impl Filter<Extract = (Arc<RateLimiter>,), Error = Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
+
+/// Filter to pass the human verifier to the request handler.
+pub fn with_verifier(
+    verifier: Arc<dyn HumanVerifier>,
+) -> impl Filter<Extract = (Arc<dyn HumanVerifier>,), Error = Infallible> + Clone {
+    warp::any().map(move || verifier.clone())
+}
+
+/// Filter to pass the request deduplicator to the request handler.
+pub fn with_dedupe(
+    dedupe: RequestDedupe,
+) -> impl Filter<Extract = (RequestDedupe,), Error = Infallible> + Clone {
+    warp::any().map(move || dedupe.clone())
+}
+
+/// Extracts the optional `Idempotency-Key` header.
+pub fn idempotency_key() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("idempotency-key").and_then(|key: Option<String>| async move {
+        match key {
+            Some(key)
+                if key.is_empty()
+                    || key.len() > MAX_IDEMPOTENCY_KEY_LEN
+                    || !key.bytes().all(|b| b.is_ascii_graphic()) =>
+            {
+                Err(warp::reject::custom(BadRequest {
+                    message: format!(
+                        "Idempotency-Key must be 1 to {} printable ASCII characters",
+                        MAX_IDEMPOTENCY_KEY_LEN
+                    ),
+                }))
+            }
+            key => Ok(key),
+        }
+    })
+}
+
+/// Extracts the request signals faucet keys can be derived from. `X-User-Id` and `X-Api-Key-Id`
+/// are set by an authenticating proxy, so they're ignored unless the request comes from one of
+/// the trusted proxies.
+pub fn drip_signals(
+    trusted_proxy_ips: Vec<IpAddr>,
+) -> impl Filter<Extract = (DripSignals,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::header::optional::<String>("x-device-fingerprint"))
+        .and(warp::header::optional::<String>("x-user-id"))
+        .and(warp::header::optional::<String>("x-api-key-id"))
+        .map(
+            move |remote: Option<SocketAddr>,
+                  user_agent: Option<String>,
+                  device: Option<String>,
+                  user_id: Option<String>,
+                  api_key_id: Option<String>| {
+                let trusted = remote.is_some_and(|r| trusted_proxy_ips.contains(&r.ip()));
+                let value =
+                    |v: Option<String>| v.map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+                DripSignals {
+                    user_agent: value(user_agent),
+                    device: value(device),
+                    user_id: value(user_id).filter(|_| trusted),
+                    api_key_id: value(api_key_id).filter(|_| trusted),
+                }
+            },
+        )
+}
+
+/// Filter to pass the optional native message sender to the request handler.
+pub fn with_native(
+    native: Option<Arc<NativeSender>>,
+) -> impl Filter<Extract = (Option<Arc<NativeSender>>,), Error = Infallible> + Clone {
+    warp::any().map(move || native.clone())
+}
+
+/// Filter to pass the maximum drip recipient balance to the request handler.
+pub fn with_max_balance(
+    max_balance: Option<U256>,
+) -> impl Filter<Extract = (Option<U256>,), Error = Infallible> + Clone {
+    warp::any().map(move || max_balance)
+}
+
+/// Filter to pass the faucet ABI to the request handler.
+pub fn with_faucet_abi(
+    faucet_abi: Arc<FaucetAbi>,
+) -> impl Filter<Extract = (Arc<FaucetAbi>,), Error = Infallible> + Clone {
+    warp::any().map(move || faucet_abi.clone())
+}
+
+/// Filter to pass the faucet key deriver to the request handler.
+pub fn with_key_deriver(
+    keys: Arc<KeyDeriver>,
+) -> impl Filter<Extract = (Arc<KeyDeriver>,), Error = Infallible> + Clone {
+    warp::any().map(move || keys.clone())
+}
+
+/// Filter to pass the ledger to the request handler.
+pub fn with_ledger(ledger: Ledger) -> impl Filter<Extract = (Ledger,), Error = Infallible> + Clone {
+    warp::any().map(move || ledger.clone())
+}
+
+/// Filter to pass the spending budget to the request handler.
+pub fn with_budget(
+    budget: Arc<SpendingBudget>,
+) -> impl Filter<Extract = (Arc<SpendingBudget>,), Error = Infallible> + Clone {
+    warp::any().map(move || budget.clone())
+}
+
+/// Filter to pass the transaction monitor to the request handler.
+pub fn with_monitor(
+    monitor: TxMonitor,
+) -> impl Filter<Extract = (TxMonitor,), Error = Infallible> + Clone {
+    warp::any().map(move || monitor.clone())
+}
+
+/// Filter to pass an optional request batcher to the request handler.
+pub fn with_batcher<T: Send + 'static>(
+    batcher: Option<Batcher<T>>,
+) -> impl Filter<Extract = (Option<Batcher<T>>,), Error = Infallible> + Clone {
+    warp::any().map(move || batcher.clone())
+}
+
+/// Filter that only passes requests carrying the admin bearer token.
+/// Admin endpoints are hidden entirely if no token is configured.
+pub fn with_admin_auth(
+    admin_token: Option<String>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let admin_token = admin_token.clone();
+            async move {
+                let Some(admin_token) = admin_token else {
+                    return Err(warp::reject::not_found());
+                };
+                let token = header.as_deref().and_then(|h| h.strip_prefix("Bearer "));
+                if token == Some(admin_token.as_str()) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized {}))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Deduplicates requests that send transactions, by `Idempotency-Key` header and by target
+/// address.
+///
+/// Both claim a key in the shared state. The request holding a claim stores its response there
+/// once it succeeds; repeated or concurrent requests wait for that response and return it
+/// instead of sending another transaction. Claims of failed requests are released, so that a
+/// retry runs again.
+#[derive(Clone)]
+pub struct RequestDedupe {
+    state: Arc<SharedState>,
+}
+
+/// Outcome of claiming a request key.
+pub enum Claim {
+    /// An earlier or concurrent request already produced this response.
+    Done(serde_json::Value),
+    /// The request holds the key, see [`ClaimGuard::complete`].
+    Held(ClaimGuard),
+}
+
+/// Claim record kept in the shared state.
+#[derive(Serialize, Deserialize)]
+struct ClaimRecord {
+    /// Random id of the request holding the claim.
+    owner: String,
+    address: String,
+    response: Option<serde_json::Value>,
 }
 
-/// Filter to pass the Cloudflare Turnstile client to the request handler.
-pub fn with_turnstile(
-    turnstile: Arc<TurnstileClient>,
-) -> impl Filter<Extract = (Arc<TurnstileClient>,), Error = Infallible> + Clone {
-    warp::any().map(move || turnstile.clone())
+impl RequestDedupe {
+    pub fn new(state: Arc<SharedState>) -> Self {
+        Self { state }
+    }
+
+    /// Claims an idempotency key for a request to `address`, in canonical form, or returns the
+    /// response of the request that used the key first. Requests without a key return `None`.
+    ///
+    /// Keys are scoped to the client IP, so that other clients can't read or block a response
+    /// by guessing its key.
+    pub async fn claim_idempotency_key(
+        &self,
+        route: &'static str,
+        key: Option<String>,
+        client_ip: Option<IpAddr>,
+        address: &str,
+    ) -> Result<Option<Claim>, Rejection> {
+        let Some(key) = key else {
+            return Ok(None);
+        };
+        let client = client_ip.map_or("unknown".to_string(), |ip| ip.to_string());
+        let claim = self
+            .claim(
+                route,
+                "idempotency",
+                format!("idempotency:{}:{}:{}", route, client, key),
+                address,
+                IDEMPOTENCY_TTL,
+            )
+            .await?;
+        Ok(Some(claim))
+    }
+
+    /// Claims a target address for a request, or attaches to the in-flight request for the
+    /// same address and returns its response.
+    pub async fn claim_address(
+        &self,
+        route: &'static str,
+        address: &str,
+    ) -> Result<Claim, Rejection> {
+        self.claim(
+            route,
+            "in_flight",
+            format!("inflight:{}:{}", route, address),
+            address,
+            IN_FLIGHT_RESULT_TTL,
+        )
+        .await
+    }
+
+    async fn claim(
+        &self,
+        route: &'static str,
+        kind: &'static str,
+        key: String,
+        address: &str,
+        result_ttl: Duration,
+    ) -> Result<Claim, Rejection> {
+        let pending = serde_json::to_string(&ClaimRecord {
+            owner: uuid::Uuid::new_v4().to_string(),
+            address: address.to_string(),
+            response: None,
+        })
+        .expect("claim record serializes");
+        let deadline = Instant::now() + CLAIM_WAIT;
+        loop {
+            if self.state.try_insert(&key, &pending, CLAIM_TTL).await {
+                return Ok(Claim::Held(ClaimGuard {
+                    state: self.state.clone(),
+                    key,
+                    pending,
+                    address: address.to_string(),
+                    result_ttl,
+                    completed: false,
+                }));
+            }
+            let record = self
+                .state
+                .get(&key)
+                .await
+                .and_then(|value| serde_json::from_str::<ClaimRecord>(&value).ok());
+            if let Some(record) = record {
+                if record.address != address {
+                    return Err(warp::reject::custom(BadRequest {
+                        message: "Idempotency-Key was already used for a different address"
+                            .to_string(),
+                    }));
+                }
+                if let Some(response) = record.response {
+                    DEDUPLICATED_REQUESTS
+                        .with_label_values(&[route, kind])
+                        .inc();
+                    return Ok(Claim::Done(response));
+                }
+            }
+            if Instant::now() > deadline {
+                return Err(warp::reject::custom(Conflict {
+                    message: "a request for the same key or address is still in progress"
+                        .to_string(),
+                }));
+            }
+            tokio::time::sleep(CLAIM_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Completes the held claims of a successful request with its response and replies with it.
+pub async fn complete_claims(
+    idempotency: Option<ClaimGuard>,
+    in_flight: Option<ClaimGuard>,
+    body: serde_json::Value,
+) -> warp::reply::Json {
+    for guard in idempotency.into_iter().chain(in_flight) {
+        guard.complete(&body).await;
+    }
+    warp::reply::json(&body)
+}
+
+/// A held claim. Dropping it without completing releases the claim.
+pub struct ClaimGuard {
+    state: Arc<SharedState>,
+    key: String,
+    pending: String,
+    address: String,
+    result_ttl: Duration,
+    completed: bool,
+}
+
+impl ClaimGuard {
+    /// Stores the response of the request for repeated and waiting requests.
+    pub async fn complete(mut self, response: &serde_json::Value) {
+        let record = ClaimRecord {
+            owner: String::new(),
+            address: self.address.clone(),
+            response: Some(response.clone()),
+        };
+        let record = serde_json::to_string(&record).expect("claim record serializes");
+        self.state.insert(&self.key, &record, self.result_ttl).await;
+        self.completed = true;
+    }
+}
+
+impl Drop for ClaimGuard {
+    fn drop(&mut self) {
+        if !self.completed {
+            let state = self.state.clone();
+            let key = std::mem::take(&mut self.key);
+            let pending = std::mem::take(&mut self.pending);
+            tokio::spawn(async move { state.remove_if(&key, &pending).await });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(claim: Option<Claim>) -> ClaimGuard {
+        match claim {
+            Some(Claim::Held(guard)) => guard,
+            _ => panic!("expected a held claim"),
+        }
+    }
+
+    #[tokio::test]
+    async fn scopes_idempotency_keys_to_clients() {
+        let dedupe = RequestDedupe::new(Arc::new(SharedState::memory()));
+        let key = || Some("key".to_string());
+        let (a, b): (IpAddr, IpAddr) = ([192, 0, 2, 1].into(), [198, 51, 100, 1].into());
+
+        let guard = held(
+            dedupe
+                .claim_idempotency_key("drip", key(), Some(a), "0xa")
+                .await
+                .unwrap(),
+        );
+        guard.complete(&serde_json::json!({"tx": 1})).await;
+
+        let repeat = dedupe
+            .claim_idempotency_key("drip", key(), Some(a), "0xa")
+            .await
+            .unwrap();
+        assert!(matches!(repeat, Some(Claim::Done(body)) if body["tx"] == 1));
+        // Another client using the same key neither sees the response nor conflicts with it.
+        held(
+            dedupe
+                .claim_idempotency_key("drip", key(), Some(b), "0xb")
+                .await
+                .unwrap(),
+        );
+        assert!(dedupe
+            .claim_idempotency_key("drip", None, Some(a), "0xa")
+            .await
+            .unwrap()
+            .is_none());
+    }
 }
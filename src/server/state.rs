@@ -0,0 +1,616 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use ethers::prelude::U256;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{register_int_counter_vec, register_int_gauge, IntCounterVec, IntGauge};
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::{RedisError, Script};
+
+use crate::server::ratelimit::Quota;
+
+/// Prefix of every key written to Redis.
+const KEY_PREFIX: &str = "registrar:";
+
+/// Timeout for connecting to Redis and for each command.
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Time to use the in-memory state after Redis failed before trying Redis again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of in-memory operations between sweeps of expired state.
+const SWEEP_INTERVAL: u64 = 1024;
+
+lazy_static! {
+    static ref STATE_FALLBACKS: IntCounterVec = register_int_counter_vec!(
+        "state_backend_fallbacks_total",
+        "Number of state operations served from memory because Redis was unavailable.",
+        &["op"]
+    )
+    .unwrap();
+    static ref STATE_REDIS_UP: IntGauge = register_int_gauge!(
+        "state_backend_redis_up",
+        "Whether the last Redis state operation succeeded."
+    )
+    .unwrap();
+
+    /// Takes a token from every bucket in KEYS if each has one left. ARGV holds the requests and
+    /// window in milliseconds of every bucket. Returns the 1-based index of the bucket that ran
+    /// out and the milliseconds until it has a token again, or `{0, 0}` if admitted.
+    static ref TAKE_TOKENS: Script = Script::new(
+        r"
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+        local levels = {}
+        local worst, wait = 0, 0
+        for i, key in ipairs(KEYS) do
+            local capacity = tonumber(ARGV[2 * i - 1])
+            local rate = capacity / tonumber(ARGV[2 * i])
+            local bucket = redis.call('HMGET', key, 'tokens', 'updated')
+            local tokens = tonumber(bucket[1]) or capacity
+            local updated = tonumber(bucket[2]) or now
+            tokens = math.min(capacity, tokens + math.max(0, now - updated) * rate)
+            levels[i] = tokens
+            if tokens < 1 and (1 - tokens) / rate > wait then
+                worst, wait = i, (1 - tokens) / rate
+            end
+        end
+        if worst > 0 then
+            return {worst, math.ceil(wait)}
+        end
+        for i, key in ipairs(KEYS) do
+            redis.call('HSET', key, 'tokens', tostring(levels[i] - 1), 'updated', now)
+            redis.call('PEXPIRE', key, ARGV[2 * i])
+        end
+        return {0, 0}
+        "
+    );
+
+    /// Adds ARGV[2], a decimal amount, to the fixed window counter in KEYS[1] that lasts ARGV[1]
+    /// milliseconds, starting a new window if the current one has passed. Returns the amount
+    /// used in the window, as a decimal string, and the milliseconds until it passes. Amounts
+    /// are added as strings, since they may not fit in a Lua number.
+    static ref ADD_TO_WINDOW: Script = Script::new(
+        r"
+        local function add(a, b)
+            local digits, carry = {}, 0
+            local i, j = #a, #b
+            while i > 0 or j > 0 or carry > 0 do
+                local sum = carry
+                if i > 0 then sum, i = sum + tonumber(a:sub(i, i)), i - 1 end
+                if j > 0 then sum, j = sum + tonumber(b:sub(j, j)), j - 1 end
+                table.insert(digits, 1, sum % 10)
+                carry = math.floor(sum / 10)
+            end
+            return table.concat(digits)
+        end
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+        local length = tonumber(ARGV[1])
+        local window = redis.call('HMGET', KEYS[1], 'started', 'used')
+        local started, used = tonumber(window[1]), window[2]
+        if not started or not used or now - started >= length then
+            started, used = now, '0'
+        end
+        used = add(used, ARGV[2])
+        redis.call('HSET', KEYS[1], 'started', started, 'used', used)
+        redis.call('PEXPIRE', KEYS[1], started + length - now)
+        return {used, started + length - now}
+        "
+    );
+
+    /// Deletes KEYS[1] if it holds ARGV[1].
+    static ref REMOVE_IF: Script = Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            return redis.call('DEL', KEYS[1])
+        end
+        return 0
+        "
+    );
+}
+
+/// Where rate-limit counters, idempotency records and in-flight locks are kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum StateBackend {
+    /// Process memory, not shared between replicas.
+    #[default]
+    Memory,
+    /// Redis server shared by all replicas, see `--redis-url`.
+    Redis,
+}
+
+/// Short-lived state shared between replicas.
+///
+/// With the Redis backend, operations that fail because Redis is unreachable are served from
+/// process memory instead, and Redis is tried again after a few seconds. Limits and locks are
+/// then only enforced per replica until Redis is back.
+pub struct SharedState {
+    redis: Option<RedisState>,
+    memory: MemoryState,
+}
+
+impl SharedState {
+    /// Returns state kept in process memory.
+    pub fn memory() -> Self {
+        Self {
+            redis: None,
+            memory: MemoryState::default(),
+        }
+    }
+
+    /// Returns state kept in Redis at the given URL, e.g. `redis://127.0.0.1:6379/0`.
+    ///
+    /// Only the URL is checked here, the connection is made on first use.
+    pub fn redis(url: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            redis: Some(RedisState {
+                client: redis::Client::open(url)?,
+                conn: tokio::sync::Mutex::new(None),
+                retry_at: Mutex::new(None),
+            }),
+            memory: MemoryState::default(),
+        })
+    }
+
+    /// Takes a token from each of the buckets, keyed by name, if all of them have one left.
+    ///
+    /// Otherwise nothing is taken and the index of the bucket that ran out is returned, along
+    /// with the time until it has a token again.
+    pub async fn take_tokens(&self, buckets: &[(String, Quota)]) -> Result<(), (usize, Duration)> {
+        if let Some(mut conn) = self.redis_connection().await {
+            let mut invocation = TAKE_TOKENS.prepare_invoke();
+            for (key, quota) in buckets {
+                invocation
+                    .key(format!("{}{}", KEY_PREFIX, key))
+                    .arg(quota.requests)
+                    .arg(quota.window.as_millis() as u64);
+            }
+            match invocation.invoke_async::<(usize, u64)>(&mut conn).await {
+                Ok((0, _)) => return self.redis_ok(Ok(())),
+                Ok((worst, wait)) => {
+                    return self.redis_ok(Err((worst - 1, Duration::from_millis(wait))))
+                }
+                Err(e) => self.redis_failed("take_tokens", e),
+            }
+        }
+        self.memory.take_tokens(buckets)
+    }
+
+    /// Returns the value stored under a key, if any.
+    pub async fn get(&self, key: &str) -> Option<String> {
+        if let Some(mut conn) = self.redis_connection().await {
+            match redis::cmd("GET")
+                .arg(format!("{}{}", KEY_PREFIX, key))
+                .query_async(&mut conn)
+                .await
+            {
+                Ok(value) => return self.redis_ok(value),
+                Err(e) => self.redis_failed("get", e),
+            }
+        }
+        self.memory.get(key)
+    }
+
+    /// Stores a value under a key for the given time, replacing any previous value.
+    pub async fn insert(&self, key: &str, value: &str, ttl: Duration) {
+        if let Some(mut conn) = self.redis_connection().await {
+            match redis::cmd("SET")
+                .arg(format!("{}{}", KEY_PREFIX, key))
+                .arg(value)
+                .arg("PX")
+                .arg(ttl_millis(ttl))
+                .query_async::<()>(&mut conn)
+                .await
+            {
+                Ok(()) => return self.redis_ok(()),
+                Err(e) => self.redis_failed("insert", e),
+            }
+        }
+        self.memory.insert(key, value, ttl, true);
+    }
+
+    /// Stores a value under a key for the given time unless the key is taken. Returns whether
+    /// the value was stored.
+    pub async fn try_insert(&self, key: &str, value: &str, ttl: Duration) -> bool {
+        if let Some(mut conn) = self.redis_connection().await {
+            match redis::cmd("SET")
+                .arg(format!("{}{}", KEY_PREFIX, key))
+                .arg(value)
+                .arg("NX")
+                .arg("PX")
+                .arg(ttl_millis(ttl))
+                .query_async::<Option<String>>(&mut conn)
+                .await
+            {
+                Ok(stored) => return self.redis_ok(stored.is_some()),
+                Err(e) => self.redis_failed("try_insert", e),
+            }
+        }
+        self.memory.insert(key, value, ttl, false)
+    }
+
+    /// Adds an amount to a counter over fixed windows of the given length, starting a new window
+    /// once the current one has passed. Returns the amount used in the current window and the
+    /// time until it passes.
+    pub async fn add_to_window(
+        &self,
+        key: &str,
+        length: Duration,
+        amount: U256,
+    ) -> (U256, Duration) {
+        if let Some(mut conn) = self.redis_connection().await {
+            match ADD_TO_WINDOW
+                .key(format!("{}{}", KEY_PREFIX, key))
+                .arg(ttl_millis(length))
+                .arg(amount.to_string())
+                .invoke_async::<(String, u64)>(&mut conn)
+                .await
+            {
+                Ok((used, resets_in)) => {
+                    let used = U256::from_dec_str(&used).unwrap_or(U256::MAX);
+                    return self.redis_ok((used, Duration::from_millis(resets_in)));
+                }
+                Err(e) => self.redis_failed("add_to_window", e),
+            }
+        }
+        self.memory.add_to_window(key, length, amount)
+    }
+
+    /// Removes a key, e.g. to start a new window of a counter updated with
+    /// [`SharedState::add_to_window`].
+    pub async fn remove(&self, key: &str) {
+        if let Some(mut conn) = self.redis_connection().await {
+            match redis::cmd("DEL")
+                .arg(format!("{}{}", KEY_PREFIX, key))
+                .query_async::<()>(&mut conn)
+                .await
+            {
+                Ok(()) => return self.redis_ok(()),
+                Err(e) => self.redis_failed("remove", e),
+            }
+        }
+        self.memory.remove(key);
+    }
+
+    /// Removes a key if it still holds the given value, e.g. to release a lock taken with
+    /// [`SharedState::try_insert`] without releasing somebody else's after it expired.
+    pub async fn remove_if(&self, key: &str, value: &str) {
+        if let Some(mut conn) = self.redis_connection().await {
+            match REMOVE_IF
+                .key(format!("{}{}", KEY_PREFIX, key))
+                .arg(value)
+                .invoke_async::<()>(&mut conn)
+                .await
+            {
+                Ok(()) => return self.redis_ok(()),
+                Err(e) => self.redis_failed("remove_if", e),
+            }
+        }
+        self.memory.remove_if(key, value);
+    }
+
+    /// Returns a Redis connection, unless Redis isn't configured or recently failed.
+    async fn redis_connection(&self) -> Option<ConnectionManager> {
+        let redis = self.redis.as_ref()?;
+        {
+            let mut retry_at = redis.retry_at.lock().unwrap();
+            match *retry_at {
+                Some(at) if Instant::now() < at => return None,
+                Some(_) => *retry_at = None,
+                None => {}
+            }
+        }
+        let mut conn = redis.conn.lock().await;
+        if conn.is_none() {
+            let config = ConnectionManagerConfig::new()
+                .set_connection_timeout(REDIS_TIMEOUT)
+                .set_response_timeout(REDIS_TIMEOUT)
+                .set_number_of_retries(1);
+            match redis
+                .client
+                .get_connection_manager_with_config(config)
+                .await
+            {
+                Ok(manager) => {
+                    info!("connected to redis state backend");
+                    *conn = Some(manager);
+                }
+                Err(e) => {
+                    drop(conn);
+                    self.redis_failed("connect", e);
+                    return None;
+                }
+            }
+        }
+        conn.clone()
+    }
+
+    fn redis_ok<T>(&self, value: T) -> T {
+        STATE_REDIS_UP.set(1);
+        value
+    }
+
+    fn redis_failed(&self, op: &'static str, error: RedisError) {
+        STATE_REDIS_UP.set(0);
+        STATE_FALLBACKS.with_label_values(&[op]).inc();
+        if let Some(redis) = &self.redis {
+            let mut retry_at = redis.retry_at.lock().unwrap();
+            if retry_at.is_none() {
+                warn!(
+                    "redis state backend failed ({}), using in-memory state for {}s: {}",
+                    op,
+                    RETRY_INTERVAL.as_secs(),
+                    error
+                );
+            }
+            *retry_at = Some(Instant::now() + RETRY_INTERVAL);
+        }
+    }
+}
+
+struct RedisState {
+    client: redis::Client,
+    /// Connection established on first use. It reconnects by itself once established.
+    conn: tokio::sync::Mutex<Option<ConnectionManager>>,
+    /// Set while falling back to memory after a failure.
+    retry_at: Mutex<Option<Instant>>,
+}
+
+/// Redis rejects zero expiry times.
+fn ttl_millis(ttl: Duration) -> u64 {
+    (ttl.as_millis() as u64).max(1)
+}
+
+#[derive(Default)]
+struct MemoryState {
+    inner: Mutex<MemoryInner>,
+}
+
+#[derive(Default)]
+struct MemoryInner {
+    buckets: HashMap<String, Bucket>,
+    records: HashMap<String, (String, Instant)>,
+    windows: HashMap<String, Window>,
+    ops: u64,
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    length: Duration,
+    used: U256,
+}
+
+impl Window {
+    fn has_passed(&self, now: Instant) -> bool {
+        now.duration_since(self.started) >= self.length
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    quota: Quota,
+}
+
+impl Bucket {
+    /// Returns the tokens available at `now` without consuming any.
+    fn available(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.quota.rate()).min(self.quota.requests as f64)
+    }
+}
+
+impl MemoryState {
+    fn lock(&self, now: Instant) -> std::sync::MutexGuard<'_, MemoryInner> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ops += 1;
+        if inner.ops % SWEEP_INTERVAL == 0 {
+            // Full buckets behave the same as missing ones.
+            inner
+                .buckets
+                .retain(|_, bucket| bucket.available(now) < bucket.quota.requests as f64);
+            inner.records.retain(|_, (_, expires)| *expires > now);
+            inner.windows.retain(|_, window| !window.has_passed(now));
+        }
+        inner
+    }
+
+    fn take_tokens(&self, buckets: &[(String, Quota)]) -> Result<(), (usize, Duration)> {
+        let now = Instant::now();
+        let mut inner = self.lock(now);
+        let mut retry_after: Option<(usize, Duration)> = None;
+        for (i, (key, quota)) in buckets.iter().enumerate() {
+            let available = inner
+                .buckets
+                .get(key)
+                .map_or(quota.requests as f64, |b| b.available(now));
+            if available < 1.0 {
+                let wait = Duration::from_secs_f64((1.0 - available) / quota.rate());
+                if retry_after.is_none_or(|(_, w)| wait > w) {
+                    retry_after = Some((i, wait));
+                }
+            }
+        }
+        if let Some(retry_after) = retry_after {
+            return Err(retry_after);
+        }
+
+        for (key, quota) in buckets {
+            let bucket = inner.buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: quota.requests as f64,
+                updated: now,
+                quota: *quota,
+            });
+            bucket.quota = *quota;
+            bucket.tokens = bucket.available(now) - 1.0;
+            bucket.updated = now;
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        let now = Instant::now();
+        self.lock(now)
+            .records
+            .get(key)
+            .filter(|(_, expires)| *expires > now)
+            .map(|(value, _)| value.clone())
+    }
+
+    fn insert(&self, key: &str, value: &str, ttl: Duration, replace: bool) -> bool {
+        let now = Instant::now();
+        let mut inner = self.lock(now);
+        let taken = inner
+            .records
+            .get(key)
+            .is_some_and(|(_, expires)| *expires > now);
+        if taken && !replace {
+            return false;
+        }
+        inner
+            .records
+            .insert(key.to_string(), (value.to_string(), now + ttl));
+        true
+    }
+
+    fn add_to_window(&self, key: &str, length: Duration, amount: U256) -> (U256, Duration) {
+        let now = Instant::now();
+        let mut inner = self.lock(now);
+        let window = inner.windows.entry(key.to_string()).or_insert(Window {
+            started: now,
+            length,
+            used: U256::zero(),
+        });
+        if window.has_passed(now) || window.length != length {
+            *window = Window {
+                started: now,
+                length,
+                used: U256::zero(),
+            };
+        }
+        window.used = window.used.saturating_add(amount);
+        (
+            window.used,
+            length.saturating_sub(now.duration_since(window.started)),
+        )
+    }
+
+    fn remove(&self, key: &str) {
+        let mut inner = self.lock(Instant::now());
+        inner.windows.remove(key);
+        inner.records.remove(key);
+    }
+
+    fn remove_if(&self, key: &str, value: &str) {
+        let mut inner = self.lock(Instant::now());
+        if inner.records.get(key).is_some_and(|(v, _)| v == value) {
+            inner.records.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn exercise(state: SharedState) {
+        let id = uuid::Uuid::new_v4();
+        let quota = Quota {
+            requests: 2,
+            window: Duration::from_secs(60),
+        };
+        let a = (format!("test:{}:a", id), quota);
+        let b = (
+            format!("test:{}:b", id),
+            Quota {
+                requests: 1,
+                ..quota
+            },
+        );
+
+        assert_eq!(state.take_tokens(std::slice::from_ref(&a)).await, Ok(()));
+        assert_eq!(state.take_tokens(&[a.clone(), b.clone()]).await, Ok(()));
+        let (index, wait) = state
+            .take_tokens(&[b.clone(), a.clone()])
+            .await
+            .unwrap_err();
+        assert!(index <= 1 && wait > Duration::ZERO && wait <= quota.window);
+
+        // Buckets refill at `requests` per window.
+        let c = (
+            format!("test:{}:c", id),
+            Quota {
+                requests: 10,
+                window: Duration::from_secs(1),
+            },
+        );
+        for _ in 0..10 {
+            assert_eq!(state.take_tokens(std::slice::from_ref(&c)).await, Ok(()));
+        }
+        let (index, wait) = state
+            .take_tokens(std::slice::from_ref(&c))
+            .await
+            .unwrap_err();
+        assert!(index == 0 && wait <= Duration::from_millis(101));
+        tokio::time::sleep(wait + Duration::from_millis(50)).await;
+        assert_eq!(state.take_tokens(std::slice::from_ref(&c)).await, Ok(()));
+
+        let key = format!("test:{}:lock", id);
+        let ttl = Duration::from_secs(10);
+        assert!(state.try_insert(&key, "one", ttl).await);
+        assert!(!state.try_insert(&key, "two", ttl).await);
+        state.remove_if(&key, "two").await;
+        assert_eq!(state.get(&key).await.as_deref(), Some("one"));
+        state.remove_if(&key, "one").await;
+        assert_eq!(state.get(&key).await, None);
+        state.insert(&key, "three", ttl).await;
+        assert_eq!(state.get(&key).await.as_deref(), Some("three"));
+
+        // Window counters add amounts beyond 64 bits and start over once the window passes.
+        let key = format!("test:{}:window", id);
+        let length = Duration::from_millis(500);
+        let amount = U256::from(u64::MAX);
+        assert_eq!(state.add_to_window(&key, length, amount).await.0, amount);
+        let (used, resets_in) = state.add_to_window(&key, length, amount).await;
+        assert_eq!(used, amount * 2);
+        assert!(resets_in > Duration::ZERO && resets_in <= length);
+        tokio::time::sleep(resets_in + Duration::from_millis(50)).await;
+        assert_eq!(
+            state.add_to_window(&key, length, U256::zero()).await.0,
+            U256::zero()
+        );
+        state.add_to_window(&key, length, amount).await;
+        state.remove(&key).await;
+        assert_eq!(
+            state.add_to_window(&key, length, U256::one()).await.0,
+            U256::one()
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_state() {
+        exercise(SharedState::memory()).await;
+    }
+
+    #[tokio::test]
+    async fn unreachable_redis_falls_back_to_memory() {
+        exercise(SharedState::redis("redis://127.0.0.1:1").unwrap()).await;
+    }
+
+    /// Runs against the server at `REDIS_URL`, e.g. `REDIS_URL=redis://127.0.0.1:6379 cargo test
+    /// -- --ignored`.
+    #[tokio::test]
+    #[ignore = "requires a redis server"]
+    async fn redis_state() {
+        let url = std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1:6379".to_string());
+        let state = SharedState::redis(&url).unwrap();
+        exercise(state).await;
+        assert_eq!(STATE_REDIS_UP.get(), 1);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::server::ledger::{Ledger, LedgerEntry, RequestKind};
+use crate::server::shared::{
+    with_client, with_ledger, BadGateway, BadRequest, DefaultSignerMiddleware, Unavailable,
+};
+use ethers::prelude::{Address, Middleware, TxHash, U256};
+use serde::Serialize;
+use std::sync::Arc;
+use warp::{Filter, Rejection, Reply};
+
+/// Status of a transaction sent by the service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum TxStatus {
+    /// The transaction is known to the node but not mined yet.
+    Pending,
+    /// The transaction was mined successfully.
+    Mined,
+    /// The transaction was mined but reverted.
+    Failed,
+    /// The transaction was rebroadcast with bumped fees, see `replaced_by`.
+    Replaced,
+    /// The service sent the transaction but the node no longer knows about it.
+    Dropped,
+}
+
+/// Response body of the `/tx/{hash}` endpoint.
+#[derive(Debug, Serialize)]
+struct TxStatusResponse {
+    tx_hash: TxHash,
+    status: TxStatus,
+    block_number: Option<u64>,
+    gas_used: Option<U256>,
+    confirmations: Option<u64>,
+    /// Latest transaction for the same request, if this one was replaced.
+    replaced_by: Option<TxHash>,
+    /// Earlier transactions for the same request that were replaced.
+    replaces: Vec<TxHash>,
+    kind: Option<RequestKind>,
+    /// Target address, omitted for batched transactions serving several requests.
+    address: Option<Address>,
+    requested_at: Option<u64>,
+    /// Position of the request in a batched transaction, omitted if it serves several requests.
+    batch_index: Option<u32>,
+    /// Number of requests served by a batched transaction.
+    batch_size: Option<usize>,
+    nonce: Option<U256>,
+    max_fee_per_gas: Option<U256>,
+    max_priority_fee_per_gas: Option<U256>,
+}
+
+impl TxStatusResponse {
+    /// Builds the response from the ledger entries of the requests the transaction served.
+    ///
+    /// All entries of a batched transaction share its hashes and fees, while the details of
+    /// each request are only included if there's a single one, so that requesters can't see
+    /// each other's addresses.
+    fn new(tx_hash: TxHash, status: TxStatus, entries: &[LedgerEntry]) -> Self {
+        let entry = entries.first();
+        let single = match entries {
+            [entry] => Some(entry),
+            _ => None,
+        };
+        Self {
+            tx_hash,
+            status,
+            block_number: None,
+            gas_used: None,
+            confirmations: None,
+            replaced_by: entry
+                .and_then(|e| e.tx_hash)
+                .filter(|latest| *latest != tx_hash),
+            replaces: entry
+                .map(|e| {
+                    e.replaced_tx_hashes
+                        .iter()
+                        .copied()
+                        .filter(|h| *h != tx_hash)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            kind: entry.map(|e| e.kind),
+            address: single.map(|e| e.address),
+            requested_at: single.map(|e| e.requested_at),
+            batch_index: single.and_then(|e| e.batch_index),
+            batch_size: (entries.len() > 1).then_some(entries.len()),
+            nonce: entry.and_then(|e| e.nonce),
+            max_fee_per_gas: entry.and_then(|e| e.max_fee_per_gas),
+            max_priority_fee_per_gas: entry.and_then(|e| e.max_priority_fee_per_gas),
+        }
+    }
+}
+
+/// Route filter for `/tx/{hash}` endpoint.
+pub fn tx_route(
+    client: Arc<DefaultSignerMiddleware>,
+    ledger: Ledger,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("tx" / String)
+        .and(warp::get())
+        .and(with_client(client))
+        .and(with_ledger(ledger))
+        .and_then(handle_tx)
+}
+
+/// Handles the `/tx/{hash}` request.
+pub async fn handle_tx(
+    hash: String,
+    client: Arc<DefaultSignerMiddleware>,
+    ledger: Ledger,
+) -> anyhow::Result<impl Reply, Rejection> {
+    let tx_hash = hash.parse::<TxHash>().map_err(|e| {
+        Rejection::from(BadRequest {
+            message: format!("invalid transaction hash: {}", e),
+        })
+    })?;
+    let rpc_error = |e: ethers::providers::ProviderError| {
+        Rejection::from(BadGateway {
+            message: format!("rpc error: {}", e),
+        })
+    };
+
+    // Read only: the transaction monitor keeps the ledger entries up to date.
+    let entries = ledger.find_by_tx_hash(tx_hash).await.map_err(|e| {
+        Rejection::from(Unavailable {
+            reason: "ledger-unavailable",
+            message: format!("ledger error: {}", e),
+        })
+    })?;
+
+    let provider = client.provider();
+    if let Some(receipt) = provider
+        .get_transaction_receipt(tx_hash)
+        .await
+        .map_err(rpc_error)?
+    {
+        let status = if receipt.status == Some(1u64.into()) {
+            TxStatus::Mined
+        } else {
+            TxStatus::Failed
+        };
+        let latest = provider.get_block_number().await.map_err(rpc_error)?;
+        let mut res = TxStatusResponse::new(tx_hash, status, &entries);
+        res.block_number = receipt.block_number.map(|n| n.as_u64());
+        res.gas_used = receipt.gas_used;
+        res.confirmations = receipt
+            .block_number
+            .map(|n| latest.saturating_sub(n).as_u64() + 1);
+        return Ok(warp::reply::json(&res));
+    }
+
+    let pending = provider
+        .get_transaction(tx_hash)
+        .await
+        .map_err(rpc_error)?
+        .is_some();
+    let status = match (pending, entries.first()) {
+        (true, _) => TxStatus::Pending,
+        (false, Some(e)) if e.tx_hash != Some(tx_hash) => TxStatus::Replaced,
+        (false, Some(_)) => TxStatus::Dropped,
+        (false, None) => return Err(warp::reject::not_found()),
+    };
+    Ok(warp::reply::json(&TxStatusResponse::new(
+        tx_hash, status, &entries,
+    )))
+}
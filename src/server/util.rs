@@ -1,9 +1,11 @@
 use lazy_static::lazy_static;
-use log::debug;
+use log::{debug, info};
 use prometheus::{register_histogram_vec, HistogramVec};
 use serde_json::json;
 use warp::log::Info;
 
+use crate::server::keys::DripKey;
+
 /// Helper function to log details for failed requests.
 pub fn log_failed_request(request: Info) {
     if request.status().as_u16() < 400 {
@@ -33,6 +35,16 @@ pub fn log_request_body(route: &str, body: &str) {
     debug!("{}", log_data);
 }
 
+/// Logs the faucet keys a drip is sent with, by key type.
+pub fn log_drip_keys(address: &str, keys: &[DripKey]) {
+    let log_data = json!({
+        "route": "drip",
+        "address": address,
+        "keys": keys
+    });
+    info!("{}", log_data);
+}
+
 lazy_static! {
     static ref HISTOGRAM_REQUESTS: HistogramVec = register_histogram_vec!(
         "http_request_duration_seconds",
@@ -0,0 +1,524 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use ethers::prelude::Address;
+use ethers::utils::keccak256;
+use lazy_static::lazy_static;
+use log::warn;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::server::state::SharedState;
+
+const TURNSTILE_VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+const HCAPTCHA_VERIFY_URL: &str = "https://api.hcaptcha.com/siteverify";
+const RECAPTCHA_VERIFY_URL: &str = "https://www.google.com/recaptcha/api/siteverify";
+
+/// Timeout for connecting to a `siteverify` endpoint.
+const SITEVERIFY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Timeout for a `siteverify` call, including the connection.
+const SITEVERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum age, in seconds, of a local proof-of-work stamp.
+const POW_STAMP_MAX_AGE: u64 = 300;
+
+/// Maximum time, in seconds, a local proof-of-work stamp may be ahead of the clock.
+const POW_STAMP_MAX_SKEW: u64 = 60;
+
+/// `siteverify` error codes passed through as rejection reasons. Anything else is reported as
+/// `invalid-response`.
+const KNOWN_ERROR_CODES: &[&str] = &[
+    "missing-input-response",
+    "invalid-input-response",
+    "timeout-or-duplicate",
+    "already-seen-response",
+    "missing-input-secret",
+    "invalid-input-secret",
+    "internal-error",
+];
+
+lazy_static! {
+    static ref HUMAN_VERIFICATIONS: IntCounterVec = register_int_counter_vec!(
+        "human_verifications_total",
+        "Number of human verifications of drip requests, by provider and result.",
+        &["provider", "result"]
+    )
+    .unwrap();
+}
+
+/// Service used to check that a drip request was made by a human.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CaptchaProvider {
+    /// Cloudflare Turnstile.
+    #[default]
+    Turnstile,
+    /// hCaptcha.
+    Hcaptcha,
+    /// Google reCAPTCHA v2 (checkbox or invisible).
+    RecaptchaV2,
+    /// Google reCAPTCHA v3, accepted above a minimum score.
+    RecaptchaV3,
+    /// Local hashcash-style proof of work, no third-party service.
+    Pow,
+}
+
+/// Settings for building the human verifier.
+#[derive(Clone, Debug)]
+pub struct VerifierConfig {
+    pub provider: CaptchaProvider,
+    /// Cloudflare Turnstile secret key.
+    pub ts_secret_key: Option<String>,
+    /// Hostnames Turnstile tokens must have been issued on. Any hostname if empty.
+    pub turnstile_hostnames: Vec<String>,
+    /// Widget action Turnstile tokens must have been issued for.
+    pub turnstile_action: Option<String>,
+    /// Customer data Turnstile tokens must carry.
+    pub turnstile_cdata: Option<String>,
+    /// hCaptcha or reCAPTCHA secret key.
+    pub captcha_secret_key: Option<String>,
+    /// Minimum reCAPTCHA v3 score.
+    pub recaptcha_min_score: f64,
+    /// Leading zero bits required of a proof-of-work stamp.
+    pub pow_difficulty: u32,
+}
+
+/// Details of the request being verified.
+#[derive(Clone, Copy, Debug)]
+pub struct VerifyContext {
+    pub client_ip: IpAddr,
+    pub address: Address,
+}
+
+/// Reason a human verification didn't pass.
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The response was rejected, with a short kebab-case reason code.
+    Rejected(&'static str),
+    /// The provider couldn't be reached or returned something unexpected.
+    Provider(anyhow::Error),
+}
+
+impl VerifyError {
+    /// Returns the reason code reported to clients and in metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            VerifyError::Rejected(reason) => reason,
+            VerifyError::Provider(_) => "provider-error",
+        }
+    }
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Rejected(reason) => write!(f, "{}", reason),
+            VerifyError::Provider(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for VerifyError {
+    fn from(e: reqwest::Error) -> Self {
+        VerifyError::Provider(e.into())
+    }
+}
+
+/// Checks the captcha (or equivalent) response sent with a drip request.
+#[async_trait]
+pub trait HumanVerifier: Send + Sync {
+    /// Name of the provider, used in error messages and metrics.
+    fn name(&self) -> &'static str;
+
+    /// Checks the response for the given request.
+    async fn verify(&self, response: &str, context: &VerifyContext) -> Result<(), VerifyError>;
+}
+
+/// Verifies a response, recording the result per provider and reason.
+pub async fn verify_human(
+    verifier: &dyn HumanVerifier,
+    response: &str,
+    context: &VerifyContext,
+) -> Result<(), VerifyError> {
+    let res = verifier.verify(response, context).await;
+    let result = match &res {
+        Ok(()) => "success",
+        Err(e) => e.reason(),
+    };
+    HUMAN_VERIFICATIONS
+        .with_label_values(&[verifier.name(), result])
+        .inc();
+    res
+}
+
+/// Maps `siteverify` error codes to a rejection reason.
+fn rejection_from_codes(codes: &[String]) -> VerifyError {
+    let reason = codes
+        .iter()
+        .find_map(|code| KNOWN_ERROR_CODES.iter().find(|known| *known == code))
+        .copied()
+        .unwrap_or("invalid-response");
+    VerifyError::Rejected(reason)
+}
+
+/// Builds the verifier for the configured provider. Spent proof-of-work stamps are recorded in
+/// the shared state.
+pub fn build_verifier(
+    config: VerifierConfig,
+    state: Arc<SharedState>,
+) -> anyhow::Result<Arc<dyn HumanVerifier>> {
+    let captcha_secret = || {
+        config
+            .captcha_secret_key
+            .clone()
+            .ok_or_else(|| anyhow!("{:?} requires a captcha secret key", config.provider))
+    };
+    let client = reqwest::Client::builder()
+        .connect_timeout(SITEVERIFY_CONNECT_TIMEOUT)
+        .timeout(SITEVERIFY_TIMEOUT)
+        .build()?;
+    let verifier: Arc<dyn HumanVerifier> = match config.provider {
+        CaptchaProvider::Turnstile => {
+            let secret = config
+                .ts_secret_key
+                .clone()
+                .ok_or_else(|| anyhow!("turnstile requires a turnstile secret key"))?;
+            Arc::new(TurnstileVerifier {
+                secret,
+                hostnames: config.turnstile_hostnames.clone(),
+                action: config.turnstile_action.clone(),
+                cdata: config.turnstile_cdata.clone(),
+                client,
+            })
+        }
+        CaptchaProvider::Hcaptcha => Arc::new(SiteVerifyVerifier {
+            name: "hcaptcha",
+            url: HCAPTCHA_VERIFY_URL,
+            secret: captcha_secret()?,
+            min_score: None,
+            client,
+        }),
+        CaptchaProvider::RecaptchaV2 => Arc::new(SiteVerifyVerifier {
+            name: "recaptcha",
+            url: RECAPTCHA_VERIFY_URL,
+            secret: captcha_secret()?,
+            min_score: None,
+            client,
+        }),
+        CaptchaProvider::RecaptchaV3 => Arc::new(SiteVerifyVerifier {
+            name: "recaptcha",
+            url: RECAPTCHA_VERIFY_URL,
+            secret: captcha_secret()?,
+            min_score: Some(config.recaptcha_min_score),
+            client,
+        }),
+        CaptchaProvider::Pow => Arc::new(PowVerifier {
+            difficulty: config.pow_difficulty,
+            state,
+        }),
+    };
+    Ok(verifier)
+}
+
+/// Response of the Turnstile `siteverify` endpoint.
+#[derive(Deserialize)]
+struct TurnstileResponse {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+    hostname: Option<String>,
+    action: Option<String>,
+    cdata: Option<String>,
+}
+
+/// Cloudflare Turnstile verifier.
+///
+/// Besides the token itself, the hostname, action and customer data the token was issued with
+/// are checked, so that tokens minted on other sites or for other widgets are rejected.
+struct TurnstileVerifier {
+    secret: String,
+    hostnames: Vec<String>,
+    action: Option<String>,
+    cdata: Option<String>,
+    client: reqwest::Client,
+}
+
+impl TurnstileVerifier {
+    async fn siteverify(
+        &self,
+        response: &str,
+        remote_ip: &str,
+        idempotency_key: &str,
+    ) -> Result<TurnstileResponse, reqwest::Error> {
+        self.client
+            .post(TURNSTILE_VERIFY_URL)
+            .json(&json!({
+                "secret": self.secret,
+                "response": response,
+                "remoteip": remote_ip,
+                "idempotency_key": idempotency_key,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+#[async_trait]
+impl HumanVerifier for TurnstileVerifier {
+    fn name(&self) -> &'static str {
+        "turnstile"
+    }
+
+    async fn verify(&self, response: &str, context: &VerifyContext) -> Result<(), VerifyError> {
+        let remote_ip = context.client_ip.to_string();
+        // The idempotency key lets a failed call be retried without the token counting as
+        // already redeemed.
+        let idempotency_key = Uuid::new_v4().to_string();
+        let res = match self
+            .siteverify(response, &remote_ip, &idempotency_key)
+            .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("turnstile siteverify failed, retrying: {}", e);
+                self.siteverify(response, &remote_ip, &idempotency_key)
+                    .await?
+            }
+        };
+
+        if !res.success {
+            return Err(rejection_from_codes(&res.error_codes));
+        }
+        if !self.hostnames.is_empty() && !res.hostname.is_some_and(|h| self.hostnames.contains(&h))
+        {
+            return Err(VerifyError::Rejected("hostname-mismatch"));
+        }
+        if self.action.is_some() && res.action != self.action {
+            return Err(VerifyError::Rejected("action-mismatch"));
+        }
+        if self.cdata.is_some() && res.cdata != self.cdata {
+            return Err(VerifyError::Rejected("cdata-mismatch"));
+        }
+        Ok(())
+    }
+}
+
+/// Response of the hCaptcha and reCAPTCHA `siteverify` endpoints.
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+    /// reCAPTCHA v3 only.
+    score: Option<f64>,
+}
+
+/// Verifier for hCaptcha and reCAPTCHA, which share the same `siteverify` API.
+struct SiteVerifyVerifier {
+    name: &'static str,
+    url: &'static str,
+    secret: String,
+    /// Minimum score required of a v3 response.
+    min_score: Option<f64>,
+    client: reqwest::Client,
+}
+
+#[async_trait]
+impl HumanVerifier for SiteVerifyVerifier {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn verify(&self, response: &str, context: &VerifyContext) -> Result<(), VerifyError> {
+        let remote_ip = context.client_ip.to_string();
+        let res: SiteVerifyResponse = self
+            .client
+            .post(self.url)
+            .form(&[
+                ("secret", self.secret.as_str()),
+                ("response", response),
+                ("remoteip", remote_ip.as_str()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if !res.success {
+            return Err(rejection_from_codes(&res.error_codes));
+        }
+        match self.min_score {
+            Some(min_score) if !res.score.is_some_and(|s| s >= min_score) => {
+                Err(VerifyError::Rejected("score-too-low"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Local hashcash-style proof-of-work verifier.
+///
+/// The response is a stamp `<unix timestamp>:<counter>`. It's valid if it's recent and
+/// `keccak256("<address>:<timestamp>:<counter>")` starts with at least `difficulty` zero bits,
+/// which binds the work to the drip recipient. Accepted stamps are kept in the shared state
+/// until they expire, so each one can only be spent once.
+struct PowVerifier {
+    difficulty: u32,
+    state: Arc<SharedState>,
+}
+
+#[async_trait]
+impl HumanVerifier for PowVerifier {
+    fn name(&self) -> &'static str {
+        "pow"
+    }
+
+    async fn verify(&self, response: &str, context: &VerifyContext) -> Result<(), VerifyError> {
+        let invalid = VerifyError::Rejected("invalid-stamp");
+        let Some((timestamp, counter)) = response.split_once(':') else {
+            return Err(invalid);
+        };
+        let (Ok(timestamp), Ok(counter)) = (timestamp.parse::<u64>(), counter.parse::<u64>())
+        else {
+            return Err(invalid);
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| VerifyError::Provider(e.into()))?
+            .as_secs();
+        if timestamp > now + POW_STAMP_MAX_SKEW || now.saturating_sub(timestamp) > POW_STAMP_MAX_AGE
+        {
+            return Err(VerifyError::Rejected("stamp-expired"));
+        }
+        let preimage = format!("{:?}:{}:{}", context.address, timestamp, counter);
+        let hash = keccak256(preimage);
+        if leading_zero_bits(&hash) < self.difficulty {
+            return Err(VerifyError::Rejected("insufficient-work"));
+        }
+
+        let key = format!("pow:{}", hex::encode(hash));
+        let ttl = Duration::from_secs((timestamp + POW_STAMP_MAX_AGE).saturating_sub(now) + 1);
+        if !self.state.try_insert(&key, "used", ttl).await {
+            return Err(VerifyError::Rejected("stamp-already-used"));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the number of leading zero bits of a hash.
+pub fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pow_verifier(difficulty: u32) -> PowVerifier {
+        PowVerifier {
+            difficulty,
+            state: Arc::new(SharedState::memory()),
+        }
+    }
+
+    /// Finds a stamp with enough work for the address at the given time.
+    fn solve(address: Address, timestamp: u64, difficulty: u32) -> String {
+        (0u64..)
+            .find(|counter| {
+                let preimage = format!("{:?}:{}:{}", address, timestamp, counter);
+                leading_zero_bits(&keccak256(preimage)) >= difficulty
+            })
+            .map(|counter| format!("{}:{}", timestamp, counter))
+            .unwrap()
+    }
+
+    fn context(address: Address) -> VerifyContext {
+        VerifyContext {
+            client_ip: [127, 0, 0, 1].into(),
+            address,
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[]), 0);
+        assert_eq!(leading_zero_bits(&[0xff, 0x00]), 0);
+        assert_eq!(leading_zero_bits(&[0x01, 0xff]), 7);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10, 0x00]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn maps_error_codes() {
+        let codes = [
+            "bad-request".to_string(),
+            "timeout-or-duplicate".to_string(),
+        ];
+        assert_eq!(
+            rejection_from_codes(&codes).reason(),
+            "timeout-or-duplicate"
+        );
+        assert_eq!(
+            rejection_from_codes(&codes[..1]).reason(),
+            "invalid-response"
+        );
+    }
+
+    #[tokio::test]
+    async fn accepts_pow_stamp_once() {
+        let verifier = pow_verifier(8);
+        let address = Address::repeat_byte(0x11);
+        let stamp = solve(address, now(), 8);
+        assert!(verifier.verify(&stamp, &context(address)).await.is_ok());
+        let replay = verifier.verify(&stamp, &context(address)).await;
+        assert_eq!(replay.unwrap_err().reason(), "stamp-already-used");
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_pow_stamps() {
+        let verifier = pow_verifier(8);
+        let address = Address::repeat_byte(0x11);
+        let reason = |res: Result<(), VerifyError>| res.unwrap_err().reason();
+
+        for stamp in ["", "12", "a:1", "1:b"] {
+            let res = verifier.verify(stamp, &context(address)).await;
+            assert_eq!(reason(res), "invalid-stamp");
+        }
+
+        let stale = solve(address, now() - POW_STAMP_MAX_AGE - 10, 8);
+        let res = verifier.verify(&stale, &context(address)).await;
+        assert_eq!(reason(res), "stamp-expired");
+        let future = solve(address, now() + POW_STAMP_MAX_SKEW + 10, 8);
+        let res = verifier.verify(&future, &context(address)).await;
+        assert_eq!(reason(res), "stamp-expired");
+
+        // The work is bound to the recipient.
+        let stamp = solve(address, now(), 16);
+        let other = Address::repeat_byte(0x22);
+        let res = pow_verifier(16).verify(&stamp, &context(other)).await;
+        assert_eq!(reason(res), "insufficient-work");
+    }
+}